@@ -0,0 +1,2 @@
+DROP TABLE measurements_daily;
+DROP TABLE measurements_hourly;
@@ -0,0 +1,41 @@
+CREATE TABLE measurements_hourly (
+    bucket_start BIGINT PRIMARY KEY NOT NULL,
+    count INTEGER NOT NULL,
+    temperature_count INTEGER NOT NULL DEFAULT 0,
+    temperature_min REAL,
+    temperature_max REAL,
+    temperature_sum REAL NOT NULL DEFAULT 0,
+    humidity_count INTEGER NOT NULL DEFAULT 0,
+    humidity_min REAL,
+    humidity_max REAL,
+    humidity_sum REAL NOT NULL DEFAULT 0,
+    pressure_count INTEGER NOT NULL DEFAULT 0,
+    pressure_min REAL,
+    pressure_max REAL,
+    pressure_sum REAL NOT NULL DEFAULT 0,
+    light_level_count INTEGER NOT NULL DEFAULT 0,
+    light_level_min REAL,
+    light_level_max REAL,
+    light_level_sum REAL NOT NULL DEFAULT 0
+);
+
+CREATE TABLE measurements_daily (
+    bucket_start BIGINT PRIMARY KEY NOT NULL,
+    count INTEGER NOT NULL,
+    temperature_count INTEGER NOT NULL DEFAULT 0,
+    temperature_min REAL,
+    temperature_max REAL,
+    temperature_sum REAL NOT NULL DEFAULT 0,
+    humidity_count INTEGER NOT NULL DEFAULT 0,
+    humidity_min REAL,
+    humidity_max REAL,
+    humidity_sum REAL NOT NULL DEFAULT 0,
+    pressure_count INTEGER NOT NULL DEFAULT 0,
+    pressure_min REAL,
+    pressure_max REAL,
+    pressure_sum REAL NOT NULL DEFAULT 0,
+    light_level_count INTEGER NOT NULL DEFAULT 0,
+    light_level_min REAL,
+    light_level_max REAL,
+    light_level_sum REAL NOT NULL DEFAULT 0
+);
@@ -0,0 +1 @@
+ALTER TABLE measurements DROP COLUMN meas_duration_us;
@@ -0,0 +1 @@
+ALTER TABLE measurements ADD COLUMN meas_duration_us BIGINT;
@@ -0,0 +1,5 @@
+DROP INDEX measurements_unsynced;
+
+ALTER TABLE measurements DROP COLUMN monotonic_us;
+ALTER TABLE measurements DROP COLUMN boot_id;
+ALTER TABLE measurements DROP COLUMN clock_synced;
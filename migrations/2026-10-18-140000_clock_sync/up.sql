@@ -0,0 +1,6 @@
+-- Rows from before this was tracked are assumed to have been fine.
+ALTER TABLE measurements ADD COLUMN clock_synced BOOLEAN NOT NULL DEFAULT 1;
+ALTER TABLE measurements ADD COLUMN boot_id TEXT;
+ALTER TABLE measurements ADD COLUMN monotonic_us BIGINT;
+
+CREATE INDEX measurements_unsynced ON measurements (boot_id) WHERE NOT clock_synced;
@@ -0,0 +1,3 @@
+DROP INDEX measurements_not_uploaded;
+
+ALTER TABLE measurements DROP COLUMN uploaded_at;
@@ -0,0 +1,4 @@
+-- NULL until the collector has acknowledged the row.
+ALTER TABLE measurements ADD COLUMN uploaded_at BIGINT;
+
+CREATE INDEX measurements_not_uploaded ON measurements (id) WHERE uploaded_at IS NULL;
@@ -0,0 +1,76 @@
+DROP INDEX measurements_node_time;
+DROP INDEX measurements_uid;
+
+DELETE FROM measurements WHERE node_id != '';
+ALTER TABLE measurements DROP COLUMN uid;
+ALTER TABLE measurements DROP COLUMN node_id;
+
+CREATE TABLE measurements_hourly_old (
+    bucket_start BIGINT PRIMARY KEY NOT NULL,
+    count INTEGER NOT NULL,
+    temperature_count INTEGER NOT NULL DEFAULT 0,
+    temperature_min REAL,
+    temperature_max REAL,
+    temperature_sum REAL NOT NULL DEFAULT 0,
+    humidity_count INTEGER NOT NULL DEFAULT 0,
+    humidity_min REAL,
+    humidity_max REAL,
+    humidity_sum REAL NOT NULL DEFAULT 0,
+    pressure_count INTEGER NOT NULL DEFAULT 0,
+    pressure_min REAL,
+    pressure_max REAL,
+    pressure_sum REAL NOT NULL DEFAULT 0,
+    light_level_count INTEGER NOT NULL DEFAULT 0,
+    light_level_min REAL,
+    light_level_max REAL,
+    light_level_sum REAL NOT NULL DEFAULT 0
+);
+INSERT INTO measurements_hourly_old (
+    bucket_start, count, temperature_count, temperature_min, temperature_max,
+    temperature_sum, humidity_count, humidity_min, humidity_max, humidity_sum,
+    pressure_count, pressure_min, pressure_max, pressure_sum,
+    light_level_count, light_level_min, light_level_max, light_level_sum
+)
+SELECT
+    bucket_start, count, temperature_count, temperature_min, temperature_max,
+    temperature_sum, humidity_count, humidity_min, humidity_max, humidity_sum,
+    pressure_count, pressure_min, pressure_max, pressure_sum,
+    light_level_count, light_level_min, light_level_max, light_level_sum
+FROM measurements_hourly WHERE node_id = '';
+DROP TABLE measurements_hourly;
+ALTER TABLE measurements_hourly_old RENAME TO measurements_hourly;
+
+CREATE TABLE measurements_daily_old (
+    bucket_start BIGINT PRIMARY KEY NOT NULL,
+    count INTEGER NOT NULL,
+    temperature_count INTEGER NOT NULL DEFAULT 0,
+    temperature_min REAL,
+    temperature_max REAL,
+    temperature_sum REAL NOT NULL DEFAULT 0,
+    humidity_count INTEGER NOT NULL DEFAULT 0,
+    humidity_min REAL,
+    humidity_max REAL,
+    humidity_sum REAL NOT NULL DEFAULT 0,
+    pressure_count INTEGER NOT NULL DEFAULT 0,
+    pressure_min REAL,
+    pressure_max REAL,
+    pressure_sum REAL NOT NULL DEFAULT 0,
+    light_level_count INTEGER NOT NULL DEFAULT 0,
+    light_level_min REAL,
+    light_level_max REAL,
+    light_level_sum REAL NOT NULL DEFAULT 0
+);
+INSERT INTO measurements_daily_old (
+    bucket_start, count, temperature_count, temperature_min, temperature_max,
+    temperature_sum, humidity_count, humidity_min, humidity_max, humidity_sum,
+    pressure_count, pressure_min, pressure_max, pressure_sum,
+    light_level_count, light_level_min, light_level_max, light_level_sum
+)
+SELECT
+    bucket_start, count, temperature_count, temperature_min, temperature_max,
+    temperature_sum, humidity_count, humidity_min, humidity_max, humidity_sum,
+    pressure_count, pressure_min, pressure_max, pressure_sum,
+    light_level_count, light_level_min, light_level_max, light_level_sum
+FROM measurements_daily WHERE node_id = '';
+DROP TABLE measurements_daily;
+ALTER TABLE measurements_daily_old RENAME TO measurements_daily;
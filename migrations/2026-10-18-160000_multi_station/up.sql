@@ -0,0 +1,60 @@
+-- The empty node ID is this node, everything else came from a collector upload.
+ALTER TABLE measurements ADD COLUMN node_id TEXT NOT NULL DEFAULT '';
+-- Set for uploaded measurements, so that repeated uploads are ignored.
+ALTER TABLE measurements ADD COLUMN uid TEXT;
+
+CREATE UNIQUE INDEX measurements_uid ON measurements (uid);
+CREATE INDEX measurements_node_time ON measurements (node_id, meas_time);
+
+-- SQLite can't change a primary key, so the rollups are copied over.
+CREATE TABLE measurements_hourly_new (
+    node_id TEXT NOT NULL DEFAULT '',
+    bucket_start BIGINT NOT NULL,
+    count INTEGER NOT NULL,
+    temperature_count INTEGER NOT NULL DEFAULT 0,
+    temperature_min REAL,
+    temperature_max REAL,
+    temperature_sum REAL NOT NULL DEFAULT 0,
+    humidity_count INTEGER NOT NULL DEFAULT 0,
+    humidity_min REAL,
+    humidity_max REAL,
+    humidity_sum REAL NOT NULL DEFAULT 0,
+    pressure_count INTEGER NOT NULL DEFAULT 0,
+    pressure_min REAL,
+    pressure_max REAL,
+    pressure_sum REAL NOT NULL DEFAULT 0,
+    light_level_count INTEGER NOT NULL DEFAULT 0,
+    light_level_min REAL,
+    light_level_max REAL,
+    light_level_sum REAL NOT NULL DEFAULT 0,
+    PRIMARY KEY (node_id, bucket_start)
+);
+INSERT INTO measurements_hourly_new SELECT '', * FROM measurements_hourly;
+DROP TABLE measurements_hourly;
+ALTER TABLE measurements_hourly_new RENAME TO measurements_hourly;
+
+CREATE TABLE measurements_daily_new (
+    node_id TEXT NOT NULL DEFAULT '',
+    bucket_start BIGINT NOT NULL,
+    count INTEGER NOT NULL,
+    temperature_count INTEGER NOT NULL DEFAULT 0,
+    temperature_min REAL,
+    temperature_max REAL,
+    temperature_sum REAL NOT NULL DEFAULT 0,
+    humidity_count INTEGER NOT NULL DEFAULT 0,
+    humidity_min REAL,
+    humidity_max REAL,
+    humidity_sum REAL NOT NULL DEFAULT 0,
+    pressure_count INTEGER NOT NULL DEFAULT 0,
+    pressure_min REAL,
+    pressure_max REAL,
+    pressure_sum REAL NOT NULL DEFAULT 0,
+    light_level_count INTEGER NOT NULL DEFAULT 0,
+    light_level_min REAL,
+    light_level_max REAL,
+    light_level_sum REAL NOT NULL DEFAULT 0,
+    PRIMARY KEY (node_id, bucket_start)
+);
+INSERT INTO measurements_daily_new SELECT '', * FROM measurements_daily;
+DROP TABLE measurements_daily;
+ALTER TABLE measurements_daily_new RENAME TO measurements_daily;
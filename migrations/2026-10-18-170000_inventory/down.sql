@@ -0,0 +1,8 @@
+ALTER TABLE measurements DROP COLUMN light_level_sensor_id;
+ALTER TABLE measurements DROP COLUMN pressure_sensor_id;
+ALTER TABLE measurements DROP COLUMN humidity_sensor_id;
+ALTER TABLE measurements DROP COLUMN temperature_sensor_id;
+ALTER TABLE measurements DROP COLUMN station_id;
+
+DROP TABLE sensors;
+DROP TABLE stations;
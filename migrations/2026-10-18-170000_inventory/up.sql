@@ -0,0 +1,36 @@
+CREATE TABLE stations (
+    id INTEGER PRIMARY KEY NOT NULL,
+    -- Empty for this node, like in measurements.
+    node_id TEXT NOT NULL UNIQUE,
+    hostname TEXT,
+    location TEXT,
+    first_seen BIGINT NOT NULL,
+    last_seen BIGINT NOT NULL
+);
+
+CREATE TABLE sensors (
+    id INTEGER PRIMARY KEY NOT NULL,
+    station_id INTEGER NOT NULL REFERENCES stations (id),
+    chip TEXT NOT NULL,
+    chip_id INTEGER,
+    i2c_bus TEXT,
+    i2c_address INTEGER,
+    calibration BLOB,
+    first_seen BIGINT NOT NULL,
+    last_seen BIGINT NOT NULL
+);
+
+CREATE INDEX sensors_station ON sensors (station_id);
+
+ALTER TABLE measurements ADD COLUMN station_id INTEGER REFERENCES stations (id);
+ALTER TABLE measurements ADD COLUMN temperature_sensor_id INTEGER REFERENCES sensors (id);
+ALTER TABLE measurements ADD COLUMN humidity_sensor_id INTEGER REFERENCES sensors (id);
+ALTER TABLE measurements ADD COLUMN pressure_sensor_id INTEGER REFERENCES sensors (id);
+ALTER TABLE measurements ADD COLUMN light_level_sensor_id INTEGER REFERENCES sensors (id);
+
+-- Every node that's already been heard from gets a station, there's no telling
+-- which sensors the old measurements came from though.
+INSERT INTO stations (node_id, first_seen, last_seen)
+    SELECT node_id, MIN(meas_time), MAX(meas_time) FROM measurements GROUP BY node_id;
+UPDATE measurements
+    SET station_id = (SELECT id FROM stations WHERE stations.node_id = measurements.node_id);
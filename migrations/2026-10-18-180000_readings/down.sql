@@ -0,0 +1,34 @@
+CREATE TABLE measurements_new (
+    id INTEGER PRIMARY KEY NOT NULL,
+    meas_time BIGINT NOT NULL,
+    temperature REAL,
+    humidity REAL,
+    pressure REAL,
+    light_level REAL,
+    meas_duration_us BIGINT,
+    clock_synced BOOLEAN NOT NULL DEFAULT 1,
+    boot_id TEXT,
+    monotonic_us BIGINT,
+    uploaded_at BIGINT,
+    node_id TEXT NOT NULL DEFAULT '',
+    uid TEXT,
+    station_id INTEGER REFERENCES stations (id),
+    temperature_sensor_id INTEGER REFERENCES sensors (id),
+    humidity_sensor_id INTEGER REFERENCES sensors (id),
+    pressure_sensor_id INTEGER REFERENCES sensors (id),
+    light_level_sensor_id INTEGER REFERENCES sensors (id)
+);
+
+-- Anything but the four quantities of the wide table is lost.
+INSERT INTO measurements_new SELECT * FROM measurements;
+
+DROP VIEW measurements;
+DROP TABLE readings;
+DROP TABLE readouts;
+
+ALTER TABLE measurements_new RENAME TO measurements;
+
+CREATE UNIQUE INDEX measurements_uid ON measurements (uid);
+CREATE INDEX measurements_node_time ON measurements (node_id, meas_time);
+CREATE INDEX measurements_unsynced ON measurements (boot_id) WHERE NOT clock_synced;
+CREATE INDEX measurements_not_uploaded ON measurements (id) WHERE uploaded_at IS NULL;
@@ -0,0 +1,89 @@
+-- One row per readout of a node's sensors, without the values.
+CREATE TABLE readouts (
+    id INTEGER PRIMARY KEY NOT NULL,
+    meas_time BIGINT NOT NULL,
+    meas_duration_us BIGINT,
+    clock_synced BOOLEAN NOT NULL DEFAULT 1,
+    boot_id TEXT,
+    monotonic_us BIGINT,
+    uploaded_at BIGINT,
+    node_id TEXT NOT NULL DEFAULT '',
+    uid TEXT,
+    station_id INTEGER REFERENCES stations (id)
+);
+
+-- One row per value, whatever the quantity. `time` is the readout's, repeated
+-- so that readings can be queried and pruned on their own.
+CREATE TABLE readings (
+    id INTEGER PRIMARY KEY NOT NULL,
+    readout_id INTEGER NOT NULL REFERENCES readouts (id),
+    time BIGINT NOT NULL,
+    sensor_id INTEGER REFERENCES sensors (id),
+    quantity TEXT NOT NULL,
+    value REAL NOT NULL,
+    unit TEXT NOT NULL,
+    quality TEXT NOT NULL DEFAULT 'good'
+);
+
+INSERT INTO readouts (
+    id, meas_time, meas_duration_us, clock_synced, boot_id, monotonic_us,
+    uploaded_at, node_id, uid, station_id
+)
+SELECT
+    id, meas_time, meas_duration_us, clock_synced, boot_id, monotonic_us,
+    uploaded_at, node_id, uid, station_id
+FROM measurements;
+
+INSERT INTO readings (readout_id, time, sensor_id, quantity, value, unit)
+    SELECT id, meas_time, temperature_sensor_id, 'temperature', temperature, '°C'
+    FROM measurements WHERE temperature IS NOT NULL;
+INSERT INTO readings (readout_id, time, sensor_id, quantity, value, unit)
+    SELECT id, meas_time, humidity_sensor_id, 'humidity', humidity, '%'
+    FROM measurements WHERE humidity IS NOT NULL;
+INSERT INTO readings (readout_id, time, sensor_id, quantity, value, unit)
+    SELECT id, meas_time, pressure_sensor_id, 'pressure', pressure, 'Pa'
+    FROM measurements WHERE pressure IS NOT NULL;
+INSERT INTO readings (readout_id, time, sensor_id, quantity, value, unit)
+    SELECT id, meas_time, light_level_sensor_id, 'light_level', light_level, '1'
+    FROM measurements WHERE light_level IS NOT NULL;
+
+DROP TABLE measurements;
+
+CREATE UNIQUE INDEX readouts_uid ON readouts (uid);
+CREATE INDEX readouts_node_time ON readouts (node_id, meas_time);
+CREATE INDEX readouts_unsynced ON readouts (boot_id) WHERE NOT clock_synced;
+CREATE INDEX readouts_not_uploaded ON readouts (id) WHERE uploaded_at IS NULL;
+
+CREATE INDEX readings_readout ON readings (readout_id, quantity);
+CREATE INDEX readings_time ON readings (time);
+
+-- The old wide table, for reading only.
+CREATE VIEW measurements AS
+SELECT
+    readouts.id,
+    meas_time,
+    (SELECT value FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'temperature') AS temperature,
+    (SELECT value FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'humidity') AS humidity,
+    (SELECT value FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'pressure') AS pressure,
+    (SELECT value FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'light_level') AS light_level,
+    meas_duration_us,
+    clock_synced,
+    boot_id,
+    monotonic_us,
+    uploaded_at,
+    node_id,
+    uid,
+    station_id,
+    (SELECT sensor_id FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'temperature') AS temperature_sensor_id,
+    (SELECT sensor_id FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'humidity') AS humidity_sensor_id,
+    (SELECT sensor_id FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'pressure') AS pressure_sensor_id,
+    (SELECT sensor_id FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'light_level') AS light_level_sensor_id
+FROM readouts;
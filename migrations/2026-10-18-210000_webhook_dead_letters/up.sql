@@ -0,0 +1,12 @@
+-- Webhook deliveries that were given up on, kept so that they can be looked
+-- into and sent again by hand.
+CREATE TABLE webhook_dead_letters (
+    id INTEGER PRIMARY KEY NOT NULL,
+    url TEXT NOT NULL,
+    body TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    -- Why the last attempt failed.
+    error TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    failed_at BIGINT NOT NULL
+);
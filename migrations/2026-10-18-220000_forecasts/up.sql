@@ -0,0 +1,16 @@
+-- The pressure tendency and Zambretti forecast of this node, computed from
+-- the pressure readings every now and then.
+CREATE TABLE forecasts (
+    id INTEGER PRIMARY KEY NOT NULL,
+    time BIGINT NOT NULL,
+    -- Reduced to sea level, in Pa.
+    sea_level_pressure REAL NOT NULL,
+    -- The change over the last three hours, in Pa.
+    pressure_change REAL NOT NULL,
+    tendency TEXT NOT NULL,
+    -- 1 to 32, the number of the forecast in Zambretti's table.
+    zambretti INTEGER NOT NULL,
+    forecast TEXT NOT NULL
+);
+
+CREATE INDEX forecasts_time ON forecasts (time);
@@ -0,0 +1,7 @@
+DROP VIEW measurements;
+DROP TABLE measurements_daily;
+DROP TABLE measurements_hourly;
+DROP TABLE readings;
+DROP TABLE readouts;
+DROP TABLE sensors;
+DROP TABLE stations;
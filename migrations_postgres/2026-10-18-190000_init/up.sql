@@ -0,0 +1,137 @@
+-- The same schema as the SQLite migrations end up with, except that times
+-- are TIMESTAMPTZ rather than microseconds since the epoch.
+
+CREATE TABLE stations (
+    id SERIAL PRIMARY KEY,
+    node_id TEXT NOT NULL UNIQUE,
+    hostname TEXT,
+    location TEXT,
+    first_seen TIMESTAMPTZ NOT NULL,
+    last_seen TIMESTAMPTZ NOT NULL
+);
+
+CREATE TABLE sensors (
+    id SERIAL PRIMARY KEY,
+    station_id INTEGER NOT NULL REFERENCES stations (id),
+    chip TEXT NOT NULL,
+    chip_id INTEGER,
+    i2c_bus TEXT,
+    i2c_address INTEGER,
+    calibration BYTEA,
+    first_seen TIMESTAMPTZ NOT NULL,
+    last_seen TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX sensors_station ON sensors (station_id);
+
+CREATE TABLE readouts (
+    id SERIAL PRIMARY KEY,
+    meas_time TIMESTAMPTZ NOT NULL,
+    meas_duration_us BIGINT,
+    clock_synced BOOLEAN NOT NULL DEFAULT TRUE,
+    boot_id TEXT,
+    monotonic_us BIGINT,
+    uploaded_at TIMESTAMPTZ,
+    node_id TEXT NOT NULL DEFAULT '',
+    uid TEXT,
+    station_id INTEGER REFERENCES stations (id)
+);
+
+CREATE UNIQUE INDEX readouts_uid ON readouts (uid);
+CREATE INDEX readouts_node_time ON readouts (node_id, meas_time);
+CREATE INDEX readouts_unsynced ON readouts (boot_id) WHERE NOT clock_synced;
+CREATE INDEX readouts_not_uploaded ON readouts (id) WHERE uploaded_at IS NULL;
+
+-- The time is part of the primary key so that the table can be turned into
+-- a Timescale hypertable.
+CREATE TABLE readings (
+    id SERIAL,
+    readout_id INTEGER NOT NULL REFERENCES readouts (id),
+    time TIMESTAMPTZ NOT NULL,
+    sensor_id INTEGER REFERENCES sensors (id),
+    quantity TEXT NOT NULL,
+    value REAL NOT NULL,
+    unit TEXT NOT NULL,
+    quality TEXT NOT NULL DEFAULT 'good',
+    PRIMARY KEY (id, time)
+);
+
+CREATE INDEX readings_readout ON readings (readout_id, quantity);
+CREATE INDEX readings_time ON readings (time);
+
+CREATE TABLE measurements_hourly (
+    node_id TEXT NOT NULL DEFAULT '',
+    bucket_start TIMESTAMPTZ NOT NULL,
+    count INTEGER NOT NULL,
+    temperature_count INTEGER NOT NULL DEFAULT 0,
+    temperature_min REAL,
+    temperature_max REAL,
+    temperature_sum REAL NOT NULL DEFAULT 0,
+    humidity_count INTEGER NOT NULL DEFAULT 0,
+    humidity_min REAL,
+    humidity_max REAL,
+    humidity_sum REAL NOT NULL DEFAULT 0,
+    pressure_count INTEGER NOT NULL DEFAULT 0,
+    pressure_min REAL,
+    pressure_max REAL,
+    pressure_sum REAL NOT NULL DEFAULT 0,
+    light_level_count INTEGER NOT NULL DEFAULT 0,
+    light_level_min REAL,
+    light_level_max REAL,
+    light_level_sum REAL NOT NULL DEFAULT 0,
+    PRIMARY KEY (node_id, bucket_start)
+);
+
+CREATE TABLE measurements_daily (
+    node_id TEXT NOT NULL DEFAULT '',
+    bucket_start TIMESTAMPTZ NOT NULL,
+    count INTEGER NOT NULL,
+    temperature_count INTEGER NOT NULL DEFAULT 0,
+    temperature_min REAL,
+    temperature_max REAL,
+    temperature_sum REAL NOT NULL DEFAULT 0,
+    humidity_count INTEGER NOT NULL DEFAULT 0,
+    humidity_min REAL,
+    humidity_max REAL,
+    humidity_sum REAL NOT NULL DEFAULT 0,
+    pressure_count INTEGER NOT NULL DEFAULT 0,
+    pressure_min REAL,
+    pressure_max REAL,
+    pressure_sum REAL NOT NULL DEFAULT 0,
+    light_level_count INTEGER NOT NULL DEFAULT 0,
+    light_level_min REAL,
+    light_level_max REAL,
+    light_level_sum REAL NOT NULL DEFAULT 0,
+    PRIMARY KEY (node_id, bucket_start)
+);
+
+-- The old wide table, for reading only.
+CREATE VIEW measurements AS
+SELECT
+    readouts.id,
+    meas_time,
+    (SELECT value FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'temperature') AS temperature,
+    (SELECT value FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'humidity') AS humidity,
+    (SELECT value FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'pressure') AS pressure,
+    (SELECT value FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'light_level') AS light_level,
+    meas_duration_us,
+    clock_synced,
+    boot_id,
+    monotonic_us,
+    uploaded_at,
+    node_id,
+    uid,
+    station_id,
+    (SELECT sensor_id FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'temperature') AS temperature_sensor_id,
+    (SELECT sensor_id FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'humidity') AS humidity_sensor_id,
+    (SELECT sensor_id FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'pressure') AS pressure_sensor_id,
+    (SELECT sensor_id FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'light_level') AS light_level_sensor_id
+FROM readouts;
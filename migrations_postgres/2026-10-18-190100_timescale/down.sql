@@ -0,0 +1,2 @@
+-- A hypertable can't be turned back into a plain table in place, the
+-- readings stay partitioned.
@@ -0,0 +1,10 @@
+-- Partitions the readings by time where TimescaleDB is installed, and does
+-- nothing where it isn't.
+DO $$
+BEGIN
+    IF EXISTS (SELECT FROM pg_available_extensions WHERE name = 'timescaledb') THEN
+        CREATE EXTENSION IF NOT EXISTS timescaledb;
+        PERFORM create_hypertable('readings', 'time', migrate_data => TRUE);
+    END IF;
+END
+$$;
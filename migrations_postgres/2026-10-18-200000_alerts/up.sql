@@ -0,0 +1,12 @@
+-- The state of each alert rule, so that a rule that's firing or waiting out
+-- its minimum duration stays so across restarts.
+CREATE TABLE alert_states (
+    rule TEXT PRIMARY KEY NOT NULL,
+    -- 'ok', 'pending' or 'firing'.
+    state TEXT NOT NULL,
+    -- When the rule went into the state.
+    since TIMESTAMPTZ NOT NULL,
+    -- The value last evaluated, e.g. the change in pressure for a rate rule.
+    value REAL,
+    updated_at TIMESTAMPTZ NOT NULL
+);
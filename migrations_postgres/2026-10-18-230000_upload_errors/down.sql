@@ -0,0 +1 @@
+ALTER TABLE readouts DROP COLUMN upload_error;
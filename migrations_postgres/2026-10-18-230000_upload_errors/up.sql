@@ -0,0 +1,3 @@
+-- Why the collector rejected the row, NULL unless it did. Rejected rows aren't
+-- uploaded again until this is cleared.
+ALTER TABLE readouts ADD COLUMN upload_error TEXT;
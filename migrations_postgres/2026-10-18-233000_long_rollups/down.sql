@@ -0,0 +1,126 @@
+-- Quantities other than the four of the wide tables are lost.
+
+CREATE TABLE measurements_hourly_wide (
+    node_id TEXT NOT NULL DEFAULT '',
+    bucket_start TIMESTAMPTZ NOT NULL,
+    count INTEGER NOT NULL,
+    temperature_count INTEGER NOT NULL DEFAULT 0,
+    temperature_min REAL,
+    temperature_max REAL,
+    temperature_sum REAL NOT NULL DEFAULT 0,
+    humidity_count INTEGER NOT NULL DEFAULT 0,
+    humidity_min REAL,
+    humidity_max REAL,
+    humidity_sum REAL NOT NULL DEFAULT 0,
+    pressure_count INTEGER NOT NULL DEFAULT 0,
+    pressure_min REAL,
+    pressure_max REAL,
+    pressure_sum REAL NOT NULL DEFAULT 0,
+    light_level_count INTEGER NOT NULL DEFAULT 0,
+    light_level_min REAL,
+    light_level_max REAL,
+    light_level_sum REAL NOT NULL DEFAULT 0,
+    PRIMARY KEY (node_id, bucket_start)
+);
+INSERT INTO measurements_hourly_wide
+SELECT
+    node_id, bucket_start, MAX(count),
+    SUM(CASE WHEN quantity = 'temperature' THEN count ELSE 0 END),
+    MIN(CASE WHEN quantity = 'temperature' THEN min END),
+    MAX(CASE WHEN quantity = 'temperature' THEN max END),
+    SUM(CASE WHEN quantity = 'temperature' THEN sum ELSE 0 END),
+    SUM(CASE WHEN quantity = 'humidity' THEN count ELSE 0 END),
+    MIN(CASE WHEN quantity = 'humidity' THEN min END),
+    MAX(CASE WHEN quantity = 'humidity' THEN max END),
+    SUM(CASE WHEN quantity = 'humidity' THEN sum ELSE 0 END),
+    SUM(CASE WHEN quantity = 'pressure' THEN count ELSE 0 END),
+    MIN(CASE WHEN quantity = 'pressure' THEN min END),
+    MAX(CASE WHEN quantity = 'pressure' THEN max END),
+    SUM(CASE WHEN quantity = 'pressure' THEN sum ELSE 0 END),
+    SUM(CASE WHEN quantity = 'light_level' THEN count ELSE 0 END),
+    MIN(CASE WHEN quantity = 'light_level' THEN min END),
+    MAX(CASE WHEN quantity = 'light_level' THEN max END),
+    SUM(CASE WHEN quantity = 'light_level' THEN sum ELSE 0 END)
+FROM measurements_hourly
+WHERE quantity IN ('temperature', 'humidity', 'pressure', 'light_level')
+GROUP BY node_id, bucket_start;
+DROP TABLE measurements_hourly;
+ALTER TABLE measurements_hourly_wide RENAME TO measurements_hourly;
+
+CREATE TABLE measurements_daily_wide (
+    node_id TEXT NOT NULL DEFAULT '',
+    bucket_start TIMESTAMPTZ NOT NULL,
+    count INTEGER NOT NULL,
+    temperature_count INTEGER NOT NULL DEFAULT 0,
+    temperature_min REAL,
+    temperature_max REAL,
+    temperature_sum REAL NOT NULL DEFAULT 0,
+    humidity_count INTEGER NOT NULL DEFAULT 0,
+    humidity_min REAL,
+    humidity_max REAL,
+    humidity_sum REAL NOT NULL DEFAULT 0,
+    pressure_count INTEGER NOT NULL DEFAULT 0,
+    pressure_min REAL,
+    pressure_max REAL,
+    pressure_sum REAL NOT NULL DEFAULT 0,
+    light_level_count INTEGER NOT NULL DEFAULT 0,
+    light_level_min REAL,
+    light_level_max REAL,
+    light_level_sum REAL NOT NULL DEFAULT 0,
+    PRIMARY KEY (node_id, bucket_start)
+);
+INSERT INTO measurements_daily_wide
+SELECT
+    node_id, bucket_start, MAX(count),
+    SUM(CASE WHEN quantity = 'temperature' THEN count ELSE 0 END),
+    MIN(CASE WHEN quantity = 'temperature' THEN min END),
+    MAX(CASE WHEN quantity = 'temperature' THEN max END),
+    SUM(CASE WHEN quantity = 'temperature' THEN sum ELSE 0 END),
+    SUM(CASE WHEN quantity = 'humidity' THEN count ELSE 0 END),
+    MIN(CASE WHEN quantity = 'humidity' THEN min END),
+    MAX(CASE WHEN quantity = 'humidity' THEN max END),
+    SUM(CASE WHEN quantity = 'humidity' THEN sum ELSE 0 END),
+    SUM(CASE WHEN quantity = 'pressure' THEN count ELSE 0 END),
+    MIN(CASE WHEN quantity = 'pressure' THEN min END),
+    MAX(CASE WHEN quantity = 'pressure' THEN max END),
+    SUM(CASE WHEN quantity = 'pressure' THEN sum ELSE 0 END),
+    SUM(CASE WHEN quantity = 'light_level' THEN count ELSE 0 END),
+    MIN(CASE WHEN quantity = 'light_level' THEN min END),
+    MAX(CASE WHEN quantity = 'light_level' THEN max END),
+    SUM(CASE WHEN quantity = 'light_level' THEN sum ELSE 0 END)
+FROM measurements_daily
+WHERE quantity IN ('temperature', 'humidity', 'pressure', 'light_level')
+GROUP BY node_id, bucket_start;
+DROP TABLE measurements_daily;
+ALTER TABLE measurements_daily_wide RENAME TO measurements_daily;
+
+-- The old wide table, for reading only.
+CREATE VIEW measurements AS
+SELECT
+    readouts.id,
+    meas_time,
+    (SELECT value FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'temperature') AS temperature,
+    (SELECT value FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'humidity') AS humidity,
+    (SELECT value FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'pressure') AS pressure,
+    (SELECT value FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'light_level') AS light_level,
+    meas_duration_us,
+    clock_synced,
+    boot_id,
+    monotonic_us,
+    uploaded_at,
+    node_id,
+    uid,
+    station_id,
+    (SELECT sensor_id FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'temperature') AS temperature_sensor_id,
+    (SELECT sensor_id FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'humidity') AS humidity_sensor_id,
+    (SELECT sensor_id FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'pressure') AS pressure_sensor_id,
+    (SELECT sensor_id FROM readings
+     WHERE readout_id = readouts.id AND quantity = 'light_level') AS light_level_sensor_id
+FROM readouts;
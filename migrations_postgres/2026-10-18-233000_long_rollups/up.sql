@@ -0,0 +1,65 @@
+-- One rollup row per quantity rather than columns for each, so that a new
+-- quantity doesn't need a migration.
+
+CREATE TABLE measurements_hourly_long (
+    node_id TEXT NOT NULL DEFAULT '',
+    bucket_start TIMESTAMPTZ NOT NULL,
+    quantity TEXT NOT NULL,
+    unit TEXT NOT NULL,
+    count INTEGER NOT NULL,
+    min REAL NOT NULL,
+    max REAL NOT NULL,
+    sum REAL NOT NULL,
+    PRIMARY KEY (node_id, bucket_start, quantity)
+);
+INSERT INTO measurements_hourly_long
+    SELECT node_id, bucket_start, 'temperature', '°C',
+        temperature_count, temperature_min, temperature_max, temperature_sum
+    FROM measurements_hourly WHERE temperature_count > 0;
+INSERT INTO measurements_hourly_long
+    SELECT node_id, bucket_start, 'humidity', '%',
+        humidity_count, humidity_min, humidity_max, humidity_sum
+    FROM measurements_hourly WHERE humidity_count > 0;
+INSERT INTO measurements_hourly_long
+    SELECT node_id, bucket_start, 'pressure', 'Pa',
+        pressure_count, pressure_min, pressure_max, pressure_sum
+    FROM measurements_hourly WHERE pressure_count > 0;
+INSERT INTO measurements_hourly_long
+    SELECT node_id, bucket_start, 'light_level', '1',
+        light_level_count, light_level_min, light_level_max, light_level_sum
+    FROM measurements_hourly WHERE light_level_count > 0;
+DROP TABLE measurements_hourly;
+ALTER TABLE measurements_hourly_long RENAME TO measurements_hourly;
+
+CREATE TABLE measurements_daily_long (
+    node_id TEXT NOT NULL DEFAULT '',
+    bucket_start TIMESTAMPTZ NOT NULL,
+    quantity TEXT NOT NULL,
+    unit TEXT NOT NULL,
+    count INTEGER NOT NULL,
+    min REAL NOT NULL,
+    max REAL NOT NULL,
+    sum REAL NOT NULL,
+    PRIMARY KEY (node_id, bucket_start, quantity)
+);
+INSERT INTO measurements_daily_long
+    SELECT node_id, bucket_start, 'temperature', '°C',
+        temperature_count, temperature_min, temperature_max, temperature_sum
+    FROM measurements_daily WHERE temperature_count > 0;
+INSERT INTO measurements_daily_long
+    SELECT node_id, bucket_start, 'humidity', '%',
+        humidity_count, humidity_min, humidity_max, humidity_sum
+    FROM measurements_daily WHERE humidity_count > 0;
+INSERT INTO measurements_daily_long
+    SELECT node_id, bucket_start, 'pressure', 'Pa',
+        pressure_count, pressure_min, pressure_max, pressure_sum
+    FROM measurements_daily WHERE pressure_count > 0;
+INSERT INTO measurements_daily_long
+    SELECT node_id, bucket_start, 'light_level', '1',
+        light_level_count, light_level_min, light_level_max, light_level_sum
+    FROM measurements_daily WHERE light_level_count > 0;
+DROP TABLE measurements_daily;
+ALTER TABLE measurements_daily_long RENAME TO measurements_daily;
+
+-- Everything reads the readouts and their readings directly now.
+DROP VIEW measurements;
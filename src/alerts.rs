@@ -0,0 +1,742 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{Local, NaiveTime, TimeDelta};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use tokio::task;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::config::optional_var;
+use crate::db::alerts::StoredAlertState;
+use crate::db::{self, DateTimeUtc, DbConnection, InsertableMeasurement, LOCAL_NODE_ID};
+use crate::metrics::METRICS;
+use crate::notify::{Event, Notifiers};
+
+#[derive(Debug, Clone)]
+pub struct AlertConfig {
+    rules: Vec<Rule>,
+}
+
+impl AlertConfig {
+    const RULES_PATH_ENV_VAR: &'static str = "ALERT_RULES_PATH";
+
+    /// Returns `None` if there's no rules file.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(path) = optional_var::<PathBuf>(Self::RULES_PATH_ENV_VAR)? else {
+            return Ok(None);
+        };
+
+        let rules = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let specs: Vec<RuleSpec> = serde_json::from_str(&rules)
+            .with_context(|| format!("Invalid alert rules in {}", path.display()))?;
+
+        let mut names = HashSet::new();
+        let rules = specs
+            .into_iter()
+            .map(|spec| {
+                if !names.insert(spec.name.clone()) {
+                    return Err(anyhow!("There's more than one alert rule {:?}.", spec.name));
+                }
+                let name = spec.name.clone();
+                Rule::try_from(spec).with_context(|| format!("Invalid alert rule {name:?}"))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Some(Self { rules }))
+    }
+}
+
+/// A rule as written in the rules file, e.g.
+/// `{"name": "hot", "quantity": "temperature", "above": 30, "for_secs": 300}`.
+/// Values are in the units they're stored in, i.e. pressure in Pa and the
+/// light level relative to the sensor's full scale.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    name: String,
+    quantity: String,
+    /// Exactly one of the conditions. `rise` and `drop` are changes over
+    /// `window_secs`.
+    above: Option<f32>,
+    below: Option<f32>,
+    rise: Option<f32>,
+    drop: Option<f32>,
+    window_secs: Option<u64>,
+    /// How far back past the threshold the value has to go for a firing
+    /// alert to resolve.
+    #[serde(default)]
+    hysteresis: f32,
+    /// How long the condition has to hold before the alert fires.
+    #[serde(default)]
+    for_secs: u64,
+    /// Local times ("HH:MM") between which the rule applies, which may wrap
+    /// around midnight. Always if not given.
+    between: Option<[String; 2]>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Condition {
+    Above,
+    Below,
+    Rise,
+    Drop,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    name: String,
+    quantity: &'static str,
+    unit: &'static str,
+    condition: Condition,
+    threshold: f32,
+    window: Option<TimeDelta>,
+    hysteresis: f32,
+    min_duration: TimeDelta,
+    between: Option<(NaiveTime, NaiveTime)>,
+}
+
+impl TryFrom<RuleSpec> for Rule {
+    type Error = anyhow::Error;
+
+    fn try_from(spec: RuleSpec) -> Result<Self> {
+        let (quantity, unit) = db::readings::QUANTITIES
+            .into_iter()
+            .find(|(quantity, _)| *quantity == spec.quantity)
+            .ok_or_else(|| anyhow!("Unknown quantity {:?}", spec.quantity))?;
+
+        let conditions = [
+            (Condition::Above, spec.above),
+            (Condition::Below, spec.below),
+            (Condition::Rise, spec.rise),
+            (Condition::Drop, spec.drop),
+        ]
+        .into_iter()
+        .filter_map(|(condition, threshold)| Some((condition, threshold?)))
+        .collect::<Vec<_>>();
+        let [(condition, threshold)] = conditions[..] else {
+            return Err(anyhow!(
+                "Exactly one of above, below, rise and drop has to be given."
+            ));
+        };
+
+        let window = match (condition, spec.window_secs) {
+            (Condition::Rise | Condition::Drop, Some(secs)) if secs > 0 => {
+                Some(TimeDelta::seconds(secs as i64))
+            }
+            (Condition::Rise | Condition::Drop, _) => {
+                return Err(anyhow!("A rise or drop needs a window_secs."));
+            }
+            (_, Some(_)) => return Err(anyhow!("Only a rise or drop has a window_secs.")),
+            (_, None) => None,
+        };
+
+        if spec.hysteresis < 0.0 {
+            return Err(anyhow!("The hysteresis can't be negative."));
+        }
+
+        let between = spec
+            .between
+            .map(|[start, end]| -> Result<_> {
+                Ok((
+                    NaiveTime::parse_from_str(&start, "%H:%M")?,
+                    NaiveTime::parse_from_str(&end, "%H:%M")?,
+                ))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            name: spec.name,
+            quantity,
+            unit,
+            condition,
+            threshold,
+            window,
+            hysteresis: spec.hysteresis,
+            min_duration: TimeDelta::seconds(spec.for_secs as i64),
+            between,
+        })
+    }
+}
+
+impl Rule {
+    /// Whether the rule applies at the time, i.e. whether it's within the
+    /// rule's hours.
+    fn applies_at(&self, time: &DateTimeUtc) -> bool {
+        let Some((start, end)) = self.between else {
+            return true;
+        };
+        let time = time.with_timezone(&Local).time();
+
+        if start <= end {
+            start <= time && time < end
+        } else {
+            start <= time || time < end
+        }
+    }
+
+    /// What a rule in `state` since `since` moves on to at `time`, depending
+    /// on whether the threshold is breached.
+    ///
+    /// How long a rule has been pending can't be told from times taken before
+    /// the clock was synchronised, so a rule only starts pending with a
+    /// synchronised clock and starts over when it isn't. It starts over as
+    /// well when the clock has been set back past the time it started pending.
+    fn next_state(
+        &self,
+        state: State,
+        since: Option<&DateTimeUtc>,
+        time: &DateTimeUtc,
+        clock_synced: bool,
+        breached: bool,
+    ) -> State {
+        match (state, breached) {
+            (State::Firing, true) => State::Firing,
+            (State::Firing, false) => State::Ok,
+            (State::Ok | State::Pending, false) => State::Ok,
+            (State::Ok, true) if self.min_duration <= TimeDelta::zero() => State::Firing,
+            (State::Ok | State::Pending, true) if !clock_synced => State::Ok,
+            (State::Ok, true) => State::Pending,
+            (State::Pending, true) => {
+                let pending_for = since.map_or(TimeDelta::zero(), |since| **time - **since);
+                if pending_for < TimeDelta::zero() {
+                    State::Ok
+                } else if pending_for >= self.min_duration {
+                    State::Firing
+                } else {
+                    State::Pending
+                }
+            }
+        }
+    }
+
+    /// Whether the value breaches the threshold. One that's already firing
+    /// only stops once it's back past the threshold by the hysteresis.
+    fn is_breached(&self, value: f32, firing: bool) -> bool {
+        let hysteresis = if firing { self.hysteresis } else { 0.0 };
+
+        match self.condition {
+            Condition::Below => value < self.threshold + hysteresis,
+            Condition::Above | Condition::Rise | Condition::Drop => {
+                value > self.threshold - hysteresis
+            }
+        }
+    }
+
+    fn describe(&self, value: f32) -> String {
+        // Light levels are relative and have no unit to show.
+        let unit = match self.unit {
+            "1" => String::new(),
+            unit => format!(" {unit}"),
+        };
+        let quantity = self.quantity;
+        let threshold = self.threshold;
+        let window = self.window.unwrap_or_default().num_seconds();
+
+        match self.condition {
+            Condition::Above | Condition::Below => {
+                format!("{quantity} is {value}{unit}, limit {threshold}{unit}")
+            }
+            Condition::Rise => {
+                format!("{quantity} rose by {value}{unit} in {window} s, limit {threshold}{unit}")
+            }
+            Condition::Drop => format!(
+                "{quantity} dropped by {value}{unit} in {window} s, limit {threshold}{unit}"
+            ),
+        }
+    }
+}
+
+/// The value of the quantity in the measurement, if it has one.
+fn quantity_value(measurement: &InsertableMeasurement, quantity: &str) -> Option<f32> {
+    db::readings::quantities(measurement)
+        .into_iter()
+        .find(|(name, ..)| *name == quantity)
+        .and_then(|(_, value, ..)| value)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ok,
+    /// Breached, but not yet for long enough.
+    Pending,
+    Firing,
+}
+
+impl State {
+    fn as_str(self) -> &'static str {
+        match self {
+            State::Ok => "ok",
+            State::Pending => "pending",
+            State::Firing => "firing",
+        }
+    }
+
+    fn parse(state: &str) -> Option<State> {
+        match state {
+            "ok" => Some(State::Ok),
+            "pending" => Some(State::Pending),
+            "firing" => Some(State::Firing),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertStatus {
+    Firing,
+    Resolved,
+}
+
+/// An alert firing or resolving.
+#[derive(Debug, Clone, Serialize)]
+pub struct AlertEvent {
+    pub rule: String,
+    pub status: AlertStatus,
+    pub quantity: &'static str,
+    /// The value the rule was evaluated on, the change for a rise or drop.
+    pub value: f32,
+    pub threshold: f32,
+    pub unit: &'static str,
+    pub time: DateTimeUtc,
+    pub message: String,
+}
+
+impl fmt::Display for AlertEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match self.status {
+            AlertStatus::Firing => "firing",
+            AlertStatus::Resolved => "resolved",
+        };
+
+        write!(f, "Alert {:?} {status}: {}", self.rule, self.message)
+    }
+}
+
+/// Evaluates the alert rules on every new measurement of this node and sends
+/// the resulting events to the notifiers. The rules' states are kept in the
+/// DB, so that the minimum durations and firing alerts carry over restarts.
+pub struct AlertEngine {
+    rules: Vec<Rule>,
+    states: HashMap<String, StoredAlertState>,
+    db_conn: Arc<Mutex<DbConnection>>,
+    notifiers: Notifiers,
+}
+
+impl AlertEngine {
+    pub fn new(
+        config: AlertConfig,
+        db_conn: Arc<Mutex<DbConnection>>,
+        notifiers: Notifiers,
+    ) -> QueryResult<Self> {
+        let mut conn = db_conn.lock().unwrap();
+
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| rule.name.as_str())
+            .collect::<Vec<_>>();
+        let removed = db::alerts::delete_other_alert_states(&mut conn, &rules)?;
+        if removed > 0 {
+            log::info!(
+                "Deleted the states of {removed} alert rules that are no longer configured."
+            );
+        }
+
+        let states = db::alerts::alert_states(&mut conn)?
+            .into_iter()
+            .map(|state| (state.rule.clone(), state))
+            .collect();
+        drop(conn);
+
+        let engine = Self {
+            rules: config.rules,
+            states,
+            db_conn,
+            notifiers,
+        };
+        engine.update_firing_metric();
+
+        Ok(engine)
+    }
+
+    fn state(&self, rule: &Rule) -> (State, Option<&DateTimeUtc>) {
+        self.states
+            .get(&rule.name)
+            .and_then(|stored| Some((State::parse(&stored.state)?, Some(&stored.since))))
+            .unwrap_or((State::Ok, None))
+    }
+
+    fn update_firing_metric(&self) {
+        let firing = self
+            .rules
+            .iter()
+            .filter(|rule| self.state(rule).0 == State::Firing)
+            .count();
+
+        METRICS.alerts_firing.set(firing as f64);
+    }
+
+    /// The values the rules are evaluated on, `None` where there's nothing to
+    /// go by. A rise or drop is relative to the last reading at least the
+    /// window before, as long as that isn't older than twice the window. That
+    /// reading may still be in the write buffer, among `buffered`.
+    async fn rule_values(
+        &self,
+        measurement: &InsertableMeasurement,
+        buffered: &[InsertableMeasurement],
+    ) -> Result<Vec<Option<f32>>> {
+        let now = *measurement.meas_time;
+
+        // Comparing with earlier readings needs times that are comparable.
+        let lookups = self
+            .rules
+            .iter()
+            .map(|rule| {
+                let window = rule.window.filter(|_| measurement.clock_synced)?;
+                Some((rule.quantity, DateTimeUtc::from(now - window)))
+            })
+            .collect::<Vec<_>>();
+
+        let buffered = lookups
+            .iter()
+            .map(|lookup| {
+                let (quantity, before) = lookup.as_ref()?;
+                buffered
+                    .iter()
+                    .filter(|buffered| buffered.clock_synced && *buffered.meas_time <= **before)
+                    .filter_map(|buffered| {
+                        Some((*buffered.meas_time, quantity_value(buffered, quantity)?))
+                    })
+                    .max_by_key(|(time, _)| *time)
+            })
+            .collect::<Vec<_>>();
+
+        let db_conn = self.db_conn.clone();
+        let stored = task::spawn_blocking(move || {
+            let mut conn = db_conn.lock().unwrap();
+
+            lookups
+                .into_iter()
+                .map(|lookup| {
+                    let Some((quantity, before)) = lookup else {
+                        return Ok(None);
+                    };
+                    let reading =
+                        db::query::reading_before(&mut conn, LOCAL_NODE_ID, quantity, &before)?;
+
+                    Ok(reading.map(|(time, value)| (*time, value)))
+                })
+                .collect::<QueryResult<Vec<_>>>()
+        })
+        .await??;
+
+        let values = self
+            .rules
+            .iter()
+            .zip(stored.into_iter().zip(buffered))
+            .map(|(rule, (stored, buffered))| {
+                let value = quantity_value(measurement, rule.quantity)?;
+                let earlier = [stored, buffered]
+                    .into_iter()
+                    .flatten()
+                    .max_by_key(|(time, _)| *time)
+                    .filter(|(time, _)| rule.window.is_some_and(|window| *time >= now - window * 2))
+                    .map(|(_, value)| value);
+
+                match rule.condition {
+                    Condition::Above | Condition::Below => Some(value),
+                    Condition::Rise => Some(value - earlier?),
+                    Condition::Drop => Some(earlier? - value),
+                }
+            })
+            .collect();
+
+        Ok(values)
+    }
+
+    /// Moves each rule on according to the measurement, stores the states of
+    /// those whose state changed, and notifies about the alerts that fired or
+    /// resolved. A rule without a value to go by stays as it is. `buffered`
+    /// are the measurements that haven't been written to the DB yet.
+    pub async fn evaluate(
+        &mut self,
+        measurement: &InsertableMeasurement,
+        buffered: &[InsertableMeasurement],
+    ) -> Result<()> {
+        let values = self.rule_values(measurement, buffered).await?;
+        let time = &measurement.meas_time;
+
+        let mut changed = Vec::new();
+        let mut events = Vec::new();
+
+        for (rule, value) in self.rules.iter().zip(values) {
+            let Some(value) = value else {
+                continue;
+            };
+            let (state, since) = self.state(rule);
+            let breached = rule.applies_at(time) && rule.is_breached(value, state == State::Firing);
+
+            let new_state = rule.next_state(state, since, time, measurement.clock_synced, breached);
+
+            let status = match (state, new_state) {
+                (State::Firing, State::Ok) => Some(AlertStatus::Resolved),
+                (State::Ok | State::Pending, State::Firing) => Some(AlertStatus::Firing),
+                _ => None,
+            };
+            if let Some(status) = status {
+                events.push(AlertEvent {
+                    rule: rule.name.clone(),
+                    status,
+                    quantity: rule.quantity,
+                    value,
+                    threshold: rule.threshold,
+                    unit: rule.unit,
+                    time: time.clone(),
+                    message: rule.describe(value),
+                });
+            }
+
+            // Stored with the value that made it change.
+            if new_state != state {
+                changed.push(StoredAlertState {
+                    rule: rule.name.clone(),
+                    state: new_state.as_str().to_owned(),
+                    since: time.clone(),
+                    value: Some(value),
+                });
+            }
+        }
+
+        let db_conn = self.db_conn.clone();
+        let changed = task::spawn_blocking(move || {
+            db_conn.lock().unwrap().transaction(|conn| {
+                for state in &changed {
+                    db::alerts::save_alert_state(conn, state)?;
+                }
+
+                QueryResult::Ok(changed)
+            })
+        })
+        .await??;
+
+        for state in changed {
+            self.states.insert(state.rule.clone(), state);
+        }
+        self.update_firing_metric();
+
+        for event in events {
+            self.notifiers.notify(&Event::Alert(event));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_connection;
+    use crate::notify::Notifier;
+
+    fn rule(spec: &str) -> Rule {
+        Rule::try_from(serde_json::from_str::<RuleSpec>(spec).unwrap()).unwrap()
+    }
+
+    fn at(secs: i64) -> DateTimeUtc {
+        DateTimeUtc::from_micros(1_800_000_000_000_000 + secs * 1_000_000).unwrap()
+    }
+
+    #[test]
+    fn hysteresis_only_applies_once_firing() {
+        let hot =
+            rule(r#"{"name": "hot", "quantity": "temperature", "above": 30, "hysteresis": 2}"#);
+        assert!(!hot.is_breached(29.5, false));
+        assert!(hot.is_breached(30.5, false));
+        assert!(hot.is_breached(29.5, true));
+        assert!(!hot.is_breached(27.5, true));
+
+        let cold =
+            rule(r#"{"name": "cold", "quantity": "temperature", "below": 5, "hysteresis": 1}"#);
+        assert!(!cold.is_breached(5.5, false));
+        assert!(cold.is_breached(4.5, false));
+        assert!(cold.is_breached(5.5, true));
+        assert!(!cold.is_breached(6.5, true));
+    }
+
+    #[test]
+    fn fires_right_away_without_a_minimum_duration() {
+        let hot = rule(r#"{"name": "hot", "quantity": "temperature", "above": 30}"#);
+
+        assert_eq!(
+            hot.next_state(State::Ok, None, &at(0), true, true),
+            State::Firing
+        );
+        assert_eq!(
+            hot.next_state(State::Firing, Some(&at(0)), &at(60), true, false),
+            State::Ok
+        );
+    }
+
+    #[test]
+    fn fires_once_breached_for_the_minimum_duration() {
+        let hot =
+            rule(r#"{"name": "hot", "quantity": "temperature", "above": 30, "for_secs": 300}"#);
+
+        assert_eq!(
+            hot.next_state(State::Ok, None, &at(0), true, true),
+            State::Pending
+        );
+        assert_eq!(
+            hot.next_state(State::Pending, Some(&at(0)), &at(299), true, true),
+            State::Pending
+        );
+        assert_eq!(
+            hot.next_state(State::Pending, Some(&at(0)), &at(300), true, true),
+            State::Firing
+        );
+        assert_eq!(
+            hot.next_state(State::Pending, Some(&at(0)), &at(200), true, false),
+            State::Ok
+        );
+        assert_eq!(
+            hot.next_state(State::Firing, Some(&at(300)), &at(400), true, true),
+            State::Firing
+        );
+    }
+
+    #[test]
+    fn only_pends_with_a_synchronised_clock() {
+        let hot =
+            rule(r#"{"name": "hot", "quantity": "temperature", "above": 30, "for_secs": 300}"#);
+
+        // Unsynchronised times say nothing about how long it's been breached.
+        assert_eq!(
+            hot.next_state(State::Ok, None, &at(0), false, true),
+            State::Ok
+        );
+        assert_eq!(
+            hot.next_state(State::Pending, Some(&at(0)), &at(-1_000_000), false, true),
+            State::Ok
+        );
+        // Once synchronised, the minimum duration counts from then on.
+        assert_eq!(
+            hot.next_state(State::Ok, None, &at(1_000_000), true, true),
+            State::Pending
+        );
+        assert_eq!(
+            hot.next_state(
+                State::Pending,
+                Some(&at(1_000_000)),
+                &at(1_000_060),
+                true,
+                true
+            ),
+            State::Pending
+        );
+
+        // A rule without a minimum duration doesn't have to wait.
+        let hotter = rule(r#"{"name": "hotter", "quantity": "temperature", "above": 35}"#);
+        assert_eq!(
+            hotter.next_state(State::Ok, None, &at(0), false, true),
+            State::Firing
+        );
+    }
+
+    #[test]
+    fn starts_pending_over_after_the_clock_is_set_back() {
+        let hot =
+            rule(r#"{"name": "hot", "quantity": "temperature", "above": 30, "for_secs": 300}"#);
+
+        assert_eq!(
+            hot.next_state(State::Pending, Some(&at(3_600)), &at(0), true, true),
+            State::Ok
+        );
+        assert_eq!(
+            hot.next_state(State::Ok, None, &at(60), true, true),
+            State::Pending
+        );
+        assert_eq!(
+            hot.next_state(State::Pending, Some(&at(60)), &at(360), true, true),
+            State::Firing
+        );
+    }
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<AlertStatus>>);
+
+    impl Notifier for Recorder {
+        fn notify(&self, event: &Event) {
+            if let Event::Alert(alert) = event {
+                self.0.lock().unwrap().push(alert.status);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn rise_is_relative_to_buffered_measurements() {
+        let config = AlertConfig {
+            rules: vec![rule(
+                r#"{"name": "warming", "quantity": "temperature", "rise": 2,
+                    "window_secs": 600}"#,
+            )],
+        };
+        let recorder = Arc::new(Recorder::default());
+        let db_conn = Arc::new(Mutex::new(test_connection()));
+        let mut engine = AlertEngine::new(
+            config,
+            db_conn.clone(),
+            Notifiers::new(vec![recorder.clone()]),
+        )
+        .unwrap();
+
+        let measurement = |secs, temperature| InsertableMeasurement {
+            meas_time: at(secs),
+            temperature: Some(temperature),
+            ..InsertableMeasurement::example()
+        };
+        let buffered = [measurement(0, 20.0), measurement(300, 21.0)];
+
+        engine
+            .evaluate(&measurement(700, 22.5), &buffered)
+            .await
+            .unwrap();
+        assert_eq!(*recorder.0.lock().unwrap(), [AlertStatus::Firing]);
+
+        let states = db::alerts::alert_states(&mut db_conn.lock().unwrap()).unwrap();
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0].state, "firing");
+        assert_eq!(states[0].value, Some(2.5));
+    }
+
+    #[test]
+    fn forgets_the_states_of_removed_rules() {
+        let mut conn = test_connection();
+        for rule in ["hot", "gone"] {
+            let state = StoredAlertState {
+                rule: rule.to_owned(),
+                state: "firing".to_owned(),
+                since: at(0),
+                value: Some(31.0),
+            };
+            db::alerts::save_alert_state(&mut conn, &state).unwrap();
+        }
+
+        let config = AlertConfig {
+            rules: vec![rule(
+                r#"{"name": "hot", "quantity": "temperature", "above": 30}"#,
+            )],
+        };
+        let db_conn = Arc::new(Mutex::new(conn));
+        let engine = AlertEngine::new(config, db_conn.clone(), Notifiers::new(Vec::new())).unwrap();
+
+        assert_eq!(engine.states.keys().collect::<Vec<_>>(), ["hot"]);
+        let stored = db::alerts::alert_states(&mut db_conn.lock().unwrap()).unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].rule, "hot");
+    }
+}
@@ -0,0 +1,603 @@
+use anyhow::{anyhow, Result};
+use axum::body::Bytes;
+use axum::extract::rejection::QueryRejection;
+use axum::extract::{Query, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::task;
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::collect::{self, UploadReceiver};
+use crate::db::{self, query::Bucket, DbConnection};
+use crate::enviro_phat::{EnviroPHat, MeasureEnvironment};
+use crate::metrics::METRICS;
+use crate::upload::UploadBatch;
+
+const PRESSURE_UNIT: &str = "Pa";
+
+const DEFAULT_LIMIT: i64 = 1000;
+const MAX_LIMIT: i64 = 10_000;
+
+#[derive(Clone)]
+struct AppState {
+    db_conn: Arc<Mutex<DbConnection>>,
+    enviro_phat: Option<Arc<EnviroPHat>>,
+    uploads: Option<UploadReceiver>,
+}
+
+/// Serves the API until shutdown.
+pub async fn serve(
+    listen_addr: SocketAddr,
+    db_conn: Arc<Mutex<DbConnection>>,
+    enviro_phat: Option<Arc<EnviroPHat>>,
+    uploads: Option<UploadReceiver>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let app = router(db_conn, enviro_phat, uploads);
+
+    let listener = TcpListener::bind(listen_addr).await?;
+    log::info!("HTTP API listening on {listen_addr}");
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move {
+            let _ = shutdown.wait_for(|&shutdown| shutdown).await;
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// The API's routes. `/measure` is only there with sensors, and `/upload`
+/// only when collecting.
+fn router(
+    db_conn: Arc<Mutex<DbConnection>>,
+    enviro_phat: Option<Arc<EnviroPHat>>,
+    uploads: Option<UploadReceiver>,
+) -> Router {
+    let mut app = Router::new()
+        .route("/measurements", get(get_measurements))
+        .route("/measurements/latest", get(get_latest_measurement))
+        .route("/measurements/aggregate", get(get_aggregate))
+        .route("/nodes", get(get_nodes))
+        .route("/forecast", get(get_forecast))
+        .route("/metrics", get(get_metrics));
+
+    if enviro_phat.is_some() {
+        app = app.route("/measure", post(post_measure));
+    }
+    if uploads.is_some() {
+        app = app.route("/upload", post(post_upload));
+    }
+
+    app.with_state(AppState {
+        db_conn,
+        enviro_phat,
+        uploads,
+    })
+}
+
+/// An error is a 500 unless it's the request's fault.
+struct ApiError {
+    status: StatusCode,
+    error: anyhow::Error,
+}
+
+impl ApiError {
+    fn bad_request(error: impl Into<anyhow::Error>) -> Self {
+        ApiError {
+            status: StatusCode::BAD_REQUEST,
+            error: error.into(),
+        }
+    }
+}
+
+impl<E: Into<anyhow::Error>> From<E> for ApiError {
+    fn from(err: E) -> Self {
+        ApiError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            error: err.into(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if self.status.is_server_error() {
+            log::error!("API request failed: {:#}", self.error);
+        }
+
+        error_response(self.status, &format!("{:#}", self.error))
+    }
+}
+
+fn error_response(status: StatusCode, error: &str) -> Response {
+    (status, Json(serde_json::json!({ "error": error }))).into_response()
+}
+
+type ApiResult<T> = std::result::Result<Json<T>, ApiError>;
+
+/// The query parameters, or a 400 if they can't be parsed.
+fn query_params<T>(params: Result<Query<T>, QueryRejection>) -> Result<T, ApiError> {
+    let Query(params) = params.map_err(|e| ApiError::bad_request(anyhow!(e.body_text())))?;
+
+    Ok(params)
+}
+
+/// The range as stored times, or a 400 if it ends before it starts.
+fn time_range(
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> Result<(Option<db::DateTimeUtc>, Option<db::DateTimeUtc>), ApiError> {
+    if let (Some(from), Some(to)) = (from, to) {
+        if from > to {
+            return Err(ApiError::bad_request(anyhow!(
+                "from ({from}) is after to ({to})"
+            )));
+        }
+    }
+
+    Ok((
+        from.map(db::DateTimeUtc::from),
+        to.map(db::DateTimeUtc::from),
+    ))
+}
+
+/// Runs a blocking DB query on the shared connection.
+async fn with_db<T, F>(state: &AppState, f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut DbConnection) -> diesel::QueryResult<T> + Send + 'static,
+{
+    let db_conn = state.db_conn.clone();
+    let res = task::spawn_blocking(move || f(&mut db_conn.lock().unwrap())).await??;
+
+    Ok(res)
+}
+
+#[derive(Debug, Serialize)]
+struct Value {
+    value: f32,
+    unit: String,
+}
+
+/// A measurement with a field for each quantity it has a reading of.
+#[derive(Debug, Serialize)]
+struct MeasurementResponse {
+    id: Option<i32>,
+    time: DateTime<Utc>,
+    #[serde(flatten)]
+    values: BTreeMap<String, Value>,
+    /// How long the readout took, in µs.
+    meas_duration_us: Option<i64>,
+    clock_synced: bool,
+}
+
+impl From<db::Measurement> for MeasurementResponse {
+    fn from(measurement: db::Measurement) -> Self {
+        MeasurementResponse {
+            id: Some(measurement.readout.id),
+            time: *measurement.readout.meas_time,
+            values: measurement
+                .readings
+                .into_iter()
+                .map(|reading| {
+                    let value = Value {
+                        value: reading.value,
+                        unit: reading.unit,
+                    };
+                    (reading.quantity, value)
+                })
+                .collect(),
+            meas_duration_us: measurement.readout.meas_duration_us,
+            clock_synced: measurement.readout.clock_synced,
+        }
+    }
+}
+
+impl From<db::InsertableMeasurement> for MeasurementResponse {
+    fn from(measurement: db::InsertableMeasurement) -> Self {
+        MeasurementResponse {
+            id: None,
+            time: *measurement.meas_time,
+            values: db::readings::quantities(&measurement)
+                .into_iter()
+                .filter_map(|(quantity, value, _, unit)| {
+                    let value = Value {
+                        value: value?,
+                        unit: unit.to_owned(),
+                    };
+                    Some((quantity.to_owned(), value))
+                })
+                .collect(),
+            meas_duration_us: measurement.meas_duration_us,
+            clock_synced: measurement.clock_synced,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Stats {
+    count: i64,
+    min: f32,
+    max: f32,
+    mean: f32,
+    unit: String,
+}
+
+/// The stats of each quantity there are readings of in the bucket.
+#[derive(Debug, Serialize)]
+struct AggregateResponse {
+    start_time: DateTime<Utc>,
+    count: i64,
+    #[serde(flatten)]
+    quantities: BTreeMap<String, Stats>,
+}
+
+impl From<db::query::Aggregate> for AggregateResponse {
+    fn from(agg: db::query::Aggregate) -> Self {
+        AggregateResponse {
+            start_time: *agg.start_time,
+            count: agg.count(),
+            quantities: agg
+                .quantities
+                .into_iter()
+                .map(|quantity| {
+                    let stats = Stats {
+                        count: quantity.count,
+                        min: quantity.min,
+                        max: quantity.max,
+                        mean: quantity.mean,
+                        unit: quantity.unit,
+                    };
+                    (quantity.quantity, stats)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Selects the node whose measurements are queried, this one if not given.
+#[derive(Debug, Deserialize)]
+struct NodeParams {
+    #[serde(default)]
+    node: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RangeParams {
+    #[serde(default)]
+    node: String,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct NodeResponse {
+    node: String,
+    count: i64,
+    latest_time: Option<DateTime<Utc>>,
+}
+
+impl From<db::query::NodeSummary> for NodeResponse {
+    fn from(node: db::query::NodeSummary) -> Self {
+        NodeResponse {
+            node: node.node_id,
+            count: node.count,
+            latest_time: node.latest_time.map(|latest_time| *latest_time),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ForecastResponse {
+    time: DateTime<Utc>,
+    sea_level_pressure: Value,
+    pressure_change: Value,
+    tendency: String,
+    zambretti: i32,
+    forecast: String,
+}
+
+impl From<db::forecasts::Forecast> for ForecastResponse {
+    fn from(forecast: db::forecasts::Forecast) -> Self {
+        ForecastResponse {
+            time: *forecast.time,
+            sea_level_pressure: Value {
+                value: forecast.sea_level_pressure,
+                unit: PRESSURE_UNIT.to_owned(),
+            },
+            pressure_change: Value {
+                value: forecast.pressure_change,
+                unit: PRESSURE_UNIT.to_owned(),
+            },
+            tendency: forecast.tendency,
+            zambretti: forecast.zambretti,
+            forecast: forecast.forecast,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct UploadResponse {
+    stored: usize,
+    duplicates: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum BucketParam {
+    Hour,
+    Day,
+    Auto,
+}
+
+#[derive(Debug, Deserialize)]
+struct AggregateParams {
+    #[serde(default)]
+    node: String,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    bucket: Option<BucketParam>,
+}
+
+async fn get_latest_measurement(
+    State(state): State<AppState>,
+    params: Result<Query<NodeParams>, QueryRejection>,
+) -> ApiResult<Option<MeasurementResponse>> {
+    let params = query_params(params)?;
+    let latest = with_db(&state, move |conn| {
+        db::query::latest_measurement(conn, &params.node)
+    })
+    .await?;
+
+    Ok(Json(latest.map(MeasurementResponse::from)))
+}
+
+async fn get_measurements(
+    State(state): State<AppState>,
+    params: Result<Query<RangeParams>, QueryRejection>,
+) -> ApiResult<Vec<MeasurementResponse>> {
+    let params = query_params(params)?;
+    let (from, to) = time_range(params.from, params.to)?;
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(0, MAX_LIMIT);
+
+    let measurements = with_db(&state, move |conn| {
+        db::query::measurements_in_range(conn, &params.node, from, to, limit)
+    })
+    .await?;
+
+    Ok(Json(
+        measurements
+            .into_iter()
+            .map(MeasurementResponse::from)
+            .collect(),
+    ))
+}
+
+async fn get_aggregate(
+    State(state): State<AppState>,
+    params: Result<Query<AggregateParams>, QueryRejection>,
+) -> ApiResult<Vec<AggregateResponse>> {
+    let params = query_params(params)?;
+    let (from, to) = time_range(params.from, params.to)?;
+    let bucket = params.bucket.map(|bucket| match bucket {
+        BucketParam::Hour => Bucket::Hour,
+        BucketParam::Day => Bucket::Day,
+        BucketParam::Auto => Bucket::for_range(from.as_ref(), to.as_ref()),
+    });
+
+    let aggregates = with_db(&state, move |conn| {
+        db::query::aggregate_measurements(conn, &params.node, from, to, bucket)
+    })
+    .await?;
+
+    Ok(Json(
+        aggregates
+            .into_iter()
+            .map(AggregateResponse::from)
+            .collect(),
+    ))
+}
+
+async fn get_nodes(State(state): State<AppState>) -> ApiResult<Vec<NodeResponse>> {
+    let nodes = with_db(&state, db::query::nodes).await?;
+
+    Ok(Json(nodes.into_iter().map(NodeResponse::from).collect()))
+}
+
+async fn get_forecast(State(state): State<AppState>) -> ApiResult<Option<ForecastResponse>> {
+    let forecast = with_db(&state, db::forecasts::latest_forecast).await?;
+
+    Ok(Json(forecast.map(ForecastResponse::from)))
+}
+
+async fn post_measure(State(state): State<AppState>) -> ApiResult<MeasurementResponse> {
+    // Only routed with sensors.
+    let phat = state.enviro_phat.clone().unwrap();
+    let measurement = task::spawn_blocking(move || phat.measure()).await??;
+
+    Ok(Json(MeasurementResponse::from(
+        db::InsertableMeasurement::from(&measurement),
+    )))
+}
+
+/// Takes an upload from another node, answering with how many of its
+/// measurements were new. A malformed upload is a 400, the node drops it
+/// rather than trying again.
+async fn post_upload(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    // Only routed when collecting.
+    let uploads = state.uploads.as_ref().unwrap();
+
+    let authorization = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    if !uploads.is_authorized(authorization) {
+        return Ok(error_response(StatusCode::UNAUTHORIZED, "Invalid token"));
+    }
+
+    let batch = match serde_json::from_slice::<UploadBatch>(&body) {
+        Ok(batch) => batch,
+        Err(e) => return Ok(error_response(StatusCode::BAD_REQUEST, &e.to_string())),
+    };
+    let hostname = batch.hostname.clone();
+    let measurements = match collect::upload_measurements(batch) {
+        Ok(measurements) => measurements,
+        Err(e) => return Ok(error_response(StatusCode::BAD_REQUEST, &format!("{e:#}"))),
+    };
+
+    let received = measurements.len();
+    let stored = with_db(&state, move |conn| {
+        collect::store(conn, Some(&hostname), measurements)
+    })
+    .await?;
+
+    Ok(Json(UploadResponse {
+        stored,
+        duplicates: received - stored,
+    })
+    .into_response())
+}
+
+async fn get_metrics() -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{readings, rollup, test_connection, DateTimeUtc, InsertableMeasurement};
+    use axum::body::{self, Body};
+    use axum::http::{Method, Request};
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    const HOUR_US: i64 = 3_600 * 1_000_000;
+
+    /// The API without sensors or uploads, over two measurements on the 10th
+    /// day after the epoch, an hour apart.
+    fn app() -> Router {
+        let mut conn = test_connection();
+        let measurements =
+            [(1, 20.0), (2, 22.0)].map(|(hour, temperature)| InsertableMeasurement {
+                meas_time: DateTimeUtc::from_micros(240 * HOUR_US + hour * HOUR_US).unwrap(),
+                temperature: Some(temperature),
+                ..InsertableMeasurement::example()
+            });
+        for measurement in &measurements {
+            readings::insert_measurement(&mut conn, measurement).unwrap();
+        }
+        rollup::update_rollups(&mut conn, &measurements).unwrap();
+
+        router(Arc::new(Mutex::new(conn)), None, None)
+    }
+
+    async fn request(method: Method, uri: &str) -> (StatusCode, serde_json::Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        let response = app().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    async fn get(uri: &str) -> (StatusCode, serde_json::Value) {
+        request(Method::GET, uri).await
+    }
+
+    #[tokio::test]
+    async fn measurements_in_a_range() {
+        let (status, body) = get("/measurements").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.as_array().unwrap().len(), 2);
+        assert_eq!(body[0]["time"], "1970-01-11T01:00:00Z");
+        assert_eq!(body[0]["temperature"], json!({"value": 20.0, "unit": "°C"}));
+        assert_eq!(body[0]["pressure"]["unit"], "Pa");
+
+        let (status, body) = get("/measurements?from=1970-01-11T01:30:00Z&limit=5").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["temperature"]["value"], 22.0);
+
+        let (_, body) = get("/measurements?limit=1").await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn latest_measurement() {
+        let (status, body) = get("/measurements/latest").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["time"], "1970-01-11T02:00:00Z");
+
+        let (status, body) = get("/measurements/latest?node=elsewhere").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::Value::Null);
+    }
+
+    #[tokio::test]
+    async fn aggregates() {
+        let (status, body) = get("/measurements/aggregate?bucket=day").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["start_time"], "1970-01-11T00:00:00Z");
+        assert_eq!(body[0]["count"], 2);
+        assert_eq!(
+            body[0]["temperature"],
+            json!({"count": 2, "min": 20.0, "max": 22.0, "mean": 21.0, "unit": "°C"})
+        );
+
+        let (status, body) = get("/measurements/aggregate?bucket=hour").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.as_array().unwrap().len(), 2);
+
+        let (status, body) = get("/measurements/aggregate").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body[0]["temperature"]["mean"], 21.0);
+    }
+
+    #[tokio::test]
+    async fn bad_parameters_are_a_bad_request() {
+        for uri in [
+            "/measurements?limit=many",
+            "/measurements?from=yesterday",
+            "/measurements?from=1970-01-12T00:00:00Z&to=1970-01-11T00:00:00Z",
+            "/measurements/aggregate?bucket=week",
+            "/measurements/aggregate?from=1970-01-12T00:00:00Z&to=1970-01-11T00:00:00Z",
+        ] {
+            let (status, body) = get(uri).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{uri}");
+            assert!(body["error"].is_string(), "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn measure_and_upload_need_sensors_and_collecting() {
+        for uri in ["/measure", "/upload"] {
+            let (status, _) = request(Method::POST, uri).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{uri}");
+        }
+    }
+}
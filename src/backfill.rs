@@ -0,0 +1,45 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use clap::Args;
+
+use crate::db::{self, DateTimeUtc};
+use crate::retention::RetentionConfig;
+
+#[derive(Debug, Args)]
+pub struct BackfillArgs {
+    /// Only rebuild the rollups from this time on (RFC 3339), widened to a
+    /// whole day.
+    #[arg(long)]
+    from: Option<DateTime<Utc>>,
+    /// Only rebuild the rollups up to this time (RFC 3339), widened to a
+    /// whole day.
+    #[arg(long)]
+    to: Option<DateTime<Utc>>,
+    /// SQLite database or postgres:// URL to backfill, DATABASE_URL if not
+    /// given.
+    #[arg(long)]
+    database: Option<String>,
+}
+
+/// Recomputes the hourly and daily rollups from the raw measurements, for
+/// data that was there before the rollups were or was changed behind our back.
+/// The days the retention policy has started pruning are left alone.
+pub fn backfill(args: BackfillArgs, default_db_url: impl FnOnce() -> String) -> Result<()> {
+    let db_url = args.database.unwrap_or_else(default_db_url);
+    let mut conn = db::establish_connection(&db_url)?;
+
+    // Whether the measurements are uploaded only changes which of them are
+    // kept back, not from when on.
+    let keep_before = RetentionConfig::from_env(false)?.and_then(|config| config.raw_cutoff());
+
+    let written = db::rollup::rebuild_rollups(
+        &mut conn,
+        args.from.map(DateTimeUtc::from),
+        args.to.map(DateTimeUtc::from),
+        keep_before,
+    )?;
+
+    println!("Wrote {written} rollup rows.");
+
+    Ok(())
+}
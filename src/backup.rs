@@ -0,0 +1,583 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use clap::Args;
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+use libsqlite3_sys as ffi;
+use tokio::sync::watch;
+use tokio::{select, task, time};
+
+use std::ffi::{CStr, CString};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::time::{Duration, SystemTime};
+
+use crate::config::{optional_var, var_or};
+use crate::db;
+use crate::metrics::METRICS;
+
+/// Snapshots are named `<prefix><UTC time>.db`, plus `.sz` if compressed, so
+/// that sorting the names sorts them by age. The time is down to the
+/// microsecond, so that snapshots taken in quick succession, e.g. by the
+/// service and the `backup` command, don't overwrite each other.
+const SNAPSHOT_PREFIX: &str = "measurements-";
+const BUSY_TIMEOUT_MS: c_int = 5000;
+
+#[derive(Debug, Clone)]
+pub struct BackupConfig {
+    dir: PathBuf,
+    interval: Duration,
+    keep: usize,
+    compress: bool,
+}
+
+impl BackupConfig {
+    const DIR_ENV_VAR: &'static str = "BACKUP_DIR";
+    const INTERVAL_ENV_VAR: &'static str = "BACKUP_INTERVAL_SECS";
+    const KEEP_ENV_VAR: &'static str = "BACKUP_KEEP";
+    const COMPRESS_ENV_VAR: &'static str = "BACKUP_COMPRESS";
+
+    /// Returns `None` if no backup directory is set.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(dir) = optional_var(Self::DIR_ENV_VAR)? else {
+            return Ok(None);
+        };
+
+        let keep = var_or(Self::KEEP_ENV_VAR, 7)?;
+        if keep == 0 {
+            return Err(anyhow!("{} has to be at least 1.", Self::KEEP_ENV_VAR));
+        }
+
+        Ok(Some(Self {
+            dir,
+            interval: Duration::from_secs(var_or(Self::INTERVAL_ENV_VAR, 24 * 3600)?),
+            keep,
+            compress: var_or(Self::COMPRESS_ENV_VAR, false)?,
+        }))
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct BackupArgs {
+    /// Directory to write the snapshot to, BACKUP_DIR if not given.
+    #[arg(short, long)]
+    dir: Option<PathBuf>,
+    /// How many snapshots to keep in the directory, BACKUP_KEEP (7) if not
+    /// given.
+    #[arg(long)]
+    keep: Option<usize>,
+    /// Compress the snapshot with Snappy (framed, as `snzip` writes it), also
+    /// done if BACKUP_COMPRESS is set.
+    #[arg(long)]
+    compress: bool,
+    /// SQLite database to back up, DATABASE_URL if not given.
+    #[arg(long)]
+    database: Option<String>,
+}
+
+/// An SQLite connection opened through the C API, for what Diesel doesn't
+/// expose.
+struct RawConnection(*mut ffi::sqlite3);
+
+impl RawConnection {
+    fn open(path: &str, flags: c_int) -> Result<Self> {
+        let path = CString::new(path)?;
+        let mut conn = ptr::null_mut();
+
+        // SAFETY: path is a NUL-terminated string that outlives the call, conn
+        // is a valid place for the handle and a null VFS name is the default.
+        let rc = unsafe { ffi::sqlite3_open_v2(path.as_ptr(), &mut conn, flags, ptr::null()) };
+        // Even a failed open may allocate a handle, which then has to be
+        // closed.
+        let conn = RawConnection(conn);
+        if rc != ffi::SQLITE_OK {
+            return Err(conn.error(rc));
+        }
+
+        // SAFETY: the open succeeded, so conn.0 is a valid handle.
+        unsafe { ffi::sqlite3_busy_timeout(conn.0, BUSY_TIMEOUT_MS) };
+
+        Ok(conn)
+    }
+
+    fn error(&self, rc: c_int) -> anyhow::Error {
+        let message = if self.0.is_null() {
+            // SAFETY: sqlite3_errstr() returns a static NUL-terminated string
+            // for any code.
+            unsafe { CStr::from_ptr(ffi::sqlite3_errstr(rc)) }
+        } else {
+            // SAFETY: self.0 is a handle that's only closed on drop, and the
+            // message it returns stays valid until the next call on it, which
+            // is after it's been copied below.
+            unsafe { CStr::from_ptr(ffi::sqlite3_errmsg(self.0)) }
+        };
+
+        anyhow!("SQLite error {rc}: {}", message.to_string_lossy())
+    }
+}
+
+impl Drop for RawConnection {
+    fn drop(&mut self) {
+        // SAFETY: the handle is closed only once, here, and closing a null
+        // one is a no-op. Nothing else uses it after, the backups started on
+        // it are finished before it goes out of scope.
+        unsafe { ffi::sqlite3_close(self.0) };
+    }
+}
+
+/// Copies the DB to `dest` with SQLite's online backup API. All of it is
+/// copied in one step, i.e. in a single read transaction, which gives a
+/// consistent snapshot and in WAL mode doesn't hold up the writers.
+fn online_backup(db_url: &str, dest: &Path) -> Result<()> {
+    let src = RawConnection::open(db_url, ffi::SQLITE_OPEN_READONLY | ffi::SQLITE_OPEN_URI)?;
+    let dest = RawConnection::open(
+        dest.to_str()
+            .ok_or_else(|| anyhow!("Invalid path: {dest:?}"))?,
+        ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE,
+    )?;
+
+    let main = c"main";
+    // SAFETY: both handles were opened successfully and outlive the backup,
+    // and main is a NUL-terminated string.
+    let backup = unsafe { ffi::sqlite3_backup_init(dest.0, main.as_ptr(), src.0, main.as_ptr()) };
+    if backup.is_null() {
+        // SAFETY: dest.0 is a valid handle, see above.
+        let rc = unsafe { ffi::sqlite3_errcode(dest.0) };
+        return Err(dest.error(rc));
+    }
+
+    // SAFETY: backup is the non-null handle from sqlite3_backup_init() and
+    // hasn't been finished yet.
+    let step_rc = unsafe { ffi::sqlite3_backup_step(backup, -1) };
+    // Finishing returns the error of the step, if there was one.
+    //
+    // SAFETY: as for the step, and backup isn't used after this.
+    let rc = unsafe { ffi::sqlite3_backup_finish(backup) };
+
+    if rc != ffi::SQLITE_OK {
+        return Err(dest.error(rc));
+    }
+    if step_rc != ffi::SQLITE_DONE {
+        return Err(src.error(step_rc));
+    }
+
+    Ok(())
+}
+
+#[derive(QueryableByName)]
+struct IntegrityCheck {
+    #[diesel(sql_type = Text)]
+    integrity_check: String,
+}
+
+/// Runs `PRAGMA integrity_check` on the snapshot, which has a single "ok" row
+/// if it's fine and a row per problem otherwise.
+fn check_integrity(path: &Path) -> Result<()> {
+    let mut conn = SqliteConnection::establish(
+        path.to_str()
+            .ok_or_else(|| anyhow!("Invalid path: {path:?}"))?,
+    )?;
+
+    let problems = diesel::sql_query("PRAGMA integrity_check")
+        .load::<IntegrityCheck>(&mut conn)?
+        .into_iter()
+        .map(|row| row.integrity_check)
+        .filter(|row| row != "ok")
+        .collect::<Vec<_>>();
+
+    if !problems.is_empty() {
+        METRICS.backup_integrity_failures.inc();
+        return Err(anyhow!("Integrity check failed: {}", problems.join("; ")));
+    }
+
+    Ok(())
+}
+
+fn compress(src: &Path, dest: &Path) -> io::Result<()> {
+    let mut reader = BufReader::new(File::open(src)?);
+    let mut writer = snap::write::FrameEncoder::new(BufWriter::new(File::create(dest)?));
+
+    io::copy(&mut reader, &mut writer)?;
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()
+}
+
+fn is_snapshot(name: &str) -> bool {
+    name.starts_with(SNAPSHOT_PREFIX) && (name.ends_with(".db") || name.ends_with(".db.sz"))
+}
+
+/// The snapshots in the directory, oldest first.
+fn snapshots(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut snapshots = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_str().is_some_and(is_snapshot) {
+            snapshots.push(entry.path());
+        }
+    }
+    snapshots.sort();
+
+    Ok(snapshots)
+}
+
+/// Deletes all but the newest `keep` snapshots.
+fn rotate(dir: &Path, keep: usize) -> io::Result<()> {
+    let snapshots = snapshots(dir)?;
+    let expired = snapshots.len().saturating_sub(keep);
+
+    for path in &snapshots[..expired] {
+        fs::remove_file(path)?;
+        log::info!("Deleted old snapshot {}", path.display());
+    }
+
+    Ok(())
+}
+
+/// Writes a snapshot of the DB to the directory, checks it, and deletes the
+/// oldest ones beyond `keep`. Returns the snapshot's path.
+///
+/// The snapshot is written under a temporary name first, so that there's
+/// never a half-written or unchecked file with a snapshot's name.
+fn snapshot(db_url: &str, dir: &Path, keep: usize, compress_snapshot: bool) -> Result<PathBuf> {
+    if db::is_postgres_url(db_url) {
+        return Err(anyhow!(
+            "Only SQLite DBs can be backed up, use pg_dump for Postgres."
+        ));
+    }
+
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+
+    let name = format!(
+        "{SNAPSHOT_PREFIX}{}.db",
+        Utc::now().format("%Y%m%dT%H%M%S%.6fZ")
+    );
+    let partial = dir.join(format!("{name}.partial"));
+
+    let result = (|| {
+        online_backup(db_url, &partial)?;
+        check_integrity(&partial)?;
+
+        let path = if compress_snapshot {
+            let path = dir.join(format!("{name}.sz"));
+            let compressed = dir.join(format!("{name}.sz.partial"));
+
+            let result =
+                compress(&partial, &compressed).and_then(|()| fs::rename(&compressed, &path));
+            if result.is_err() {
+                let _ = fs::remove_file(&compressed);
+            }
+            result?;
+            fs::remove_file(&partial)?;
+
+            path
+        } else {
+            let path = dir.join(&name);
+            File::open(&partial)?.sync_all()?;
+            fs::rename(&partial, &path)?;
+
+            path
+        };
+
+        Ok(path)
+    })();
+
+    let path = match result {
+        Ok(path) => path,
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            METRICS.backup_failures.inc();
+            return Err(e);
+        }
+    };
+
+    METRICS.backup_successes.inc();
+    METRICS
+        .last_backup_timestamp
+        .set(Utc::now().timestamp_micros() as f64 / 1e6);
+    if let Ok(metadata) = fs::metadata(&path) {
+        METRICS.last_backup_size.set(metadata.len() as f64);
+    }
+
+    // A snapshot that couldn't be taken shouldn't rotate out a good one, so
+    // this only happens once there's a new one.
+    rotate(dir, keep)?;
+
+    Ok(path)
+}
+
+/// Takes a snapshot right away, e.g. before an upgrade.
+pub fn backup(args: BackupArgs, default_db_url: impl FnOnce() -> String) -> Result<()> {
+    let config = BackupConfig::from_env()?;
+
+    let dir = args
+        .dir
+        .or_else(|| config.as_ref().map(|config| config.dir.clone()))
+        .ok_or_else(|| anyhow!("No backup directory, give --dir or set BACKUP_DIR."))?;
+    let keep = args
+        .keep
+        .or_else(|| config.as_ref().map(|config| config.keep))
+        .unwrap_or(7);
+    if keep == 0 {
+        return Err(anyhow!("At least one snapshot has to be kept."));
+    }
+    let compress = args.compress || config.as_ref().is_some_and(|config| config.compress);
+
+    let db_url = args.database.unwrap_or_else(default_db_url);
+    let path = snapshot(&db_url, &dir, keep, compress)?;
+    println!("Wrote {}", path.display());
+
+    Ok(())
+}
+
+/// Deletes the temporary files of snapshots that were being written when the
+/// process was killed.
+fn remove_partial_snapshots(dir: &Path) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        let is_partial = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(SNAPSHOT_PREFIX) && name.ends_with(".partial"));
+        if is_partial {
+            fs::remove_file(entry.path())?;
+            log::info!("Deleted unfinished snapshot {}", entry.path().display());
+        }
+    }
+
+    Ok(())
+}
+
+/// How long ago the newest snapshot was written, `None` if there isn't one.
+fn newest_snapshot_age(dir: &Path) -> Option<Duration> {
+    let newest = snapshots(dir).ok()?.pop()?;
+    let modified = fs::metadata(newest).ok()?.modified().ok()?;
+
+    Some(
+        SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default(),
+    )
+}
+
+/// How long to wait for the first snapshot, an interval after the newest
+/// existing one or right away if there isn't one.
+fn first_delay(dir: &Path, interval: Duration) -> Duration {
+    match newest_snapshot_age(dir) {
+        Some(age) => interval.saturating_sub(age),
+        None => Duration::ZERO,
+    }
+}
+
+/// Periodically takes a snapshot until shutdown. The first one is due an
+/// interval after the newest existing snapshot, so that restarts neither
+/// skip nor pile up snapshots. What's left of snapshots that were cut short
+/// by the last run is deleted first.
+pub async fn run(
+    config: BackupConfig,
+    db_url: String,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    if db::is_postgres_url(&db_url) {
+        return Err(anyhow!(
+            "Only SQLite DBs can be backed up, use pg_dump for Postgres."
+        ));
+    }
+
+    if let Err(e) = remove_partial_snapshots(&config.dir) {
+        log::warn!("Failed to delete unfinished snapshots: {e}");
+    }
+
+    let first_delay = first_delay(&config.dir, config.interval);
+    let mut backup_timer = time::interval_at(time::Instant::now() + first_delay, config.interval);
+
+    loop {
+        select! {
+            _ = backup_timer.tick() => {}
+            _ = shutdown.wait_for(|&shutdown| shutdown) => break,
+        }
+
+        let config = config.clone();
+        let db_url = db_url.clone();
+        let result = task::spawn_blocking(move || {
+            snapshot(&db_url, &config.dir, config.keep, config.compress)
+        })
+        .await?;
+
+        match result {
+            Ok(path) => log::info!("Wrote snapshot {}", path.display()),
+            Err(e) => log::error!("Backing up the DB failed: {e:#}"),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::connection::SimpleConnection;
+    use diesel::sql_types::Integer;
+
+    use std::io::Read;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn temp_dir() -> PathBuf {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let dir = std::env::temp_dir().join(format!(
+            "rpi_client_temp-backup-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+
+        names
+    }
+
+    #[derive(QueryableByName)]
+    struct Row {
+        #[diesel(sql_type = Integer)]
+        x: i32,
+    }
+
+    #[test]
+    fn compressed_snapshots_round_trip() {
+        let dir = temp_dir();
+        let db_path = dir.join("measurements.db");
+        let db_url = db_path.to_str().unwrap();
+        db::establish_connection(db_url)
+            .unwrap()
+            .batch_execute("CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1), (2), (3);")
+            .unwrap();
+
+        let backup_dir = dir.join("backups");
+        let path = snapshot(db_url, &backup_dir, 7, true).unwrap();
+        assert_eq!(
+            file_names(&backup_dir),
+            [path.file_name().unwrap().to_str().unwrap()]
+        );
+
+        let mut decompressed = Vec::new();
+        snap::read::FrameDecoder::new(File::open(&path).unwrap())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        let restored = dir.join("restored.db");
+        fs::write(&restored, decompressed).unwrap();
+
+        let mut conn = SqliteConnection::establish(restored.to_str().unwrap()).unwrap();
+        let rows = diesel::sql_query("SELECT x FROM t ORDER BY x")
+            .load::<Row>(&mut conn)
+            .unwrap()
+            .into_iter()
+            .map(|row| row.x)
+            .collect::<Vec<_>>();
+        assert_eq!(rows, [1, 2, 3]);
+    }
+
+    #[test]
+    fn rotation_keeps_the_newest_snapshots() {
+        let dir = temp_dir();
+        for name in [
+            "measurements-20260101T000000.000000Z.db",
+            "measurements-20260102T000000.000000Z.db.sz",
+            "measurements-20260103T000000.000000Z.db",
+            "measurements-20260104T000000.000000Z.db",
+            "notes.txt",
+        ] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+
+        rotate(&dir, 2).unwrap();
+        assert_eq!(
+            file_names(&dir),
+            [
+                "measurements-20260103T000000.000000Z.db",
+                "measurements-20260104T000000.000000Z.db",
+                "notes.txt"
+            ]
+        );
+    }
+
+    #[test]
+    fn first_snapshot_is_due_an_interval_after_the_newest() {
+        const HOUR: Duration = Duration::from_secs(3600);
+        let dir = temp_dir();
+        assert_eq!(first_delay(&dir, 24 * HOUR), Duration::ZERO);
+
+        let older = File::create(dir.join("measurements-20260101T000000.000000Z.db")).unwrap();
+        older.set_modified(SystemTime::now() - 30 * HOUR).unwrap();
+        assert_eq!(first_delay(&dir, 24 * HOUR), Duration::ZERO);
+
+        let newest = File::create(dir.join("measurements-20260102T000000.000000Z.db")).unwrap();
+        newest.set_modified(SystemTime::now() - 6 * HOUR).unwrap();
+        let delay = first_delay(&dir, 24 * HOUR);
+        assert!(delay <= 18 * HOUR && delay > 18 * HOUR - Duration::from_secs(60));
+    }
+
+    #[test]
+    fn failed_integrity_checks_leave_no_snapshot() {
+        let dir = temp_dir();
+        let db_path = dir.join("measurements.db");
+        let db_url = db_path.to_str().unwrap();
+        // An index that doesn't match its table.
+        db::establish_connection(db_url)
+            .unwrap()
+            .batch_execute(
+                "CREATE TABLE t (x INTEGER, y INTEGER); CREATE INDEX i ON t (x); \
+                 INSERT INTO t VALUES (1, 2); PRAGMA writable_schema = ON; \
+                 UPDATE sqlite_master SET sql = 'CREATE INDEX i ON t (y)' WHERE name = 'i';",
+            )
+            .unwrap();
+        assert!(check_integrity(&db_path).is_err());
+
+        let backup_dir = dir.join("backups");
+        let e = snapshot(db_url, &backup_dir, 7, false).unwrap_err();
+        assert!(e.to_string().contains("missing from index"), "{e}");
+        assert!(file_names(&backup_dir).is_empty());
+    }
+
+    #[test]
+    fn removes_partial_snapshots() {
+        let dir = temp_dir();
+        for name in [
+            "measurements-20260101T000000.000000Z.db",
+            "measurements-20260102T000000.000000Z.db.partial",
+            "measurements-20260102T000000.000000Z.db.sz.partial",
+            "notes.partial",
+        ] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+
+        remove_partial_snapshots(&dir).unwrap();
+        assert_eq!(
+            file_names(&dir),
+            ["measurements-20260101T000000.000000Z.db", "notes.partial"]
+        );
+        remove_partial_snapshots(&dir.join("missing")).unwrap();
+    }
+}
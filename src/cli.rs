@@ -0,0 +1,29 @@
+use clap::{Parser, Subcommand};
+
+use crate::backfill::BackfillArgs;
+use crate::backup::BackupArgs;
+use crate::export::ExportArgs;
+use crate::import::ImportArgs;
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Measure periodically and store the results, the default.
+    Run,
+    /// Export stored measurements to a file.
+    Export(ExportArgs),
+    /// Import measurements from a CSV or JSON Lines file.
+    Import(ImportArgs),
+    /// Rebuild the hourly and daily rollups from the stored measurements.
+    Backfill(BackfillArgs),
+    /// Snapshot the SQLite database, check the snapshot and rotate old ones.
+    Backup(BackupArgs),
+    /// Receive and store measurements from other nodes, serving the same API.
+    Collect,
+}
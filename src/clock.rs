@@ -0,0 +1,77 @@
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+
+use std::io;
+use std::time::Duration;
+
+lazy_static! {
+    /// Identifies the current boot, monotonic times are only comparable
+    /// between measurements with the same boot ID.
+    pub static ref BOOT_ID: Option<String> = match boot_id() {
+        Ok(boot_id) => Some(boot_id),
+        Err(e) => {
+            log::warn!("Failed to read the boot ID: {e}");
+            None
+        }
+    };
+}
+
+/// Anything before this can't be right, whatever the kernel says. It catches
+/// clocks that start at 1970 without the kernel ever having been told the
+/// time is unsynchronised.
+const MIN_TRUSTED_TIMESTAMP: i64 = 1_704_067_200; // 2024-01-01T00:00:00Z
+
+/// A wall clock reading together with the monotonic clock and whether the wall
+/// clock could be trusted at the time.
+#[derive(Debug, Clone, Copy)]
+pub struct Timestamp {
+    pub wall: DateTime<Utc>,
+    /// Time since boot, suspend included.
+    pub monotonic_us: i64,
+    pub synced: bool,
+}
+
+impl Timestamp {
+    pub fn now() -> Timestamp {
+        let wall = Utc::now();
+
+        Timestamp {
+            wall,
+            monotonic_us: monotonic_us(),
+            synced: wall.timestamp() >= MIN_TRUSTED_TIMESTAMP && is_synchronized(),
+        }
+    }
+}
+
+fn boot_id() -> io::Result<String> {
+    let boot_id = std::fs::read_to_string("/proc/sys/kernel/random/boot_id")?;
+
+    Ok(boot_id.trim().to_owned())
+}
+
+fn monotonic_us() -> i64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: ts is a valid timespec to write to, and CLOCK_BOOTTIME always
+    // exists on Linux.
+    unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut ts) };
+
+    // time_t is only 32 bits wide on 32-bit Pis.
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32).as_micros() as i64
+}
+
+/// Whether the kernel considers the system clock synchronised, i.e. NTP (or
+/// chrony, or systemd-timesyncd) has disciplined it since boot.
+pub fn is_synchronized() -> bool {
+    // SAFETY: timex is a plain C struct of integers (and padding), so all
+    // zeroes is a valid value for it. That also leaves modes at 0.
+    let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+    // SAFETY: timex is initialised and exclusively borrowed for the call, and
+    // with modes 0 adjtimex only reads the clock state into it, changing
+    // nothing.
+    let state = unsafe { libc::adjtimex(&mut timex) };
+
+    state != libc::TIME_ERROR && timex.status & libc::STA_UNSYNC == 0
+}
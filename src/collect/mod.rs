@@ -0,0 +1,284 @@
+use anyhow::{anyhow, Result};
+use diesel::prelude::*;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::api;
+use crate::backup::{self, BackupConfig};
+use crate::config::{optional_var, var_or};
+use crate::db::{self, inventory, DateTimeUtc, DbConnection, InsertableMeasurement};
+use crate::mqtt::MqttConfig;
+use crate::retention::{self, RetentionConfig};
+use crate::systemd;
+use crate::upload::UploadBatch;
+
+mod mqtt;
+
+#[derive(Debug, Clone)]
+pub struct CollectConfig {
+    db_url: String,
+    http_listen_addr: SocketAddr,
+    token: Option<String>,
+    mqtt: Option<MqttConfig>,
+    mqtt_topic: String,
+    retention: Option<RetentionConfig>,
+    backup: Option<BackupConfig>,
+}
+
+impl CollectConfig {
+    const DB_URL_ENV_VAR: &'static str = "DATABASE_URL";
+    const HTTP_LISTEN_ADDR_ENV_VAR: &'static str = "HTTP_LISTEN_ADDR";
+    const TOKEN_ENV_VAR: &'static str = "COLLECT_TOKEN";
+    const MQTT_TOPIC_ENV_VAR: &'static str = "COLLECT_MQTT_TOPIC";
+
+    /// Unlike the node's config this doesn't need any of the sensor settings,
+    /// a collector doesn't have to have sensors at all.
+    pub fn from_env() -> Result<Self> {
+        let required = |name: &str| -> Result<String> {
+            optional_var(name)?.ok_or_else(|| anyhow!("{name} has to be set to collect."))
+        };
+
+        let http_listen_addr = required(Self::HTTP_LISTEN_ADDR_ENV_VAR)?
+            .parse()
+            .map_err(|e| anyhow!("Invalid value of {}: {e}", Self::HTTP_LISTEN_ADDR_ENV_VAR))?;
+
+        // The node ID comes with the measurement, failing that it's what the
+        // first single-level wildcard matches, by default that's the client
+        // ID in the nodes' state topics.
+        let mqtt_topic: String = var_or(
+            Self::MQTT_TOPIC_ENV_VAR,
+            "rpi_client_temp/+/state".to_owned(),
+        )?;
+        if !mqtt_topic.split('/').any(|level| level == "+") {
+            return Err(anyhow!(
+                "{} has to have a + wildcard for the node ID.",
+                Self::MQTT_TOPIC_ENV_VAR
+            ));
+        }
+
+        Ok(Self {
+            db_url: required(Self::DB_URL_ENV_VAR)?,
+            http_listen_addr,
+            token: optional_var(Self::TOKEN_ENV_VAR)?,
+            mqtt: MqttConfig::from_env()?,
+            mqtt_topic,
+            retention: RetentionConfig::from_env(false)?,
+            backup: BackupConfig::from_env()?,
+        })
+    }
+}
+
+/// Accepts the uploads sent to the HTTP API.
+#[derive(Debug, Clone)]
+pub struct UploadReceiver {
+    token: Option<String>,
+}
+
+impl UploadReceiver {
+    /// Whether the `Authorization` header carries the right token, anything
+    /// goes if no token is configured. The token is compared in constant
+    /// time, so that the time taken gives nothing away about it.
+    pub fn is_authorized(&self, authorization: Option<&str>) -> bool {
+        let Some(token) = &self.token else {
+            return true;
+        };
+        let Some(given) = authorization.and_then(|auth| auth.strip_prefix("Bearer ")) else {
+            return false;
+        };
+
+        // Deprecated in favour of nothing that ring exposes yet.
+        #[allow(deprecated)]
+        let equal =
+            ring::constant_time::verify_slices_are_equal(given.as_bytes(), token.as_bytes());
+
+        equal.is_ok()
+    }
+}
+
+/// Turns an upload into measurements ready to be stored. The node ID can't be
+/// empty, that's this node's.
+pub fn upload_measurements(batch: UploadBatch) -> Result<Vec<InsertableMeasurement>> {
+    if batch.node_id.is_empty() {
+        return Err(anyhow!("The node ID can't be empty."));
+    }
+
+    Ok(batch
+        .measurements
+        .into_iter()
+        .map(|measurement| InsertableMeasurement {
+            meas_time: DateTimeUtc::from(measurement.time),
+            temperature: measurement.temperature,
+            pressure: measurement.pressure,
+            humidity: measurement.humidity,
+            light_level: measurement.light_level,
+            meas_duration_us: measurement.meas_duration_us,
+            // Nodes hold measurements back until their clock is synced.
+            clock_synced: true,
+            boot_id: None,
+            monotonic_us: None,
+            node_id: batch.node_id.clone(),
+            uid: Some(measurement.uid),
+            station_id: None,
+            temperature_sensor_id: None,
+            humidity_sensor_id: None,
+            pressure_sensor_id: None,
+            light_level_sensor_id: None,
+        })
+        .collect())
+}
+
+/// Stores the measurements in one transaction, skipping those whose UID is
+/// already in the DB, and marks the nodes' stations as seen. Returns how many
+/// were new.
+pub fn store(
+    conn: &mut DbConnection,
+    hostname: Option<&str>,
+    mut new_measurements: Vec<InsertableMeasurement>,
+) -> QueryResult<usize> {
+    conn.transaction(|conn| {
+        let mut station_ids = HashMap::new();
+        let mut inserted = Vec::with_capacity(new_measurements.len());
+
+        for measurement in &mut new_measurements {
+            let station_id = match station_ids.get(&measurement.node_id) {
+                Some(&station_id) => station_id,
+                None => {
+                    let station_id =
+                        inventory::register_station(conn, &measurement.node_id, hostname, None)?;
+                    station_ids.insert(measurement.node_id.clone(), station_id);
+                    station_id
+                }
+            };
+            measurement.station_id = Some(station_id);
+
+            if db::readings::insert_measurement(conn, measurement)? {
+                inserted.push(measurement.clone());
+            }
+        }
+        db::rollup::update_rollups(conn, &inserted)?;
+
+        Ok(inserted.len())
+    })
+}
+
+/// Runs the collector until SIGINT or SIGTERM: the HTTP API with uploads
+/// enabled, the MQTT subscription if a broker is configured, and the
+/// retention policy and backups.
+#[tokio::main]
+pub async fn run(config: CollectConfig) -> Result<()> {
+    log::info!("Collecting measurements into {}", config.db_url);
+
+    let mut db_conn = db::establish_connection(&config.db_url)?;
+    if config.retention.is_some() {
+        retention::enable_incremental_vacuum(&mut db_conn)?;
+    }
+    let db_conn = Arc::new(Mutex::new(db_conn));
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let api_task = tokio::spawn(api::serve(
+        config.http_listen_addr,
+        db_conn.clone(),
+        None,
+        Some(UploadReceiver {
+            token: config.token.clone(),
+        }),
+        shutdown_rx.clone(),
+    ));
+
+    let mqtt_task = config.mqtt.clone().map(|mqtt_config| {
+        tokio::spawn(mqtt::run(
+            mqtt_config,
+            config.mqtt_topic.clone(),
+            db_conn.clone(),
+            shutdown_rx.clone(),
+        ))
+    });
+
+    let retention_task = config.retention.clone().map(|retention_config| {
+        tokio::spawn(retention::run(
+            retention_config,
+            db_conn.clone(),
+            shutdown_rx.clone(),
+        ))
+    });
+
+    let backup_task = config.backup.clone().map(|backup_config| {
+        tokio::spawn(backup::run(
+            backup_config,
+            config.db_url.clone(),
+            shutdown_rx.clone(),
+        ))
+    });
+
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    systemd::notify_ready();
+
+    tokio::select! {
+        _ = sigint.recv() => log::info!("Received SIGINT, shutting down."),
+        _ = sigterm.recv() => log::info!("Received SIGTERM, shutting down."),
+    }
+
+    systemd::notify_stopping();
+    shutdown_tx.send_replace(true);
+
+    match api_task.await {
+        Ok(Ok(())) => log::info!("HTTP API stopped."),
+        Ok(Err(e)) => log::error!("HTTP API failed: {e:#}"),
+        Err(e) => log::error!("HTTP API task failed: {e}"),
+    }
+
+    if let Some(mqtt_task) = mqtt_task {
+        match mqtt_task.await {
+            Ok(Ok(())) => log::info!("MQTT subscription stopped."),
+            Ok(Err(e)) => log::error!("MQTT subscription failed: {e:#}"),
+            Err(e) => log::error!("MQTT subscription task failed: {e}"),
+        }
+    }
+
+    if let Some(retention_task) = retention_task {
+        match retention_task.await {
+            Ok(Ok(())) => log::info!("Retention task stopped."),
+            Ok(Err(e)) => log::error!("Retention task failed: {e:#}"),
+            Err(e) => log::error!("Retention task failed: {e}"),
+        }
+    }
+
+    if let Some(backup_task) = backup_task {
+        match backup_task.await {
+            Ok(Ok(())) => log::info!("Backup task stopped."),
+            Ok(Err(e)) => log::error!("Backup task failed: {e:#}"),
+            Err(e) => log::error!("Backup task failed: {e}"),
+        }
+    }
+
+    log::info!("Goodbye.");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_the_bearer_token() {
+        let receiver = UploadReceiver {
+            token: Some("secret".to_owned()),
+        };
+        assert!(receiver.is_authorized(Some("Bearer secret")));
+        assert!(!receiver.is_authorized(Some("Bearer secreT")));
+        assert!(!receiver.is_authorized(Some("Bearer secret2")));
+        assert!(!receiver.is_authorized(Some("secret")));
+        assert!(!receiver.is_authorized(None));
+
+        let open = UploadReceiver { token: None };
+        assert!(open.is_authorized(None));
+    }
+}
@@ -0,0 +1,198 @@
+use anyhow::{anyhow, Result};
+use rumqttc::{AsyncClient, Event, Packet, Publish, QoS};
+use tokio::sync::watch;
+use tokio::{select, task, time};
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::db::{DbConnection, InsertableMeasurement};
+use crate::mqtt::{MqttConfig, State};
+
+const REQUEST_QUEUE_SIZE: usize = 64;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// The topic level that the first `+` of the filter matched.
+fn node_id<'a>(topic_filter: &str, topic: &'a str) -> Option<&'a str> {
+    topic_filter
+        .split('/')
+        .zip(topic.split('/'))
+        .find(|(filter_level, _)| *filter_level == "+")
+        .map(|(_, topic_level)| topic_level)
+        .filter(|node_id| !node_id.is_empty())
+}
+
+/// Parses a node's state message into a measurement of that node, `None` for
+/// a measurement that still has to be re-timestamped, there's no doing that
+/// from here. The node ID in the message is the one the node uploads with,
+/// the topic is only gone by for nodes that don't send one.
+fn parse_state(topic_filter: &str, publish: &Publish) -> Result<Option<InsertableMeasurement>> {
+    let state = serde_json::from_slice::<State>(&publish.payload)?;
+    let node_id = match state.node_id.filter(|node_id| !node_id.is_empty()) {
+        Some(node_id) => node_id,
+        None => node_id(topic_filter, &publish.topic)
+            .ok_or_else(|| anyhow!("No node ID in {}", publish.topic))?
+            .to_owned(),
+    };
+    let mut measurement = state.measurement;
+
+    if !measurement.clock_synced {
+        return Ok(None);
+    }
+
+    // Redelivered and retained messages come with the same time, so it's
+    // enough to tell them apart.
+    measurement.uid = Some(format!(
+        "{node_id}-{}",
+        measurement.meas_time.timestamp_micros()
+    ));
+    measurement.node_id = node_id;
+
+    Ok(Some(measurement))
+}
+
+/// Stores the measurements that the nodes publish to their state topics. Only
+/// what arrives while the collector is connected is stored, the HTTP upload is
+/// the way to go for nodes that can't afford any gaps.
+pub async fn run(
+    config: MqttConfig,
+    topic_filter: String,
+    db_conn: Arc<Mutex<DbConnection>>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let (client, mut event_loop) =
+        AsyncClient::new(config.collector_options()?, REQUEST_QUEUE_SIZE);
+
+    log::info!("Collecting measurements from MQTT topic {topic_filter}");
+
+    loop {
+        let event = select! {
+            event = event_loop.poll() => event,
+            _ = shutdown.wait_for(|&shutdown| shutdown) => break,
+        };
+
+        match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                log::info!("Connected to MQTT broker.");
+
+                // The session isn't persistent, so this is needed after
+                // every reconnect.
+                if let Err(e) = client.try_subscribe(&topic_filter, QoS::AtLeastOnce) {
+                    log::warn!("Failed to subscribe to {topic_filter}: {e}");
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let measurement = match parse_state(&topic_filter, &publish) {
+                    Ok(Some(measurement)) => measurement,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        log::warn!("Ignoring MQTT message on {}: {e:#}", publish.topic);
+                        continue;
+                    }
+                };
+
+                let db_conn = db_conn.clone();
+                let stored = task::spawn_blocking(move || {
+                    super::store(&mut db_conn.lock().unwrap(), None, vec![measurement])
+                })
+                .await?;
+                if let Err(e) = stored {
+                    log::error!("Failed to store MQTT measurement: {e}");
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                // The event loop reconnects by itself on the next poll.
+                log::warn!("MQTT connection error: {e}");
+                time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+
+    if let Err(e) = client.try_disconnect() {
+        log::warn!("Failed to queue MQTT disconnect: {e}");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOPIC_FILTER: &str = "rpi_client_temp/+/state";
+
+    fn publish(topic: &str, payload: &str) -> Publish {
+        Publish::new(topic, QoS::AtLeastOnce, payload)
+    }
+
+    #[test]
+    fn node_id_is_the_wildcard_level() {
+        assert_eq!(
+            node_id(TOPIC_FILTER, "rpi_client_temp/kitchen/state"),
+            Some("kitchen")
+        );
+        assert_eq!(node_id("+/sensors/+", "attic/sensors/1"), Some("attic"));
+        assert_eq!(node_id(TOPIC_FILTER, "rpi_client_temp//state"), None);
+        assert_eq!(node_id(TOPIC_FILTER, "rpi_client_temp"), None);
+    }
+
+    #[test]
+    fn state_goes_by_the_node_id_in_the_message() {
+        let state = publish(
+            "rpi_client_temp/kitchen/state",
+            r#"{"node_id": "abc", "meas_time": "2026-10-18T12:00:00.5Z", "temperature": 21.5,
+                "pressure": null, "humidity": null, "light_level": null,
+                "meas_duration_us": 1000, "clock_synced": true, "boot_id": null,
+                "monotonic_us": null}"#,
+        );
+
+        let measurement = parse_state(TOPIC_FILTER, &state).unwrap().unwrap();
+        assert_eq!(measurement.node_id, "abc");
+        assert_eq!(measurement.uid.as_deref(), Some("abc-1792324800500000"));
+        assert_eq!(measurement.temperature, Some(21.5));
+    }
+
+    #[test]
+    fn state_without_a_node_id_goes_by_the_topic() {
+        let state = publish(
+            "rpi_client_temp/kitchen/state",
+            r#"{"meas_time": "2026-10-18T12:00:00Z", "temperature": 21.5, "pressure": null,
+                "humidity": null, "light_level": null, "meas_duration_us": null,
+                "clock_synced": true, "boot_id": null, "monotonic_us": null}"#,
+        );
+
+        let measurement = parse_state(TOPIC_FILTER, &state).unwrap().unwrap();
+        assert_eq!(measurement.node_id, "kitchen");
+        assert_eq!(measurement.uid.as_deref(), Some("kitchen-1792324800000000"));
+    }
+
+    #[test]
+    fn unsynced_state_is_skipped() {
+        let state = publish(
+            "rpi_client_temp/kitchen/state",
+            r#"{"node_id": "abc", "meas_time": "1970-01-01T00:01:00Z", "temperature": 21.5,
+                "pressure": null, "humidity": null, "light_level": null,
+                "meas_duration_us": null, "clock_synced": false, "boot_id": "boot",
+                "monotonic_us": 60000000}"#,
+        );
+
+        assert!(parse_state(TOPIC_FILTER, &state).unwrap().is_none());
+    }
+
+    #[test]
+    fn published_state_parses() {
+        let state = State {
+            node_id: Some("abc".to_owned()),
+            measurement: InsertableMeasurement::example(),
+        };
+        let state = publish(
+            "rpi_client_temp/kitchen/state",
+            &serde_json::to_string(&state).unwrap(),
+        );
+
+        let measurement = parse_state(TOPIC_FILTER, &state).unwrap().unwrap();
+        assert_eq!(measurement.node_id, "abc");
+        assert_eq!(measurement.pressure, Some(101325.0));
+    }
+}
@@ -0,0 +1,65 @@
+use anyhow::{Context, Result};
+
+use std::env::VarError;
+use std::error::Error;
+use std::str::FromStr;
+
+/// Reads and parses a variable from the environment (or `.env`), returning
+/// `None` if it isn't set at all.
+pub fn optional_var<T>(name: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Error + Send + Sync + 'static,
+{
+    match dotenv::var(name) {
+        Ok(value) => {
+            let parsed = value
+                .parse()
+                .with_context(|| format!("Invalid value of {name}: {value:?}"))?;
+
+            Ok(Some(parsed))
+        }
+        Err(dotenv::Error::EnvVar(VarError::NotPresent)) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Like `optional_var`, but falls back to `default` if the variable isn't set.
+pub fn var_or<T>(name: &str, default: T) -> Result<T>
+where
+    T: FromStr,
+    T::Err: Error + Send + Sync + 'static,
+{
+    Ok(optional_var(name)?.unwrap_or(default))
+}
+
+pub fn hostname() -> Result<String> {
+    let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")?;
+
+    Ok(hostname.trim().to_owned())
+}
+
+/// The systemd machine ID, which unlike the hostname stays the same for the
+/// lifetime of the installation.
+pub fn machine_id() -> Result<String> {
+    let machine_id = std::fs::read_to_string("/etc/machine-id")?;
+
+    Ok(machine_id.trim().to_owned())
+}
+
+/// What a collector stores this node's measurements under, whether they're
+/// uploaded or published over MQTT. It has to stay the same for the collector
+/// to tell the nodes apart, so it's the machine ID unless set.
+pub fn node_id() -> Result<String> {
+    const NODE_ID_ENV_VAR: &str = "NODE_ID";
+    // What it was called when only the uploads had a node ID.
+    const UPLOAD_NODE_ID_ENV_VAR: &str = "UPLOAD_NODE_ID";
+
+    match optional_var(NODE_ID_ENV_VAR)? {
+        Some(node_id) => Ok(node_id),
+        None => match optional_var(UPLOAD_NODE_ID_ENV_VAR)? {
+            Some(node_id) => Ok(node_id),
+            None => machine_id(),
+        },
+    }
+}
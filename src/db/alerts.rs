@@ -0,0 +1,46 @@
+use diesel::prelude::*;
+use diesel::sql_types::{Float, Nullable, Text};
+
+use super::schema::alert_states;
+use super::{DateTimeUtc, DbConnection, UtcTime};
+
+#[derive(Debug, Queryable, Selectable)]
+#[diesel(table_name = alert_states)]
+pub struct StoredAlertState {
+    pub rule: String,
+    pub state: String,
+    pub since: DateTimeUtc,
+    pub value: Option<f32>,
+}
+
+pub fn alert_states(conn: &mut DbConnection) -> QueryResult<Vec<StoredAlertState>> {
+    alert_states::table
+        .select(StoredAlertState::as_select())
+        .load(conn)
+}
+
+/// Deletes the states of the rules other than `rules`, returning how many
+/// there were.
+pub fn delete_other_alert_states(conn: &mut DbConnection, rules: &[&str]) -> QueryResult<usize> {
+    diesel::delete(alert_states::table.filter(alert_states::rule.ne_all(rules))).execute(conn)
+}
+
+pub fn save_alert_state(conn: &mut DbConnection, state: &StoredAlertState) -> QueryResult<()> {
+    conn.sql_query(
+        "INSERT INTO alert_states (rule, state, since, value, updated_at) \
+         VALUES (?1, ?2, ?3, ?4, ?5) \
+         ON CONFLICT (rule) DO UPDATE SET \
+         state = excluded.state, \
+         since = excluded.since, \
+         value = excluded.value, \
+         updated_at = excluded.updated_at",
+    )
+    .bind::<Text, _>(&state.rule)
+    .bind::<Text, _>(&state.state)
+    .bind::<UtcTime, _>(&state.since)
+    .bind::<Nullable<Float>, _>(state.value)
+    .bind::<UtcTime, _>(DateTimeUtc::now())
+    .execute(conn)?;
+
+    Ok(())
+}
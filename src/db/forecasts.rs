@@ -0,0 +1,37 @@
+use diesel::prelude::*;
+use serde::Serialize;
+
+use super::schema::forecasts;
+use super::{DateTimeUtc, DbConnection};
+
+/// A forecast from this node's own pressure readings. It's local only: the
+/// table has no node ID, forecasts aren't uploaded, and the collector doesn't
+/// work any out for the nodes it receives measurements from.
+#[derive(Debug, Clone, Queryable, Selectable, Insertable, Serialize)]
+#[diesel(table_name = forecasts)]
+pub struct Forecast {
+    pub time: DateTimeUtc,
+    /// In Pa, like the pressure readings.
+    pub sea_level_pressure: f32,
+    /// Over the last three hours, in Pa.
+    pub pressure_change: f32,
+    pub tendency: String,
+    pub zambretti: i32,
+    pub forecast: String,
+}
+
+pub fn insert_forecast(conn: &mut DbConnection, forecast: &Forecast) -> QueryResult<()> {
+    diesel::insert_into(forecasts::table)
+        .values(forecast)
+        .execute(conn)?;
+
+    Ok(())
+}
+
+pub fn latest_forecast(conn: &mut DbConnection) -> QueryResult<Option<Forecast>> {
+    forecasts::table
+        .select(Forecast::as_select())
+        .order(forecasts::time.desc())
+        .first(conn)
+        .optional()
+}
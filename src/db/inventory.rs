@@ -0,0 +1,158 @@
+use diesel::prelude::*;
+use diesel::sql_types::{Nullable, Text};
+
+use super::schema::{sensors, stations};
+use super::{DateTimeUtc, DbConnection, InsertableMeasurement, UtcTime, LOCAL_NODE_ID};
+use crate::enviro_phat::{Quantity, SensorInfo};
+
+/// The station and sensors that this node's measurements come from.
+#[derive(Debug, Clone)]
+pub struct Inventory {
+    pub station_id: i32,
+    sensor_ids: Vec<(Quantity, i32)>,
+}
+
+impl Inventory {
+    fn sensor_id(&self, quantity: Quantity) -> Option<i32> {
+        self.sensor_ids
+            .iter()
+            .find(|(sensor_quantity, _)| *sensor_quantity == quantity)
+            .map(|(_, sensor_id)| *sensor_id)
+    }
+
+    /// Records where the measurement came from. Humidity isn't measured by any
+    /// of the sensors.
+    pub fn apply(&self, measurement: &mut InsertableMeasurement) {
+        measurement.station_id = Some(self.station_id);
+        measurement.temperature_sensor_id = self.sensor_id(Quantity::Temperature);
+        measurement.pressure_sensor_id = self.sensor_id(Quantity::Pressure);
+        measurement.light_level_sensor_id = self.sensor_id(Quantity::LightLevel);
+    }
+}
+
+/// Creates or updates the station of a node and returns its ID. What isn't
+/// known now is left as it was.
+pub fn register_station(
+    conn: &mut DbConnection,
+    node_id: &str,
+    hostname: Option<&str>,
+    location: Option<&str>,
+) -> QueryResult<i32> {
+    conn.sql_query(
+        "INSERT INTO stations (node_id, hostname, location, first_seen, last_seen) \
+         VALUES (?1, ?2, ?3, ?4, ?4) \
+         ON CONFLICT (node_id) DO UPDATE SET \
+         hostname = COALESCE(excluded.hostname, stations.hostname), \
+         location = COALESCE(excluded.location, stations.location), \
+         last_seen = excluded.last_seen",
+    )
+    .bind::<Text, _>(node_id)
+    .bind::<Nullable<Text>, _>(hostname)
+    .bind::<Nullable<Text>, _>(location)
+    .bind::<UtcTime, _>(DateTimeUtc::now())
+    .execute(conn)?;
+
+    stations::table
+        .select(stations::id)
+        .filter(stations::node_id.eq(node_id))
+        .first(conn)
+}
+
+/// Finds the sensor of the station, or adds it if it's new, and returns its
+/// ID. A chip that's been swapped for another of the same kind is a new
+/// sensor, as long as the chips have calibration data to tell them apart.
+pub fn register_sensor(
+    conn: &mut DbConnection,
+    station_id: i32,
+    i2c_bus: Option<&str>,
+    sensor: &SensorInfo,
+) -> QueryResult<i32> {
+    let now = DateTimeUtc::now();
+    let chip_id = sensor.chip_id.map(i32::from);
+    let i2c_address = sensor.i2c_address.map(i32::from);
+
+    // Compared here rather than in SQL, where SQLite's `IS` and Postgres'
+    // `IS NOT DISTINCT FROM` are what makes NULLs match.
+    let existing = sensors::table
+        .select((
+            sensors::id,
+            sensors::chip_id,
+            sensors::i2c_bus,
+            sensors::i2c_address,
+            sensors::calibration,
+        ))
+        .filter(sensors::station_id.eq(station_id))
+        .filter(sensors::chip.eq(sensor.chip))
+        .load::<(
+            i32,
+            Option<i32>,
+            Option<String>,
+            Option<i32>,
+            Option<Vec<u8>>,
+        )>(conn)?
+        .into_iter()
+        .find(
+            |(_, other_chip_id, other_i2c_bus, other_i2c_address, other_calibration)| {
+                *other_chip_id == chip_id
+                    && other_i2c_bus.as_deref() == i2c_bus
+                    && *other_i2c_address == i2c_address
+                    && *other_calibration == sensor.calibration
+            },
+        )
+        .map(|(sensor_id, ..)| sensor_id);
+
+    if let Some(sensor_id) = existing {
+        diesel::update(sensors::table.find(sensor_id))
+            .set(sensors::last_seen.eq(&now))
+            .execute(conn)?;
+
+        return Ok(sensor_id);
+    }
+
+    diesel::insert_into(sensors::table)
+        .values((
+            sensors::station_id.eq(station_id),
+            sensors::chip.eq(sensor.chip),
+            sensors::chip_id.eq(chip_id),
+            sensors::i2c_bus.eq(i2c_bus),
+            sensors::i2c_address.eq(i2c_address),
+            sensors::calibration.eq(sensor.calibration.as_deref()),
+            sensors::first_seen.eq(&now),
+            sensors::last_seen.eq(&now),
+        ))
+        .execute(conn)?;
+
+    sensors::table
+        .select(sensors::id)
+        .order(sensors::id.desc())
+        .first(conn)
+}
+
+/// Records this node's station and the sensors that were detected on it.
+pub fn register_local(
+    conn: &mut DbConnection,
+    hostname: &str,
+    location: Option<&str>,
+    i2c_bus: Option<&str>,
+    sensors: &[SensorInfo],
+) -> QueryResult<Inventory> {
+    conn.transaction(|conn| {
+        let station_id = register_station(conn, LOCAL_NODE_ID, Some(hostname), location)?;
+
+        let mut sensor_ids = Vec::new();
+        for sensor in sensors {
+            let sensor_id = register_sensor(conn, station_id, i2c_bus, sensor)?;
+            sensor_ids.extend(
+                sensor
+                    .quantities
+                    .iter()
+                    .map(|&quantity| (quantity, sensor_id)),
+            );
+        }
+
+        Ok(Inventory {
+            station_id,
+            sensor_ids,
+        })
+    })
+}
@@ -1,58 +1,312 @@
 use chrono::prelude::*;
-use diesel::backend;
+use diesel::backend::Backend;
+use diesel::connection::SimpleConnection;
 use diesel::deserialize::{self, FromSql};
+use diesel::pg::Pg;
+use diesel::query_builder::{QueryId, SqlQuery};
 use diesel::serialize::{self, IsNull, Output, ToSql};
-use diesel::sql_types::BigInt;
+use diesel::sql_types::{BigInt, HasSqlType, SqlOrd, SqlType, Timestamptz};
 use diesel::sqlite::Sqlite;
 use diesel::{prelude::*, AsExpression, FromSqlRow};
 
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
 use std::ops::Deref;
 
+use crate::clock;
 use crate::enviro_phat;
 
+pub mod alerts;
+pub mod forecasts;
+pub mod inventory;
+pub mod query;
+pub mod readings;
+pub mod rollup;
 pub mod schema;
 
-#[derive(Debug, Queryable)]
-#[allow(dead_code)]
+/// The node ID of the measurements taken by this node.
+pub const LOCAL_NODE_ID: &str = "";
+
+/// A connection to either of the supported databases.
+#[derive(diesel::MultiConnection)]
+pub enum DbConnection {
+    Sqlite(SqliteConnection),
+    Postgres(PgConnection),
+}
+
+impl DbConnection {
+    /// Raw SQL is written with SQLite's `?1` parameters, Postgres wants them
+    /// as `$1`.
+    pub fn sql_query(&self, query: &str) -> SqlQuery {
+        match self {
+            DbConnection::Sqlite(_) => diesel::sql_query(query),
+            DbConnection::Postgres(_) => diesel::sql_query(postgres_parameters(query)),
+        }
+    }
+
+    /// SQL for the time `micros` microseconds after the epoch.
+    pub fn time_from_micros_sql(&self, micros: &str) -> String {
+        match self {
+            DbConnection::Sqlite(_) => micros.to_owned(),
+            DbConnection::Postgres(_) => {
+                format!("(TIMESTAMPTZ 'epoch' + ({micros}) * INTERVAL '1 microsecond')")
+            }
+        }
+    }
+}
+
+/// Turns the numbered `?1` parameters into `$1`, leaving any other question
+/// marks, e.g. in string literals, alone.
+fn postgres_parameters(query: &str) -> String {
+    let mut chars = query.chars().peekable();
+    let mut rewritten = String::with_capacity(query.len());
+
+    while let Some(c) = chars.next() {
+        if c == '?' && chars.peek().is_some_and(char::is_ascii_digit) {
+            rewritten.push('$');
+        } else {
+            rewritten.push(c);
+        }
+    }
+
+    rewritten
+}
+
+pub fn is_postgres_url(db_url: &str) -> bool {
+    db_url.starts_with("postgres://") || db_url.starts_with("postgresql://")
+}
+
+/// Connects to Postgres for a `postgres://` URL, otherwise the URL is the
+/// path of an SQLite DB.
+///
+/// SQLite is put in WAL mode, which together with `synchronous = NORMAL`
+/// means one sync per checkpoint rather than several per transaction. A crash
+/// can lose the last transactions but not corrupt the DB. The busy timeout is
+/// for other processes, like an export, holding a lock.
+pub fn establish_connection(db_url: &str) -> anyhow::Result<DbConnection> {
+    let conn = if is_postgres_url(db_url) {
+        DbConnection::Postgres(PgConnection::establish(db_url)?)
+    } else {
+        let mut conn = SqliteConnection::establish(db_url)?;
+        conn.batch_execute(
+            "PRAGMA journal_mode = WAL; \
+             PRAGMA synchronous = NORMAL; \
+             PRAGMA busy_timeout = 5000;",
+        )?;
+
+        DbConnection::Sqlite(conn)
+    };
+
+    Ok(conn)
+}
+
+/// A readout of a node's sensors, without the values.
+#[derive(Debug, Queryable, Selectable)]
+#[diesel(table_name = schema::readouts)]
+pub struct Readout {
+    pub id: i32,
+    pub meas_time: DateTimeUtc,
+    pub meas_duration_us: Option<i64>,
+    pub clock_synced: bool,
+    pub boot_id: Option<String>,
+    pub monotonic_us: Option<i64>,
+    pub node_id: String,
+}
+
+/// One of a readout's values.
+#[derive(Debug, Clone, Queryable, Selectable)]
+#[diesel(table_name = schema::readings)]
+pub struct Reading {
+    pub quantity: String,
+    pub value: f32,
+    pub unit: String,
+}
+
+/// A readout with whatever quantities were read, as stored.
+#[derive(Debug)]
 pub struct Measurement {
-    id: i32,
-    meas_time: DateTimeUtc,
-    temperature: Option<f32>,
-    pressure: Option<f32>,
-    humidity: Option<f32>,
-    light_level: Option<f32>,
+    pub readout: Readout,
+    pub readings: Vec<Reading>,
+}
+
+impl Measurement {
+    pub fn value(&self, quantity: &str) -> Option<f32> {
+        self.readings
+            .iter()
+            .find(|reading| reading.quantity == quantity)
+            .map(|reading| reading.value)
+    }
 }
 
-#[derive(Debug, Insertable)]
-#[diesel(table_name = schema::measurements)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InsertableMeasurement {
-    meas_time: DateTimeUtc,
-    temperature: Option<f32>,
-    pressure: Option<f32>,
-    humidity: Option<f32>,
-    light_level: Option<f32>,
+    pub meas_time: DateTimeUtc,
+    pub temperature: Option<f32>,
+    pub pressure: Option<f32>,
+    pub humidity: Option<f32>,
+    pub light_level: Option<f32>,
+    /// How long the readout took, unknown for imported measurements.
+    pub meas_duration_us: Option<i64>,
+    /// Whether `meas_time` came from a synchronised clock. If it didn't, it's
+    /// fixed up from `monotonic_us` once the clock is synchronised, as long as
+    /// that happens before the next reboot.
+    pub clock_synced: bool,
+    pub boot_id: Option<String>,
+    /// Time since boot when the measurement was taken.
+    pub monotonic_us: Option<i64>,
+    /// Empty for the measurements taken here, the node that uploaded it
+    /// otherwise.
+    #[serde(skip)]
+    pub node_id: String,
+    /// Identifies an uploaded measurement, so that it's only stored once.
+    #[serde(skip)]
+    pub uid: Option<String>,
+    /// Where the measurement was taken and by which sensors, as far as that's
+    /// known. Only meaningful within this DB.
+    #[serde(skip)]
+    pub station_id: Option<i32>,
+    #[serde(skip)]
+    pub temperature_sensor_id: Option<i32>,
+    #[serde(skip)]
+    pub humidity_sensor_id: Option<i32>,
+    #[serde(skip)]
+    pub pressure_sensor_id: Option<i32>,
+    #[serde(skip)]
+    pub light_level_sensor_id: Option<i32>,
 }
 
-impl From<enviro_phat::Measurement> for InsertableMeasurement {
-    fn from(measurement: enviro_phat::Measurement) -> Self {
+impl From<&enviro_phat::Measurement> for InsertableMeasurement {
+    fn from(measurement: &enviro_phat::Measurement) -> Self {
+        Self {
+            meas_time: DateTimeUtc(measurement.started_at.wall),
+            temperature: measurement.temperature.as_ref().map(|t| t.0),
+            pressure: measurement.pressure.as_ref().map(|p| p.0),
+            humidity: None,
+            light_level: measurement.light_level.as_ref().map(|l| l.0),
+            meas_duration_us: measurement.duration.as_micros().try_into().ok(),
+            clock_synced: measurement.started_at.synced,
+            boot_id: clock::BOOT_ID.clone(),
+            monotonic_us: Some(measurement.started_at.monotonic_us),
+            node_id: LOCAL_NODE_ID.to_owned(),
+            uid: None,
+            station_id: None,
+            temperature_sensor_id: None,
+            humidity_sensor_id: None,
+            pressure_sensor_id: None,
+            light_level_sensor_id: None,
+        }
+    }
+}
+
+/// An in-memory SQLite DB with the migrations applied, for tests.
+#[cfg(test)]
+pub fn test_connection() -> DbConnection {
+    let mut conn = SqliteConnection::establish(":memory:").unwrap();
+
+    let migrations_dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations");
+    let mut migrations = std::fs::read_dir(migrations_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect::<Vec<_>>();
+    migrations.sort();
+
+    for migration in migrations {
+        let up = std::fs::read_to_string(migration.join("up.sql")).unwrap();
+        conn.batch_execute(&up).unwrap();
+    }
+
+    DbConnection::Sqlite(conn)
+}
+
+#[cfg(test)]
+impl InsertableMeasurement {
+    /// A measurement of all of this node's quantities, taken now.
+    pub fn example() -> Self {
         Self {
             meas_time: DateTimeUtc::now(),
-            temperature: Some(measurement.temperature.0),
-            pressure: Some(measurement.pressure.0),
+            temperature: Some(21.456),
+            pressure: Some(101325.0),
             humidity: None,
-            light_level: Some(measurement.light_level.0),
+            light_level: Some(0.0123),
+            meas_duration_us: None,
+            clock_synced: true,
+            boot_id: None,
+            monotonic_us: None,
+            node_id: LOCAL_NODE_ID.to_owned(),
+            uid: None,
+            station_id: None,
+            temperature_sensor_id: None,
+            humidity_sensor_id: None,
+            pressure_sensor_id: None,
+            light_level_sensor_id: None,
+        }
+    }
+}
+
+impl fmt::Display for InsertableMeasurement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let quantities = [
+            ("temperature", self.temperature, 1.0, "°C"),
+            ("pressure", self.pressure, 0.01, "hPa"),
+            ("humidity", self.humidity, 1.0, "%"),
+            ("light level", self.light_level, 1.0, ""),
+        ];
+
+        let mut first = true;
+        for (name, value, scale, unit) in quantities {
+            if let Some(value) = value {
+                if !first {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{name} {:.2}{unit}", value * scale)?;
+                first = false;
+            }
         }
+
+        Ok(())
+    }
+}
+
+/// The SQL type of the times: microseconds since the Unix epoch in SQLite,
+/// `timestamptz` in Postgres.
+#[derive(Debug, Clone, Copy, SqlType, QueryId)]
+#[diesel(sqlite_type(name = "Long"))]
+#[diesel(postgres_type(oid = 1184, array_oid = 1185))]
+pub struct UtcTime;
+
+impl SqlOrd for UtcTime {}
+
+impl HasSqlType<UtcTime> for MultiBackend {
+    fn metadata(lookup: &mut Self::MetadataLookup) -> Self::TypeMetadata {
+        MultiBackend::lookup_sql_type::<UtcTime>(lookup)
     }
 }
 
-#[derive(Debug, Clone, AsExpression, FromSqlRow)]
-#[diesel(sql_type = BigInt)]
+/// Stored as microseconds since the Unix epoch in SQLite, negative before
+/// 1970, and as a `timestamptz` in Postgres. Anything finer than a microsecond
+/// is dropped.
+#[derive(Debug, Clone, AsExpression, FromSqlRow, Serialize, Deserialize)]
+#[diesel(sql_type = UtcTime)]
+#[serde(transparent)]
 pub struct DateTimeUtc(DateTime<Utc>);
 
 impl DateTimeUtc {
     pub fn now() -> DateTimeUtc {
         DateTimeUtc(Utc::now())
     }
+
+    /// `None` if out of range.
+    pub fn from_micros(micros: i64) -> Option<DateTimeUtc> {
+        DateTime::from_timestamp_micros(micros).map(DateTimeUtc)
+    }
+}
+
+impl From<DateTime<Utc>> for DateTimeUtc {
+    fn from(date_time: DateTime<Utc>) -> Self {
+        DateTimeUtc(date_time)
+    }
 }
 
 impl Deref for DateTimeUtc {
@@ -63,30 +317,209 @@ impl Deref for DateTimeUtc {
     }
 }
 
-impl FromSql<BigInt, Sqlite> for DateTimeUtc
-where
-    i64: FromSql<BigInt, Sqlite>,
-{
-    fn from_sql(value: backend::RawValue<Sqlite>) -> deserialize::Result<Self> {
-        let raw_val = i64::from_sql(value)?;
+impl FromSql<UtcTime, Sqlite> for DateTimeUtc {
+    fn from_sql(value: <Sqlite as Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+        let timestamp_us = <i64 as FromSql<BigInt, Sqlite>>::from_sql(value)?;
 
-        Ok(DateTimeUtc(DateTime::from_utc(
-            NaiveDateTime::from_timestamp(raw_val / 1_000_000, (raw_val % 1_000_000) as u32),
-            Utc,
-        )))
+        let date_time =
+            DateTime::from_timestamp_micros(timestamp_us).ok_or("Timestamp out of range")?;
+
+        Ok(DateTimeUtc(date_time))
     }
 }
 
-impl ToSql<BigInt, Sqlite> for DateTimeUtc
-where
-    i64: ToSql<BigInt, Sqlite>,
-{
+impl ToSql<UtcTime, Sqlite> for DateTimeUtc {
     fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Sqlite>) -> serialize::Result {
-        let timestamp_us =
-            (self.0.timestamp() * 1_000_000) + i64::from(self.timestamp_subsec_micros());
+        out.set_value(self.0.timestamp_micros());
+
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<UtcTime, Pg> for DateTimeUtc {
+    fn from_sql(value: <Pg as Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+        <DateTime<Utc> as FromSql<Timestamptz, Pg>>::from_sql(value).map(DateTimeUtc)
+    }
+}
 
-        out.set_value(timestamp_us);
+impl ToSql<UtcTime, Pg> for DateTimeUtc {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        <DateTime<Utc> as ToSql<Timestamptz, Pg>>::to_sql(&self.0, out)
+    }
+}
+
+impl FromSql<UtcTime, MultiBackend> for DateTimeUtc {
+    fn from_sql(value: <MultiBackend as Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+        value.from_sql::<DateTimeUtc, UtcTime>()
+    }
+}
+
+impl ToSql<UtcTime, MultiBackend> for DateTimeUtc {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, MultiBackend>) -> serialize::Result {
+        out.set_value((UtcTime, self));
 
         Ok(IsNull::No)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(QueryableByName)]
+    struct Time {
+        #[diesel(sql_type = UtcTime)]
+        time: DateTimeUtc,
+    }
+
+    #[derive(QueryableByName)]
+    struct Micros {
+        #[diesel(sql_type = BigInt)]
+        micros: i64,
+    }
+
+    /// Stores the time in SQLite and reads it back, along with what's stored.
+    fn round_trip(micros: i64) -> (i64, i64) {
+        let mut conn = test_connection();
+        let time = DateTimeUtc::from_micros(micros).unwrap();
+
+        let stored = conn
+            .sql_query("SELECT ?1 AS micros")
+            .bind::<UtcTime, _>(&time)
+            .get_result::<Micros>(&mut conn)
+            .unwrap();
+        let read = conn
+            .sql_query("SELECT ?1 AS time")
+            .bind::<UtcTime, _>(&time)
+            .get_result::<Time>(&mut conn)
+            .unwrap();
+
+        (stored.micros, read.time.timestamp_micros())
+    }
+
+    #[test]
+    fn sqlite_times_round_trip_before_1970() {
+        assert_eq!(round_trip(-1), (-1, -1));
+        assert_eq!(round_trip(-1_500_000), (-1_500_000, -1_500_000));
+        assert_eq!(
+            round_trip(-86_400_000_000 - 1),
+            (-86_400_000_000 - 1, -86_400_000_000 - 1)
+        );
+    }
+
+    #[test]
+    fn sqlite_times_round_trip_with_fractional_seconds() {
+        assert_eq!(round_trip(1), (1, 1));
+        assert_eq!(round_trip(1_500_000), (1_500_000, 1_500_000));
+        assert_eq!(
+            round_trip(1_760_000_000_123_456),
+            (1_760_000_000_123_456, 1_760_000_000_123_456)
+        );
+    }
+
+    #[test]
+    fn sqlite_times_out_of_range_are_an_error() {
+        let mut conn = test_connection();
+
+        for micros in [i64::MAX, i64::MIN] {
+            let res = conn
+                .sql_query("SELECT ?1 AS time")
+                .bind::<BigInt, _>(micros)
+                .get_result::<Time>(&mut conn);
+
+            assert!(res.is_err(), "{micros} was read as a time");
+        }
+    }
+
+    #[test]
+    fn only_numbered_parameters_are_rewritten_for_postgres() {
+        assert_eq!(
+            postgres_parameters("SELECT ?1, '?' AS q, ?12 WHERE a = ?2?"),
+            "SELECT $1, '?' AS q, $12 WHERE a = $2?"
+        );
+    }
+
+    /// Runs the queries with raw SQL against the migrated Postgres DB in
+    /// `DATABASE_URL`, if that's one, in a transaction that's rolled back.
+    #[test]
+    fn postgres_queries() {
+        let Some(db_url) = std::env::var("DATABASE_URL")
+            .ok()
+            .filter(|db_url| is_postgres_url(db_url))
+        else {
+            return;
+        };
+        let mut conn = establish_connection(&db_url).unwrap();
+
+        const HOUR_US: i64 = 3_600 * 1_000_000;
+        let at = |micros| InsertableMeasurement {
+            meas_time: DateTimeUtc::from_micros(micros).unwrap(),
+            node_id: "postgres-test".to_owned(),
+            ..InsertableMeasurement::example()
+        };
+
+        conn.test_transaction::<_, diesel::result::Error, _>(|conn| {
+            let measurements = [at(10 * HOUR_US + 1), at(10 * HOUR_US + 2), at(11 * HOUR_US)];
+            for measurement in &measurements {
+                readings::insert_measurement(conn, measurement)?;
+            }
+            rollup::update_rollups(conn, &measurements)?;
+
+            let hours = query::aggregate_measurements(
+                conn,
+                "postgres-test",
+                None,
+                None,
+                Some(query::Bucket::Hour),
+            )?;
+            let counts = hours
+                .iter()
+                .map(|hour| (hour.start_time.timestamp_micros(), hour.count()))
+                .collect::<Vec<_>>();
+            assert_eq!(counts, [(10 * HOUR_US, 2), (11 * HOUR_US, 1)]);
+
+            let written = rollup::rebuild_rollups(
+                conn,
+                DateTimeUtc::from_micros(0),
+                DateTimeUtc::from_micros(HOUR_US),
+                None,
+            )?;
+            // Widened to the whole day, two hours and the day.
+            assert_eq!(written, 3 * 3);
+
+            let all = query::aggregate_measurements(conn, "postgres-test", None, None, None)?;
+            assert_eq!(all.len(), 1);
+            assert_eq!(all[0].count(), 3);
+
+            let unsynced = InsertableMeasurement {
+                clock_synced: false,
+                boot_id: Some("postgres-test".to_owned()),
+                monotonic_us: Some(5 * HOUR_US),
+                node_id: LOCAL_NODE_ID.to_owned(),
+                ..at(12 * HOUR_US)
+            };
+            readings::insert_measurement(conn, &unsynced)?;
+            rollup::update_rollups(conn, &[unsynced])?;
+            let now = crate::clock::Timestamp {
+                wall: DateTimeUtc::from_micros(20 * HOUR_US).unwrap().0,
+                monotonic_us: 6 * HOUR_US,
+                synced: true,
+            };
+            assert_eq!(query::retimestamp_unsynced(conn, "postgres-test", &now)?, 1);
+            let hours = query::aggregate_measurements(
+                conn,
+                LOCAL_NODE_ID,
+                Some(DateTimeUtc::from_micros(0).unwrap()),
+                Some(DateTimeUtc::from_micros(24 * HOUR_US).unwrap()),
+                Some(query::Bucket::Hour),
+            )?;
+            let counts = hours
+                .iter()
+                .map(|hour| (hour.start_time.timestamp_micros(), hour.count()))
+                .collect::<Vec<_>>();
+            assert_eq!(counts, [(19 * HOUR_US, 1)]);
+
+            Ok(())
+        });
+    }
+}
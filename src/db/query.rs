@@ -0,0 +1,421 @@
+use diesel::dsl::{count_star, max};
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Float, Nullable, Text};
+
+use super::readings::with_readings;
+use super::rollup;
+use super::schema::{readings, readouts};
+use super::{DateTimeUtc, DbConnection, Measurement, Readout, UtcTime, LOCAL_NODE_ID};
+use crate::clock;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Bucket {
+    Hour,
+    Day,
+}
+
+impl Bucket {
+    /// Ranges up to this long are split into hourly buckets by `for_range()`,
+    /// that's at most a couple of thousand points to draw.
+    const MAX_HOURLY_RANGE_US: i64 = 90 * 24 * 3_600 * 1_000_000;
+
+    pub(super) fn as_micros(self) -> i64 {
+        match self {
+            Bucket::Hour => 3_600 * 1_000_000,
+            Bucket::Day => 24 * 3_600 * 1_000_000,
+        }
+    }
+
+    /// SQL for the start of the bucket that `time` falls into. SQLite's `/`
+    /// and `%` truncate towards zero, so this floors explicitly to keep times
+    /// before 1970 in the right bucket.
+    pub(super) fn start_sql(self, conn: &DbConnection, time: &str) -> String {
+        let bucket_us = self.as_micros();
+
+        match conn {
+            DbConnection::Sqlite(_) => {
+                format!("({time} - (({time} % {bucket_us}) + {bucket_us}) % {bucket_us})")
+            }
+            DbConnection::Postgres(_) => format!(
+                "date_bin(INTERVAL '{bucket_us} microseconds', {time}, TIMESTAMPTZ 'epoch')"
+            ),
+        }
+    }
+
+    pub(super) fn rollup_table(self) -> &'static str {
+        match self {
+            Bucket::Hour => "measurements_hourly",
+            Bucket::Day => "measurements_daily",
+        }
+    }
+
+    /// Picks the finest resolution that keeps the number of buckets in
+    /// [from, to) reasonable, an open range is treated as a long one.
+    pub fn for_range(from: Option<&DateTimeUtc>, to: Option<&DateTimeUtc>) -> Bucket {
+        let to_us = to.map_or_else(
+            || DateTimeUtc::now().timestamp_micros(),
+            |to| to.timestamp_micros(),
+        );
+
+        match from {
+            Some(from) if to_us - from.timestamp_micros() <= Self::MAX_HOURLY_RANGE_US => {
+                Bucket::Hour
+            }
+            _ => Bucket::Day,
+        }
+    }
+}
+
+/// The min/max/mean of the readings of one quantity in a bucket.
+#[derive(Debug, QueryableByName)]
+pub struct QuantityAggregate {
+    #[diesel(sql_type = UtcTime)]
+    start_time: DateTimeUtc,
+    #[diesel(sql_type = Text)]
+    pub quantity: String,
+    #[diesel(sql_type = Text)]
+    pub unit: String,
+    #[diesel(sql_type = BigInt)]
+    pub count: i64,
+    #[diesel(sql_type = Float)]
+    pub min: f32,
+    #[diesel(sql_type = Float)]
+    pub max: f32,
+    #[diesel(sql_type = Float)]
+    pub mean: f32,
+}
+
+/// The aggregates of whichever quantities there are readings of in a bucket,
+/// or in the whole range if it isn't split into buckets. It starts at the
+/// bucket's start or the first reading.
+#[derive(Debug)]
+pub struct Aggregate {
+    pub start_time: DateTimeUtc,
+    pub quantities: Vec<QuantityAggregate>,
+}
+
+impl Aggregate {
+    /// The most readings of any one quantity, which is the number of
+    /// measurements unless some of them lack a quantity.
+    pub fn count(&self) -> i64 {
+        self.quantities
+            .iter()
+            .map(|quantity| quantity.count)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+pub fn latest_measurement(
+    conn: &mut DbConnection,
+    node_id: &str,
+) -> QueryResult<Option<Measurement>> {
+    let readout = readouts::table
+        .select(Readout::as_select())
+        .filter(readouts::node_id.eq(node_id))
+        .order(readouts::meas_time.desc())
+        .first(conn)
+        .optional()?;
+
+    Ok(with_readings(conn, readout.into_iter().collect())?.pop())
+}
+
+pub fn measurements_in_range(
+    conn: &mut DbConnection,
+    node_id: &str,
+    from: Option<DateTimeUtc>,
+    to: Option<DateTimeUtc>,
+    limit: i64,
+) -> QueryResult<Vec<Measurement>> {
+    let mut query = readouts::table
+        .select(Readout::as_select())
+        .filter(readouts::node_id.eq(node_id))
+        .order(readouts::meas_time.asc())
+        .limit(limit)
+        .into_boxed();
+
+    if let Some(from) = from {
+        query = query.filter(readouts::meas_time.ge(from));
+    }
+
+    if let Some(to) = to {
+        query = query.filter(readouts::meas_time.lt(to));
+    }
+
+    let readouts = query.load(conn)?;
+    with_readings(conn, readouts)
+}
+
+/// The last reading of the quantity by the node at or before `time`, as
+/// (time, value).
+pub fn reading_before(
+    conn: &mut DbConnection,
+    node_id: &str,
+    quantity: &str,
+    time: &DateTimeUtc,
+) -> QueryResult<Option<(DateTimeUtc, f32)>> {
+    readings::table
+        .inner_join(readouts::table)
+        .select((readings::time, readings::value))
+        .filter(readouts::node_id.eq(node_id))
+        .filter(readings::quantity.eq(quantity))
+        .filter(readings::time.le(time))
+        .order(readings::time.desc())
+        .first(conn)
+        .optional()
+}
+
+#[derive(Debug, Queryable)]
+pub struct NodeSummary {
+    pub node_id: String,
+    pub count: i64,
+    pub latest_time: Option<DateTimeUtc>,
+}
+
+/// Lists the nodes that there are measurements of, this one included.
+pub fn nodes(conn: &mut DbConnection) -> QueryResult<Vec<NodeSummary>> {
+    readouts::table
+        .group_by(readouts::node_id)
+        .select((readouts::node_id, count_star(), max(readouts::meas_time)))
+        .order(readouts::node_id.asc())
+        .load(conn)
+}
+
+/// Computes the min/max/mean of every quantity there are readings of over
+/// [from, to), either as a single aggregate or split into hourly/daily
+/// buckets. Bucketed aggregates come from the rollup tables and include every
+/// bucket that overlaps the range, in full.
+pub fn aggregate_measurements(
+    conn: &mut DbConnection,
+    node_id: &str,
+    from: Option<DateTimeUtc>,
+    to: Option<DateTimeUtc>,
+    bucket: Option<Bucket>,
+) -> QueryResult<Vec<Aggregate>> {
+    let query = match bucket {
+        Some(bucket) => format!(
+            "SELECT bucket_start AS start_time, quantity, unit, CAST(count AS BIGINT) AS count, \
+             min, max, CAST(sum / count AS REAL) AS mean \
+             FROM {} \
+             WHERE node_id = ?3 \
+             AND (?1 IS NULL OR bucket_start > ?1) AND (?2 IS NULL OR bucket_start < ?2) \
+             ORDER BY bucket_start, quantity",
+            bucket.rollup_table()
+        ),
+        None => "SELECT MIN(readings.time) AS start_time, quantity, MAX(unit) AS unit, \
+                 COUNT(*) AS count, MIN(value) AS min, MAX(value) AS max, \
+                 CAST(AVG(value) AS REAL) AS mean \
+                 FROM readings JOIN readouts ON readouts.id = readings.readout_id \
+                 WHERE node_id = ?3 \
+                 AND (?1 IS NULL OR readings.time >= ?1) AND (?2 IS NULL OR readings.time < ?2) \
+                 GROUP BY quantity \
+                 ORDER BY quantity"
+            .to_owned(),
+    };
+
+    // A bucket overlaps the range if it starts less than a bucket before it.
+    let from = match bucket {
+        Some(bucket) => from.and_then(|from| {
+            DateTimeUtc::from_micros(from.timestamp_micros() - bucket.as_micros())
+        }),
+        None => from,
+    };
+
+    let rows = conn
+        .sql_query(&query)
+        .bind::<Nullable<UtcTime>, _>(from)
+        .bind::<Nullable<UtcTime>, _>(to)
+        .bind::<Text, _>(node_id)
+        .load::<QuantityAggregate>(conn)?;
+
+    let mut aggregates = Vec::<Aggregate>::new();
+    for row in rows {
+        match aggregates.last_mut() {
+            // Without buckets it's all one aggregate, starting with the
+            // earliest reading of any quantity.
+            Some(aggregate) if bucket.is_none() || *aggregate.start_time == *row.start_time => {
+                if *row.start_time < *aggregate.start_time {
+                    aggregate.start_time = row.start_time.clone();
+                }
+                aggregate.quantities.push(row);
+            }
+            _ => aggregates.push(Aggregate {
+                start_time: row.start_time.clone(),
+                quantities: vec![row],
+            }),
+        }
+    }
+
+    Ok(aggregates)
+}
+
+/// Fixes up the times of the measurements taken during this boot before the
+/// clock was synchronised, from their monotonic times and the current offset
+/// between the wall clock and the monotonic clock. The measurements are moved
+/// from the rollup rows they were in to the ones they're in now. Returns the
+/// number of measurements fixed.
+pub fn retimestamp_unsynced(
+    conn: &mut DbConnection,
+    boot_id: &str,
+    now: &clock::Timestamp,
+) -> QueryResult<usize> {
+    let offset_us = now.wall.timestamp_micros() - now.monotonic_us;
+
+    conn.transaction(|conn| {
+        // Out of the rollups while they can still be found by their old
+        // times, and back in once they have their new ones.
+        rollup::remove_unsynced(conn, LOCAL_NODE_ID, boot_id)?;
+
+        let unsynced_sql = "WHERE node_id = ?2 AND NOT clock_synced AND boot_id = ?3 \
+                            AND monotonic_us IS NOT NULL";
+
+        // The readings first, they're found by their readouts still being
+        // unsynced.
+        let readout_time = conn.time_from_micros_sql(
+            "(SELECT monotonic_us FROM readouts WHERE readouts.id = readout_id) + ?1",
+        );
+        conn.sql_query(&format!(
+            "UPDATE readings SET time = {readout_time} \
+             WHERE readout_id IN (SELECT id FROM readouts {unsynced_sql})"
+        ))
+        .bind::<BigInt, _>(offset_us)
+        .bind::<Text, _>(LOCAL_NODE_ID)
+        .bind::<Text, _>(boot_id)
+        .execute(conn)?;
+
+        rollup::add_unsynced(conn, LOCAL_NODE_ID, boot_id)?;
+
+        let meas_time = conn.time_from_micros_sql("monotonic_us + ?1");
+        let fixed = conn
+            .sql_query(&format!(
+                "UPDATE readouts SET meas_time = {meas_time}, clock_synced = TRUE {unsynced_sql}"
+            ))
+            .bind::<BigInt, _>(offset_us)
+            .bind::<Text, _>(LOCAL_NODE_ID)
+            .bind::<Text, _>(boot_id)
+            .execute(conn)?;
+
+        Ok(fixed)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{readings, test_connection, InsertableMeasurement};
+
+    const DAY_US: i64 = 24 * 3_600 * 1_000_000;
+    const HOUR_US: i64 = 3_600 * 1_000_000;
+
+    fn daily_counts(conn: &mut DbConnection, node_id: &str) -> Vec<(i64, i64)> {
+        aggregate_measurements(conn, node_id, None, None, Some(Bucket::Day))
+            .unwrap()
+            .into_iter()
+            .map(|day| (day.start_time.timestamp_micros() / DAY_US, day.count()))
+            .collect()
+    }
+
+    #[test]
+    fn retimestamping_moves_the_measurements_between_rollups() {
+        let mut conn = test_connection();
+
+        let at = |micros| InsertableMeasurement {
+            meas_time: DateTimeUtc::from_micros(micros).unwrap(),
+            ..InsertableMeasurement::example()
+        };
+        let measurements = [
+            // Taken before the clock was synchronised, 5 hours after boot.
+            InsertableMeasurement {
+                clock_synced: false,
+                boot_id: Some("boot".to_owned()),
+                monotonic_us: Some(5 * HOUR_US),
+                ..at(10 * DAY_US + 12 * HOUR_US)
+            },
+            at(10 * DAY_US + 13 * HOUR_US),
+            InsertableMeasurement {
+                node_id: "node".to_owned(),
+                uid: Some("node-1".to_owned()),
+                ..at(10 * DAY_US + 14 * HOUR_US)
+            },
+        ];
+        for measurement in &measurements {
+            readings::insert_measurement(&mut conn, measurement).unwrap();
+        }
+        rollup::update_rollups(&mut conn, &measurements).unwrap();
+
+        // Booted at the start of day 20.
+        let now = clock::Timestamp {
+            wall: DateTimeUtc::from_micros(20 * DAY_US + 6 * HOUR_US)
+                .unwrap()
+                .0,
+            monotonic_us: 6 * HOUR_US,
+            synced: true,
+        };
+        assert_eq!(retimestamp_unsynced(&mut conn, "boot", &now).unwrap(), 1);
+
+        let moved = latest_measurement(&mut conn, LOCAL_NODE_ID)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            moved.readout.meas_time.timestamp_micros(),
+            20 * DAY_US + 5 * HOUR_US
+        );
+        assert!(moved.readout.clock_synced);
+
+        assert_eq!(daily_counts(&mut conn, LOCAL_NODE_ID), [(10, 1), (20, 1)]);
+        assert_eq!(daily_counts(&mut conn, "node"), [(10, 1)]);
+    }
+
+    #[test]
+    fn quantities_come_from_the_readings() {
+        let mut conn = test_connection();
+
+        let measurement = InsertableMeasurement {
+            meas_time: DateTimeUtc::from_micros(10 * DAY_US).unwrap(),
+            ..InsertableMeasurement::example()
+        };
+        readings::insert_measurement(&mut conn, &measurement).unwrap();
+        // A quantity that none of the sensors here measure.
+        conn.sql_query(
+            "INSERT INTO readings (readout_id, time, quantity, value, unit) \
+             SELECT id, meas_time, 'co2', 415, 'ppm' FROM readouts",
+        )
+        .execute(&mut conn)
+        .unwrap();
+        rollup::rebuild_rollups(&mut conn, None, None, None).unwrap();
+
+        let latest = latest_measurement(&mut conn, LOCAL_NODE_ID)
+            .unwrap()
+            .unwrap();
+        assert_eq!(latest.value("co2"), Some(415.0));
+        assert_eq!(latest.value("temperature"), measurement.temperature);
+
+        for bucket in [None, Some(Bucket::Hour), Some(Bucket::Day)] {
+            let aggregates =
+                aggregate_measurements(&mut conn, LOCAL_NODE_ID, None, None, bucket).unwrap();
+            assert_eq!(aggregates.len(), 1, "{bucket:?}");
+
+            let quantities = aggregates[0]
+                .quantities
+                .iter()
+                .map(|quantity| (quantity.quantity.as_str(), quantity.unit.as_str()))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                quantities,
+                [
+                    ("co2", "ppm"),
+                    ("light_level", "1"),
+                    ("pressure", "Pa"),
+                    ("temperature", "°C")
+                ],
+                "{bucket:?}"
+            );
+            assert_eq!(aggregates[0].quantities[0].mean, 415.0);
+        }
+
+        assert_eq!(
+            readings::stored_quantities(&mut conn).unwrap(),
+            ["temperature", "pressure", "light_level", "co2"]
+        );
+    }
+}
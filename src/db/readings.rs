@@ -0,0 +1,141 @@
+use diesel::dsl::min;
+use diesel::prelude::*;
+
+use std::collections::HashMap;
+
+use super::schema::{readings, readouts};
+use super::{DbConnection, InsertableMeasurement, Measurement, Reading, Readout};
+
+diesel::define_sql_function! {
+    /// The row ID of the last row inserted on this connection.
+    fn last_insert_rowid() -> Integer;
+}
+
+/// The quantities the sensors here measure, i.e. the ones of
+/// `InsertableMeasurement`, with the units their values are stored in. What's
+/// read back goes by the quantities in `readings` instead, which can be more.
+pub const QUANTITIES: [(&str, &str); 4] = [
+    ("temperature", "°C"),
+    ("humidity", "%"),
+    ("pressure", "Pa"),
+    ("light_level", "1"),
+];
+
+/// The quantities of a measurement as (quantity, value, sensor ID, unit), in
+/// the order of `QUANTITIES`.
+pub fn quantities(
+    measurement: &InsertableMeasurement,
+) -> [(&'static str, Option<f32>, Option<i32>, &'static str); 4] {
+    let values = [
+        (measurement.temperature, measurement.temperature_sensor_id),
+        (measurement.humidity, measurement.humidity_sensor_id),
+        (measurement.pressure, measurement.pressure_sensor_id),
+        (measurement.light_level, measurement.light_level_sensor_id),
+    ];
+
+    std::array::from_fn(|i| {
+        let (quantity, unit) = QUANTITIES[i];
+        let (value, sensor_id) = values[i];
+
+        (quantity, value, sensor_id, unit)
+    })
+}
+
+/// Stores the measurement as a readout with a reading for each quantity that
+/// has a value. Returns whether it was stored, it isn't if a measurement with
+/// the same UID already is.
+pub fn insert_measurement(
+    conn: &mut DbConnection,
+    measurement: &InsertableMeasurement,
+) -> QueryResult<bool> {
+    let readout = (
+        readouts::meas_time.eq(&measurement.meas_time),
+        readouts::meas_duration_us.eq(measurement.meas_duration_us),
+        readouts::clock_synced.eq(measurement.clock_synced),
+        readouts::boot_id.eq(measurement.boot_id.as_deref()),
+        readouts::monotonic_us.eq(measurement.monotonic_us),
+        readouts::node_id.eq(&measurement.node_id),
+        readouts::uid.eq(measurement.uid.as_deref()),
+        readouts::station_id.eq(measurement.station_id),
+    );
+
+    let readout_id = match conn {
+        DbConnection::Sqlite(conn) => {
+            let inserted = diesel::insert_or_ignore_into(readouts::table)
+                .values(readout)
+                .execute(conn)?;
+
+            if inserted > 0 {
+                Some(diesel::select(last_insert_rowid()).get_result::<i32>(conn)?)
+            } else {
+                None
+            }
+        }
+        DbConnection::Postgres(conn) => diesel::insert_into(readouts::table)
+            .values(readout)
+            .on_conflict_do_nothing()
+            .returning(readouts::id)
+            .get_result::<i32>(conn)
+            .optional()?,
+    };
+    let Some(readout_id) = readout_id else {
+        return Ok(false);
+    };
+
+    for (quantity, value, sensor_id, unit) in quantities(measurement) {
+        let Some(value) = value else {
+            continue;
+        };
+
+        diesel::insert_into(readings::table)
+            .values((
+                readings::readout_id.eq(readout_id),
+                readings::time.eq(&measurement.meas_time),
+                readings::sensor_id.eq(sensor_id),
+                readings::quantity.eq(quantity),
+                readings::value.eq(value),
+                readings::unit.eq(unit),
+            ))
+            .execute(conn)?;
+    }
+
+    Ok(true)
+}
+
+/// Adds their readings to the readouts, keeping their order.
+pub fn with_readings(
+    conn: &mut DbConnection,
+    readouts: Vec<Readout>,
+) -> QueryResult<Vec<Measurement>> {
+    let ids = readouts
+        .iter()
+        .map(|readout| readout.id)
+        .collect::<Vec<_>>();
+
+    let mut readings = HashMap::<i32, Vec<Reading>>::new();
+    for (readout_id, reading) in readings::table
+        .select((readings::readout_id, Reading::as_select()))
+        .filter(readings::readout_id.eq_any(ids))
+        .order(readings::id.asc())
+        .load::<(i32, Reading)>(conn)?
+    {
+        readings.entry(readout_id).or_default().push(reading);
+    }
+
+    Ok(readouts
+        .into_iter()
+        .map(|readout| Measurement {
+            readings: readings.remove(&readout.id).unwrap_or_default(),
+            readout,
+        })
+        .collect())
+}
+
+/// The quantities there are readings of, in the order they were first stored.
+pub fn stored_quantities(conn: &mut DbConnection) -> QueryResult<Vec<String>> {
+    readings::table
+        .group_by(readings::quantity)
+        .select(readings::quantity)
+        .order(min(readings::id).asc())
+        .load(conn)
+}
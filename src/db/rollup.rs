@@ -0,0 +1,289 @@
+use diesel::prelude::*;
+use diesel::sql_types::{Float, Nullable, Text};
+
+use super::query::Bucket;
+use super::readings::quantities;
+use super::{DateTimeUtc, DbConnection, InsertableMeasurement, UtcTime};
+
+/// The readings of this boot's readouts that are still waiting for the clock,
+/// with ?1 the node and ?2 the boot.
+const UNSYNCED_READINGS: &str = "readings.readout_id IN (SELECT id FROM readouts \
+                                 WHERE node_id = ?1 AND NOT clock_synced AND boot_id = ?2 \
+                                 AND monotonic_us IS NOT NULL)";
+
+/// Merges the `excluded` row of an insert into the existing rollup row.
+fn merge_sql(table: &str) -> String {
+    // CASE rather than the backends' differing scalar MIN() and LEAST().
+    format!(
+        "ON CONFLICT (node_id, bucket_start, quantity) DO UPDATE SET \
+         unit = excluded.unit, \
+         count = {table}.count + excluded.count, \
+         min = CASE WHEN excluded.min < {table}.min THEN excluded.min ELSE {table}.min END, \
+         max = CASE WHEN excluded.max > {table}.max THEN excluded.max ELSE {table}.max END, \
+         sum = {table}.sum + excluded.sum"
+    )
+}
+
+/// Adds the measurements to their node's hourly and daily rollups, a row per
+/// quantity. The rollups keep sums and counts rather than means so that they
+/// can be updated one reading at a time.
+pub fn update_rollups(
+    conn: &mut DbConnection,
+    measurements: &[InsertableMeasurement],
+) -> QueryResult<()> {
+    for bucket in [Bucket::Hour, Bucket::Day] {
+        let table = bucket.rollup_table();
+        let query = format!(
+            "INSERT INTO {table} (node_id, bucket_start, quantity, unit, count, min, max, sum) \
+             VALUES (?1, {}, ?3, ?4, 1, ?5, ?5, ?5) {}",
+            bucket.start_sql(conn, "?2"),
+            merge_sql(table)
+        );
+
+        for measurement in measurements {
+            for (quantity, value, _, unit) in quantities(measurement) {
+                let Some(value) = value else {
+                    continue;
+                };
+
+                conn.sql_query(&query)
+                    .bind::<Text, _>(&measurement.node_id)
+                    .bind::<UtcTime, _>(&measurement.meas_time)
+                    .bind::<Text, _>(quantity)
+                    .bind::<Text, _>(unit)
+                    .bind::<Float, _>(value)
+                    .execute(conn)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Takes the readings of the node's readouts from `boot_id` that are still
+/// waiting for the clock back out of the rollups, before their time is fixed
+/// up. Rows that are left empty are deleted. A minimum or maximum can't be
+/// taken back out, those of the rows that are left stay as they are.
+pub(super) fn remove_unsynced(
+    conn: &mut DbConnection,
+    node_id: &str,
+    boot_id: &str,
+) -> QueryResult<()> {
+    for bucket in [Bucket::Hour, Bucket::Day] {
+        let table = bucket.rollup_table();
+        let in_row = format!(
+            "FROM readings WHERE {UNSYNCED_READINGS} AND readings.quantity = {table}.quantity \
+             AND {} = {table}.bucket_start",
+            bucket.start_sql(conn, "readings.time")
+        );
+
+        conn.sql_query(&format!(
+            "UPDATE {table} SET count = count - (SELECT COUNT(*) {in_row}), \
+             sum = sum - (SELECT COALESCE(SUM(value), 0) {in_row}) \
+             WHERE node_id = ?1 AND EXISTS (SELECT 1 {in_row})"
+        ))
+        .bind::<Text, _>(node_id)
+        .bind::<Text, _>(boot_id)
+        .execute(conn)?;
+
+        conn.sql_query(&format!(
+            "DELETE FROM {table} WHERE node_id = ?1 AND count <= 0"
+        ))
+        .bind::<Text, _>(node_id)
+        .execute(conn)?;
+    }
+
+    Ok(())
+}
+
+/// Adds the readings of the node's readouts from `boot_id` that are still
+/// waiting for the clock back to the rollups, once their time has been fixed
+/// up, merging them into the rows that are there.
+pub(super) fn add_unsynced(
+    conn: &mut DbConnection,
+    node_id: &str,
+    boot_id: &str,
+) -> QueryResult<()> {
+    for bucket in [Bucket::Hour, Bucket::Day] {
+        let table = bucket.rollup_table();
+        let bucket_start = bucket.start_sql(conn, "readings.time");
+
+        conn.sql_query(&format!(
+            "INSERT INTO {table} (node_id, bucket_start, quantity, unit, count, min, max, sum) \
+             SELECT ?1, {bucket_start}, quantity, MAX(unit), COUNT(*), \
+             MIN(value), MAX(value), SUM(value) \
+             FROM readings WHERE {UNSYNCED_READINGS} \
+             GROUP BY {bucket_start}, quantity {}",
+            merge_sql(table)
+        ))
+        .bind::<Text, _>(node_id)
+        .bind::<Text, _>(boot_id)
+        .execute(conn)?;
+    }
+
+    Ok(())
+}
+
+/// Recomputes the rollups of every node from the raw measurements in
+/// [from, to), widened to whole days. Returns the number of hourly and daily
+/// rollup rows written, one per bucket and quantity.
+///
+/// The retention policy prunes raw measurements before `keep_before`, but
+/// keeps some of them back, so the days before it are left as they are
+/// rather than rebuilt from what's left of them.
+pub fn rebuild_rollups(
+    conn: &mut DbConnection,
+    from: Option<DateTimeUtc>,
+    to: Option<DateTimeUtc>,
+    keep_before: Option<DateTimeUtc>,
+) -> QueryResult<usize> {
+    // Days are whole hours as well, so this covers both resolutions.
+    let day_us = Bucket::Day.as_micros();
+    let from = [
+        from.map(|from| from.timestamp_micros().div_euclid(day_us) * day_us),
+        keep_before.map(|keep_before| {
+            (keep_before.timestamp_micros() + day_us - 1).div_euclid(day_us) * day_us
+        }),
+    ]
+    .into_iter()
+    .flatten()
+    .max()
+    .and_then(DateTimeUtc::from_micros);
+    let to = to.and_then(|to| {
+        DateTimeUtc::from_micros((to.timestamp_micros() + day_us - 1).div_euclid(day_us) * day_us)
+    });
+
+    conn.transaction(|conn| {
+        let mut written = 0;
+
+        for bucket in [Bucket::Hour, Bucket::Day] {
+            let table = bucket.rollup_table();
+            let bucket_start = bucket.start_sql(conn, "readings.time");
+
+            conn.sql_query(&format!(
+                "DELETE FROM {table} \
+                 WHERE (?1 IS NULL OR bucket_start >= ?1) AND (?2 IS NULL OR bucket_start < ?2)"
+            ))
+            .bind::<Nullable<UtcTime>, _>(&from)
+            .bind::<Nullable<UtcTime>, _>(&to)
+            .execute(conn)?;
+
+            written += conn
+                .sql_query(&format!(
+                    "INSERT INTO {table} \
+                     (node_id, bucket_start, quantity, unit, count, min, max, sum) \
+                     SELECT node_id, {bucket_start}, quantity, MAX(unit), COUNT(*), \
+                     MIN(value), MAX(value), SUM(value) \
+                     FROM readings JOIN readouts ON readouts.id = readings.readout_id \
+                     WHERE (?1 IS NULL OR readings.time >= ?1) \
+                     AND (?2 IS NULL OR readings.time < ?2) \
+                     GROUP BY node_id, {bucket_start}, quantity"
+                ))
+                .bind::<Nullable<UtcTime>, _>(&from)
+                .bind::<Nullable<UtcTime>, _>(&to)
+                .execute(conn)?;
+        }
+
+        Ok(written)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::query::aggregate_measurements;
+    use crate::db::{readings, test_connection, LOCAL_NODE_ID};
+
+    const DAY_US: i64 = 24 * 3_600 * 1_000_000;
+
+    /// Noon of the `day`th day after the epoch.
+    fn measurement_on(day: i64) -> InsertableMeasurement {
+        InsertableMeasurement {
+            meas_time: DateTimeUtc::from_micros(day * DAY_US + DAY_US / 2).unwrap(),
+            ..InsertableMeasurement::example()
+        }
+    }
+
+    #[test]
+    fn rebuild_keeps_the_rollups_of_pruned_measurements() {
+        let mut conn = test_connection();
+
+        let measurements = (20_000..20_003).map(measurement_on).collect::<Vec<_>>();
+        for measurement in &measurements {
+            readings::insert_measurement(&mut conn, measurement).unwrap();
+        }
+        update_rollups(&mut conn, &measurements).unwrap();
+
+        // What the retention policy leaves of the raw measurements.
+        let cutoff = DateTimeUtc::from_micros(20_002 * DAY_US).unwrap();
+        for (table, key) in [("readings", "time"), ("readouts", "meas_time")] {
+            conn.sql_query(&format!("DELETE FROM {table} WHERE {key} < ?1"))
+                .bind::<UtcTime, _>(&cutoff)
+                .execute(&mut conn)
+                .unwrap();
+        }
+
+        // An hourly and a daily row for each quantity of the day that's left.
+        assert_eq!(
+            rebuild_rollups(&mut conn, None, None, Some(cutoff)).unwrap(),
+            6
+        );
+
+        let days = aggregate_measurements(&mut conn, LOCAL_NODE_ID, None, None, Some(Bucket::Day))
+            .unwrap();
+        assert_eq!(days.len(), 3);
+        for (day, measurement) in days.iter().zip(&measurements) {
+            assert_eq!(day.start_time.timestamp_micros() % DAY_US, 0);
+            assert_eq!(day.count(), 1);
+            let temperature = &day.quantities[2];
+            assert_eq!(temperature.quantity, "temperature");
+            assert_eq!(Some(temperature.mean), measurement.temperature);
+        }
+    }
+
+    #[test]
+    fn rebuild_keeps_partly_pruned_days() {
+        let mut conn = test_connection();
+
+        let measurements = [
+            measurement_on(20_000),
+            // Kept back by the retention policy, it's waiting for the clock.
+            InsertableMeasurement {
+                temperature: Some(25.0),
+                clock_synced: false,
+                ..measurement_on(20_000)
+            },
+            measurement_on(20_001),
+        ];
+        for measurement in &measurements {
+            readings::insert_measurement(&mut conn, measurement).unwrap();
+        }
+        update_rollups(&mut conn, &measurements).unwrap();
+
+        let cutoff = DateTimeUtc::from_micros(20_001 * DAY_US).unwrap();
+        conn.sql_query(
+            "DELETE FROM readings WHERE time < ?1 \
+             AND readout_id IN (SELECT id FROM readouts WHERE clock_synced)",
+        )
+        .bind::<UtcTime, _>(&cutoff)
+        .execute(&mut conn)
+        .unwrap();
+        conn.sql_query("DELETE FROM readouts WHERE meas_time < ?1 AND clock_synced")
+            .bind::<UtcTime, _>(&cutoff)
+            .execute(&mut conn)
+            .unwrap();
+
+        rebuild_rollups(&mut conn, None, None, Some(cutoff)).unwrap();
+
+        for bucket in [Bucket::Hour, Bucket::Day] {
+            let aggregates =
+                aggregate_measurements(&mut conn, LOCAL_NODE_ID, None, None, Some(bucket)).unwrap();
+            assert_eq!(aggregates.len(), 2, "{bucket:?}");
+            assert_eq!(aggregates[0].count(), 2, "{bucket:?}");
+            let temperature = &aggregates[0].quantities[2];
+            assert_eq!(temperature.quantity, "temperature");
+            assert_eq!(temperature.max, 25.0);
+            assert_eq!(aggregates[1].count(), 1, "{bucket:?}");
+        }
+    }
+}
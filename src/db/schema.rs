@@ -1,12 +1,159 @@
 // @generated automatically by Diesel CLI.
 
 diesel::table! {
-    measurements (id) {
+    use diesel::sql_types::*;
+    use crate::db::UtcTime;
+
+    alert_states (rule) {
+        rule -> Text,
+        state -> Text,
+        since -> UtcTime,
+        value -> Nullable<Float>,
+        updated_at -> UtcTime,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::db::UtcTime;
+
+    forecasts (id) {
+        id -> Integer,
+        time -> UtcTime,
+        sea_level_pressure -> Float,
+        pressure_change -> Float,
+        tendency -> Text,
+        zambretti -> Integer,
+        forecast -> Text,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::db::UtcTime;
+
+    measurements_daily (node_id, bucket_start, quantity) {
+        node_id -> Text,
+        bucket_start -> UtcTime,
+        quantity -> Text,
+        unit -> Text,
+        count -> Integer,
+        min -> Float,
+        max -> Float,
+        sum -> Float,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::db::UtcTime;
+
+    measurements_hourly (node_id, bucket_start, quantity) {
+        node_id -> Text,
+        bucket_start -> UtcTime,
+        quantity -> Text,
+        unit -> Text,
+        count -> Integer,
+        min -> Float,
+        max -> Float,
+        sum -> Float,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::db::UtcTime;
+
+    readings (id) {
+        id -> Integer,
+        readout_id -> Integer,
+        time -> UtcTime,
+        sensor_id -> Nullable<Integer>,
+        quantity -> Text,
+        value -> Float,
+        unit -> Text,
+        quality -> Text,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::db::UtcTime;
+
+    readouts (id) {
+        id -> Integer,
+        meas_time -> UtcTime,
+        meas_duration_us -> Nullable<BigInt>,
+        clock_synced -> Bool,
+        boot_id -> Nullable<Text>,
+        monotonic_us -> Nullable<BigInt>,
+        uploaded_at -> Nullable<UtcTime>,
+        node_id -> Text,
+        uid -> Nullable<Text>,
+        station_id -> Nullable<Integer>,
+        upload_error -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::db::UtcTime;
+
+    sensors (id) {
+        id -> Integer,
+        station_id -> Integer,
+        chip -> Text,
+        chip_id -> Nullable<Integer>,
+        i2c_bus -> Nullable<Text>,
+        i2c_address -> Nullable<Integer>,
+        calibration -> Nullable<Binary>,
+        first_seen -> UtcTime,
+        last_seen -> UtcTime,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::db::UtcTime;
+
+    stations (id) {
+        id -> Integer,
+        node_id -> Text,
+        hostname -> Nullable<Text>,
+        location -> Nullable<Text>,
+        first_seen -> UtcTime,
+        last_seen -> UtcTime,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::db::UtcTime;
+
+    webhook_dead_letters (id) {
         id -> Integer,
-        meas_time -> BigInt,
-        temperature -> Nullable<Float>,
-        humidity -> Nullable<Float>,
-        pressure -> Nullable<Float>,
-        light_level -> Nullable<Float>,
+        url -> Text,
+        body -> Text,
+        attempts -> Integer,
+        error -> Text,
+        created_at -> UtcTime,
+        failed_at -> UtcTime,
     }
 }
+
+diesel::joinable!(readings -> readouts (readout_id));
+diesel::joinable!(readings -> sensors (sensor_id));
+diesel::joinable!(readouts -> stations (station_id));
+diesel::joinable!(sensors -> stations (station_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    alert_states,
+    forecasts,
+    measurements_daily,
+    measurements_hourly,
+    readings,
+    readouts,
+    sensors,
+    stations,
+    webhook_dead_letters,
+);
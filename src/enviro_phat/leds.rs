@@ -0,0 +1,212 @@
+use anyhow::{Context, Result};
+use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::var_or;
+
+/// Both of the Enviro pHAT's white LEDs are on this line of the SoC's GPIO
+/// chip.
+const ENVIRO_PHAT_LED_LINE: u32 = 4;
+/// What the line shows up as in `gpioinfo` while we hold it.
+const CONSUMER: &str = "rpi_client_temp";
+
+#[derive(Debug, Clone)]
+pub struct LedConfig {
+    chip_path: PathBuf,
+    line: u32,
+    illuminate: bool,
+    status_blink: bool,
+}
+
+impl LedConfig {
+    const CHIP_PATH_ENV_VAR: &'static str = "LED_GPIO_CHIP";
+    const LINE_ENV_VAR: &'static str = "LED_GPIO_LINE";
+    const ILLUMINATE_ENV_VAR: &'static str = "LED_ILLUMINATE";
+    const STATUS_BLINK_ENV_VAR: &'static str = "LED_STATUS_BLINK";
+
+    /// Returns `None` if the LEDs aren't used for anything, in which case
+    /// the line is left alone.
+    pub fn from_env() -> Result<Option<Self>> {
+        let illuminate = var_or(Self::ILLUMINATE_ENV_VAR, false)?;
+        let status_blink = var_or(Self::STATUS_BLINK_ENV_VAR, false)?;
+
+        if !illuminate && !status_blink {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            // Another chip or line can be set for the gpio-mockup module, or
+            // for LEDs wired up elsewhere.
+            chip_path: var_or(Self::CHIP_PATH_ENV_VAR, PathBuf::from("/dev/gpiochip0"))?,
+            line: var_or(Self::LINE_ENV_VAR, ENVIRO_PHAT_LED_LINE)?,
+            illuminate,
+            status_blink,
+        }))
+    }
+
+    /// Whether the LEDs are to blink a pattern while something's wrong.
+    pub fn status_blink(&self) -> bool {
+        self.status_blink
+    }
+}
+
+/// A light that can be switched on and off. Implemented by the GPIO line, and
+/// by whatever stands in for it where there's none.
+pub trait Led: Send {
+    fn set(&mut self, on: bool) -> Result<()>;
+}
+
+impl Led for LineHandle {
+    fn set(&mut self, on: bool) -> Result<()> {
+        Ok(self.set_value(on as u8)?)
+    }
+}
+
+/// Records how it's switched, and fails when told to.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub struct MockLed {
+    /// Every switch that went through, in order.
+    pub switches: std::sync::Arc<Mutex<Vec<bool>>>,
+    /// How many of the next switches fail.
+    pub failures: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+}
+
+#[cfg(test)]
+impl Led for MockLed {
+    fn set(&mut self, on: bool) -> Result<()> {
+        use std::sync::atomic::Ordering;
+
+        let failures = self.failures.load(Ordering::SeqCst);
+        if failures > 0 {
+            self.failures.store(failures - 1, Ordering::SeqCst);
+            return Err(anyhow::anyhow!("Failed to set the line"));
+        }
+
+        self.switches.lock().unwrap().push(on);
+        Ok(())
+    }
+}
+
+/// The LEDs, shared between the colour reads that light up what's in front of
+/// the sensor and the status blinking. Whoever switches them holds the lock
+/// until they're off again, so that the two don't get in each other's way.
+pub struct Leds {
+    led: Mutex<Box<dyn Led>>,
+    illuminate: bool,
+}
+
+impl Leds {
+    /// Requests the line as an output, with the LEDs off.
+    pub fn open(config: &LedConfig) -> Result<Leds> {
+        let line = Chip::new(&config.chip_path)
+            .and_then(|mut chip| chip.get_line(config.line))
+            .and_then(|line| line.request(LineRequestFlags::OUTPUT, 0, CONSUMER))
+            .with_context(|| {
+                format!(
+                    "Failed to request line {} of {}",
+                    config.line,
+                    config.chip_path.display()
+                )
+            })?;
+
+        log::info!(
+            "Using the LEDs on line {} of {}",
+            config.line,
+            config.chip_path.display()
+        );
+
+        Ok(Leds::new(Box::new(line), config.illuminate))
+    }
+
+    /// Takes any `Led`, e.g. a mock that records how it's switched.
+    pub fn new(led: Box<dyn Led>, illuminate: bool) -> Leds {
+        Leds {
+            led: Mutex::new(led),
+            illuminate,
+        }
+    }
+
+    /// Runs the colour read with the LEDs on if the config says so, so that
+    /// what's measured is the light reflected off the object in front of the
+    /// sensor rather than the ambient light. The read waits for `settle`
+    /// after they're switched on. They're switched off again even if the read
+    /// fails.
+    pub fn illuminated<T>(&self, settle: Duration, read: impl FnOnce() -> Result<T>) -> Result<T> {
+        if !self.illuminate {
+            return read();
+        }
+
+        let mut led = self.led.lock().unwrap();
+
+        led.set(true).context("Failed to switch the LEDs on")?;
+        thread::sleep(settle);
+        let res = read();
+        let off_res = led.set(false).context("Failed to switch the LEDs off");
+
+        let value = res?;
+        off_res?;
+
+        Ok(value)
+    }
+
+    /// Flashes the LEDs for `on` and leaves them off for `off` after, once
+    /// for each of the pattern's pairs. Blocks until done.
+    pub fn blink(&self, pattern: &[(Duration, Duration)]) -> Result<()> {
+        let mut led = self.led.lock().unwrap();
+
+        for &(on, off) in pattern {
+            led.set(true)?;
+            thread::sleep(on);
+            led.set(false)?;
+            thread::sleep(off);
+        }
+
+        Ok(())
+    }
+
+    pub fn off(&self) -> Result<()> {
+        self.led.lock().unwrap().set(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::anyhow;
+
+    #[test]
+    fn illuminated_switches_off_when_the_read_fails() {
+        let led = MockLed::default();
+        let leds = Leds::new(Box::new(led.clone()), true);
+
+        let res = leds.illuminated(Duration::ZERO, || Err::<(), _>(anyhow!("Read failed")));
+
+        assert_eq!(res.unwrap_err().to_string(), "Read failed");
+        assert_eq!(*led.switches.lock().unwrap(), [true, false]);
+    }
+
+    #[test]
+    fn illuminated_leaves_the_leds_alone_unless_configured() {
+        let led = MockLed::default();
+        let leds = Leds::new(Box::new(led.clone()), false);
+
+        assert_eq!(leds.illuminated(Duration::ZERO, || Ok(1)).unwrap(), 1);
+        assert!(led.switches.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn blinks_the_pattern() {
+        let led = MockLed::default();
+        let leds = Leds::new(Box::new(led.clone()), false);
+
+        leds.blink(&[(Duration::ZERO, Duration::ZERO); 2]).unwrap();
+
+        assert_eq!(*led.switches.lock().unwrap(), [true, false, true, false]);
+    }
+}
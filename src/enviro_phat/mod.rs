@@ -1,5 +1,11 @@
 use anyhow::Result;
 
+use std::time::Duration;
+
+use crate::clock;
+
+pub mod leds;
+
 #[cfg(feature = "enviro_phat_v1")]
 mod v1;
 #[cfg(feature = "enviro_phat_v1")]
@@ -17,13 +23,45 @@ pub struct Pressure(pub f32);
 #[derive(Debug, PartialEq, PartialOrd)]
 pub struct LightLevel(pub f32);
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantity {
+    Temperature,
+    Pressure,
+    LightLevel,
+}
+
+/// A single readout of all the detected sensors. Quantities measured by
+/// sensors that aren't present are `None`.
 #[derive(Debug)]
 pub struct Measurement {
-    pub pressure: Pressure,
-    pub temperature: Temperature,
-    pub light_level: LightLevel,
+    pub pressure: Option<Pressure>,
+    pub temperature: Option<Temperature>,
+    pub light_level: Option<LightLevel>,
+    /// When the readout started.
+    pub started_at: clock::Timestamp,
+    /// How long reading out all the sensors took, including waiting for
+    /// conversions.
+    pub duration: Duration,
+}
+
+/// A detected sensor chip, as recorded in the sensor inventory.
+#[derive(Debug, Clone)]
+pub struct SensorInfo {
+    /// Also the name the sensor goes by in the metrics.
+    pub chip: &'static str,
+    pub chip_id: Option<u8>,
+    pub i2c_address: Option<u16>,
+    /// The factory calibration block, which tells chips of the same kind
+    /// apart.
+    pub calibration: Option<Vec<u8>>,
+    pub quantities: Vec<Quantity>,
 }
 
 pub trait MeasureEnvironment {
+    /// The quantities the detected sensors are able to measure.
+    fn quantities(&self) -> Vec<Quantity>;
+    /// The sensors that were detected.
+    fn sensors(&self) -> Vec<SensorInfo>;
     fn measure(&self) -> Result<Measurement>;
+    fn power_down(&self) -> Result<()>;
 }
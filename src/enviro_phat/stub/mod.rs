@@ -1,28 +1,70 @@
 use anyhow::Result;
 
 use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use super::leds::Leds;
 use super::{LightLevel, Pressure, Temperature};
-use super::{MeasureEnvironment, Measurement};
+use super::{MeasureEnvironment, Measurement, Quantity, SensorInfo};
+use crate::clock;
+use crate::metrics::METRICS;
 
-pub struct EnviroPHatStub(());
+pub struct EnviroPHatStub {
+    leds: Option<Arc<Leds>>,
+}
 
 impl EnviroPHatStub {
-    pub fn new(_i2c_bus_path: &Path) -> Result<EnviroPHatStub> {
-        Ok(EnviroPHatStub(()))
+    /// The LEDs are switched like on the real board, so that they can be
+    /// tried out with the gpio-mockup module.
+    pub fn new(_i2c_bus_path: &Path, leds: Option<Arc<Leds>>) -> Result<EnviroPHatStub> {
+        Ok(EnviroPHatStub { leds })
     }
 }
 
 impl MeasureEnvironment for EnviroPHatStub {
+    fn quantities(&self) -> Vec<Quantity> {
+        vec![
+            Quantity::Temperature,
+            Quantity::Pressure,
+            Quantity::LightLevel,
+        ]
+    }
+
+    fn sensors(&self) -> Vec<SensorInfo> {
+        vec![SensorInfo {
+            chip: "stub",
+            chip_id: None,
+            i2c_address: None,
+            calibration: None,
+            quantities: self.quantities(),
+        }]
+    }
+
     fn measure(&self) -> Result<Measurement> {
+        let started_at = clock::Timestamp::now();
+        let start = Instant::now();
+
         let pressure = Pressure(101325.0);
         let temperature = Temperature(24.0);
-        let light_level = LightLevel(2.4);
+        let read_light_level = || Ok(LightLevel(2.4));
+        let light_level = match &self.leds {
+            Some(leds) => leds.illuminated(Duration::ZERO, read_light_level)?,
+            None => read_light_level()?,
+        };
+
+        METRICS.record_sensor_result("stub", true);
 
         Ok(Measurement {
-            pressure,
-            temperature,
-            light_level,
+            pressure: Some(pressure),
+            temperature: Some(temperature),
+            light_level: Some(light_level),
+            started_at,
+            duration: start.elapsed(),
         })
     }
+
+    fn power_down(&self) -> Result<()> {
+        Ok(())
+    }
 }
@@ -3,9 +3,10 @@ use anyhow::{anyhow, Result};
 use std::sync::{Arc, Mutex};
 
 use i2cdev::core::*;
-use i2cdev::linux::{LinuxI2CBus, LinuxI2CMessage};
+use i2cdev::linux::LinuxI2CMessage;
 
-use super::{Pressure, Temperature};
+use super::i2c_bus::I2CBus;
+use super::{Pressure, Quantity, SensorInfo, Temperature};
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy)]
@@ -68,8 +69,10 @@ struct CalibrationData {
 }
 
 pub struct Bmp280 {
-    comm_path: Arc<Mutex<LinuxI2CBus>>,
+    comm_path: Arc<Mutex<I2CBus>>,
     calib: CalibrationData,
+    /// As read out, for the sensor inventory.
+    calib_raw: [u8; Self::CALIB_DATA_SIZE],
     press_oversampling: Oversampling,
     temp_oversampling: Oversampling,
     mode: Mode,
@@ -91,7 +94,7 @@ impl Bmp280 {
     const DATA_REG_SIZE: usize = 6;
 
     pub fn new(
-        comm_path: Arc<Mutex<LinuxI2CBus>>,
+        comm_path: Arc<Mutex<I2CBus>>,
         standby_time: StandbyTime,
         iir_coef: IIRCoeficient,
         press_oversampling: Oversampling,
@@ -151,6 +154,7 @@ impl Bmp280 {
         let bmp = Bmp280 {
             comm_path,
             calib,
+            calib_raw: calib_data,
             press_oversampling,
             temp_oversampling,
             mode,
@@ -171,6 +175,16 @@ impl Bmp280 {
         Ok(bmp)
     }
 
+    pub fn sensor_info(&self) -> SensorInfo {
+        SensorInfo {
+            chip: "bmp280",
+            chip_id: Some(Self::CHIP_ID_EXPECTED),
+            i2c_address: Some(Self::I2C_ADDR),
+            calibration: Some(self.calib_raw.to_vec()),
+            quantities: vec![Quantity::Temperature, Quantity::Pressure],
+        }
+    }
+
     pub fn query_press_and_temp(&self) -> Result<(Pressure, Temperature)> {
         if self.mode != Mode::Normal {
             let ctrl_meas_reg = ((self.temp_oversampling as u8) << 5)
@@ -233,7 +247,7 @@ impl Bmp280 {
         let t_fine = t_var1 + t_var2;
         let output_temp = t_fine / 5120.0;
 
-        let mut p_var1: f32 = (t_fine as f32) / 2.0 - 64000.0;
+        let mut p_var1: f32 = t_fine / 2.0 - 64000.0;
         let mut p_var2: f32 = p_var1 * p_var1 * (self.calib.dig_p6 as f32) / 32768.0
             + p_var1 * (self.calib.dig_p5 as f32) * 2.0;
         p_var2 = (p_var2 / 4.0) + ((self.calib.dig_p4 as f32) * 65536.0);
@@ -257,6 +271,23 @@ impl Bmp280 {
         Ok((Pressure(output_press), Temperature(output_temp)))
     }
 
+    pub fn sleep(&self) -> Result<()> {
+        log::debug!("Putting BMP280 into sleep mode.");
+
+        let ctrl_meas_reg = ((self.temp_oversampling as u8) << 5)
+            | ((self.press_oversampling as u8) << 2)
+            | (Mode::Sleep as u8);
+
+        let mut config_msgs = [
+            LinuxI2CMessage::write(&[Self::CTRL_MEAS_REG_ADDR, ctrl_meas_reg])
+                .with_address(Self::I2C_ADDR),
+        ];
+
+        self.comm_path.lock().unwrap().transfer(&mut config_msgs)?;
+
+        Ok(())
+    }
+
     fn reconfigure(
         &self,
         standby_time: StandbyTime,
@@ -0,0 +1,33 @@
+use anyhow::Result;
+
+use i2cdev::core::*;
+use i2cdev::linux::{LinuxI2CBus, LinuxI2CError, LinuxI2CMessage};
+
+use std::path::Path;
+
+use crate::metrics::METRICS;
+
+/// Thin wrapper around `LinuxI2CBus` that counts failed transfers.
+pub struct I2CBus(LinuxI2CBus);
+
+impl I2CBus {
+    pub fn new(i2c_bus_path: &Path) -> Result<I2CBus> {
+        Ok(I2CBus(LinuxI2CBus::new(i2c_bus_path)?))
+    }
+}
+
+impl<'a> I2CTransfer<'a> for I2CBus {
+    type Error = LinuxI2CError;
+    type Message = LinuxI2CMessage<'a>;
+
+    fn transfer(&mut self, msgs: &'a mut [Self::Message]) -> Result<u32, LinuxI2CError> {
+        let res = self.0.transfer(msgs);
+
+        if let Err(e) = &res {
+            log::warn!("I2C transfer failed: {e}");
+            METRICS.i2c_transfer_errors.inc();
+        }
+
+        res
+    }
+}
@@ -1,28 +1,36 @@
 mod bmp280;
+mod i2c_bus;
 mod tcs3472;
 
-use anyhow::Result;
-use i2cdev::linux::LinuxI2CBus;
+use anyhow::{anyhow, Result};
 
 use bmp280::{Bmp280, IIRCoeficient, Mode, Oversampling, StandbyTime};
+use i2c_bus::I2CBus;
 use tcs3472::Tcs3472;
 
 use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
+use super::leds::Leds;
 use super::{LightLevel, Pressure, Temperature};
-use super::{MeasureEnvironment, Measurement};
+use super::{MeasureEnvironment, Measurement, Quantity, SensorInfo};
+use crate::clock;
+use crate::metrics::METRICS;
 
 pub struct EnviroPHatV1 {
-    bmp: Bmp280,
-    tcs: Tcs3472,
+    bmp: Option<Bmp280>,
+    tcs: Option<Tcs3472>,
+    leds: Option<Arc<Leds>>,
 }
 
 impl EnviroPHatV1 {
-    pub fn new(i2c_bus_path: &Path) -> Result<EnviroPHatV1> {
-        let i2c_bus = LinuxI2CBus::new(i2c_bus_path)?;
+    pub fn new(i2c_bus_path: &Path, leds: Option<Arc<Leds>>) -> Result<EnviroPHatV1> {
+        let i2c_bus = I2CBus::new(i2c_bus_path)?;
         let comm_channel = Arc::new(Mutex::new(i2c_bus));
 
+        // Keep going with whatever sensors respond, a board with a dead chip is
+        // still useful for the quantities the other one measures.
         let bmp = bmp280::Bmp280::new(
             comm_channel.clone(),
             StandbyTime::Time1000ms,
@@ -30,23 +38,81 @@ impl EnviroPHatV1 {
             Oversampling::Mult16X,
             Oversampling::Mult2X,
             Mode::Normal,
-        )?;
+        )
+        .map_err(|e| log::warn!("BMP280 not detected: {e}"))
+        .ok();
 
-        let tcs = tcs3472::Tcs3472::new(comm_channel)?;
+        let tcs = tcs3472::Tcs3472::new(comm_channel)
+            .map_err(|e| log::warn!("TCS3472 not detected: {e}"))
+            .ok();
 
-        Ok(EnviroPHatV1 { bmp, tcs })
+        if bmp.is_none() && tcs.is_none() {
+            return Err(anyhow!("No sensors detected on {}", i2c_bus_path.display()));
+        }
+
+        Ok(EnviroPHatV1 { bmp, tcs, leds })
     }
 }
 
 impl MeasureEnvironment for EnviroPHatV1 {
+    fn quantities(&self) -> Vec<Quantity> {
+        let mut quantities = Vec::new();
+
+        if self.bmp.is_some() {
+            quantities.extend([Quantity::Temperature, Quantity::Pressure]);
+        }
+
+        if self.tcs.is_some() {
+            quantities.push(Quantity::LightLevel);
+        }
+
+        quantities
+    }
+
+    fn sensors(&self) -> Vec<SensorInfo> {
+        let bmp = self.bmp.as_ref().map(|bmp| bmp.sensor_info());
+        let tcs = self.tcs.as_ref().map(|tcs| tcs.sensor_info());
+
+        bmp.into_iter().chain(tcs).collect()
+    }
+
     fn measure(&self) -> Result<Measurement> {
-        let (pressure, temperature) = self.bmp.query_press_and_temp()?;
-        let light_level = self.tcs.query_light_level()?;
+        let started_at = clock::Timestamp::now();
+        let start = Instant::now();
+
+        let bmp_res = self.bmp.as_ref().map(|bmp| bmp.query_press_and_temp());
+        if let Some(bmp_res) = &bmp_res {
+            METRICS.record_sensor_result("bmp280", bmp_res.is_ok());
+        }
+
+        let tcs_res = self.tcs.as_ref().map(|tcs| match &self.leds {
+            // The integration cycle that's running when the LEDs come on is
+            // only partly lit, the one after it is the first to count.
+            Some(leds) => leds.illuminated(2 * tcs.integration_time(), || tcs.query_light_level()),
+            None => tcs.query_light_level(),
+        });
+        if let Some(tcs_res) = &tcs_res {
+            METRICS.record_sensor_result("tcs3472", tcs_res.is_ok());
+        }
+
+        let (pressure, temperature) = bmp_res.transpose()?.unzip();
+        let light_level = tcs_res.transpose()?;
 
         Ok(Measurement {
             pressure,
             temperature,
             light_level,
+            started_at,
+            duration: start.elapsed(),
         })
     }
+
+    fn power_down(&self) -> Result<()> {
+        // Try both sensors even if the first one fails, so that we don't leave
+        // one of them running.
+        let bmp_res = self.bmp.as_ref().map_or(Ok(()), |bmp| bmp.sleep());
+        let tcs_res = self.tcs.as_ref().map_or(Ok(()), |tcs| tcs.power_off());
+
+        bmp_res.and(tcs_res)
+    }
 }
@@ -1,11 +1,13 @@
 use anyhow::{anyhow, Result};
 
 use i2cdev::core::*;
-use i2cdev::linux::{LinuxI2CBus, LinuxI2CMessage};
+use i2cdev::linux::LinuxI2CMessage;
 
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use super::LightLevel;
+use super::i2c_bus::I2CBus;
+use super::{LightLevel, Quantity, SensorInfo};
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy)]
@@ -18,7 +20,8 @@ pub enum Gain {
 }
 
 pub struct Tcs3472 {
-    comm_channel: Arc<Mutex<LinuxI2CBus>>,
+    comm_channel: Arc<Mutex<I2CBus>>,
+    chip_id: u8,
 }
 
 impl Tcs3472 {
@@ -33,8 +36,8 @@ impl Tcs3472 {
 
     #[allow(dead_code)]
     const TIMING_REG_ADDR: u8 = 0x01;
-    #[allow(dead_code)]
     const TIMING_REG_STEP_MS: f32 = 2.4;
+    const PERIOD_COUNT: u8 = 64;
 
     const CONTROL_REG_ADDR: u8 = 0x0f;
 
@@ -44,7 +47,7 @@ impl Tcs3472 {
     const CLEAR_DATA_REG_ADDR: u8 = 0x14;
     const CLEAR_DATA_REG_SIZE: usize = 2;
 
-    pub fn new(comm_channel: Arc<Mutex<LinuxI2CBus>>) -> Result<Tcs3472> {
+    pub fn new(comm_channel: Arc<Mutex<I2CBus>>) -> Result<Tcs3472> {
         // Check we have the correct sensor
         let mut id_data = [0];
         let mut id_msgs = [
@@ -74,8 +77,7 @@ impl Tcs3472 {
             Self::CMD_REG_MASK | Self::CMD_REG_AUTOINCREMENT | Self::ENABLE_REG_ADDR;
         let enable_reg = Self::ENABLE_REG_AEN | Self::ENABLE_REG_PON;
 
-        let period_count = 64;
-        let timing_reg = u8::MAX - period_count;
+        let timing_reg = u8::MAX - Self::PERIOD_COUNT;
 
         let cmd_reg_control = Self::CMD_REG_MASK | Self::CONTROL_REG_ADDR;
         let control_reg = Gain::Mult1X as u8;
@@ -88,7 +90,26 @@ impl Tcs3472 {
 
         comm_channel.lock().unwrap().transfer(&mut config_msgs)?;
 
-        Ok(Tcs3472 { comm_channel })
+        Ok(Tcs3472 {
+            comm_channel,
+            chip_id: id_data[0],
+        })
+    }
+
+    pub fn sensor_info(&self) -> SensorInfo {
+        SensorInfo {
+            chip: "tcs3472",
+            chip_id: Some(self.chip_id),
+            i2c_address: Some(Self::I2C_ADDR),
+            calibration: None,
+            quantities: vec![Quantity::LightLevel],
+        }
+    }
+
+    /// How long one integration cycle takes. A reading reflects the light
+    /// over the last complete cycle.
+    pub fn integration_time(&self) -> Duration {
+        Duration::from_secs_f32(Self::PERIOD_COUNT as f32 * Self::TIMING_REG_STEP_MS / 1000.0)
     }
 
     pub fn query_light_level(&self) -> Result<LightLevel> {
@@ -111,4 +132,21 @@ impl Tcs3472 {
 
         Ok(LightLevel((raw_val as f32) / (u16::MAX as f32)))
     }
+
+    pub fn power_off(&self) -> Result<()> {
+        log::debug!("Powering off TCS3472.");
+
+        // Clearing PON (and AEN with it) puts the chip into its sleep state.
+        let cmd_reg_enable = Self::CMD_REG_MASK | Self::ENABLE_REG_ADDR;
+
+        let mut config_msgs =
+            [LinuxI2CMessage::write(&[cmd_reg_enable, 0x00]).with_address(Self::I2C_ADDR)];
+
+        self.comm_channel
+            .lock()
+            .unwrap()
+            .transfer(&mut config_msgs)?;
+
+        Ok(())
+    }
 }
@@ -0,0 +1,738 @@
+use anyhow::Result;
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::{Args, ValueEnum};
+use diesel::prelude::*;
+use parquet::basic::Compression;
+use parquet::column::writer::ColumnWriter;
+use parquet::data_type::ByteArray;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::db::schema::readouts;
+use crate::db::{self, DateTimeUtc, DbConnection, Measurement, Readout};
+
+/// How many measurements are read from the DB at a time.
+const EXPORT_PAGE_SIZE: i64 = 10_000;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Format {
+    Csv,
+    Jsonl,
+    Parquet,
+}
+
+/// One of the readouts' columns, or the readings of a quantity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Column {
+    Id,
+    Time,
+    MeasDuration,
+    ClockSynced,
+    BootId,
+    Monotonic,
+    NodeId,
+    Quantity(String),
+}
+
+impl Column {
+    pub fn name(&self) -> &str {
+        match self {
+            Column::Id => "id",
+            Column::Time => "time",
+            Column::MeasDuration => "meas_duration_us",
+            Column::ClockSynced => "clock_synced",
+            Column::BootId => "boot_id",
+            Column::Monotonic => "monotonic_us",
+            Column::NodeId => "node_id",
+            Column::Quantity(quantity) => quantity,
+        }
+    }
+}
+
+impl FromStr for Column {
+    type Err = String;
+
+    /// Anything that isn't a readout column is taken to be a quantity.
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Ok(match name {
+            "" => return Err("Empty column name".to_owned()),
+            "id" => Column::Id,
+            "time" => Column::Time,
+            "meas_duration_us" => Column::MeasDuration,
+            "clock_synced" => Column::ClockSynced,
+            "boot_id" => Column::BootId,
+            "monotonic_us" => Column::Monotonic,
+            "node_id" => Column::NodeId,
+            quantity => Column::Quantity(quantity.to_owned()),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum TimeFormat {
+    /// RFC 3339 with microseconds, a native timestamp in Parquet.
+    Iso8601,
+    /// Fractional seconds since the Unix epoch.
+    EpochS,
+    /// Whole milliseconds since the Unix epoch.
+    EpochMs,
+    /// Microseconds since the Unix epoch, as stored in the DB.
+    EpochUs,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum TemperatureUnit {
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+}
+
+impl TemperatureUnit {
+    pub fn convert_celsius(self, t: f32) -> f32 {
+        match self {
+            TemperatureUnit::Celsius => t,
+            TemperatureUnit::Fahrenheit => t * 9.0 / 5.0 + 32.0,
+            TemperatureUnit::Kelvin => t + 273.15,
+        }
+    }
+
+    pub fn to_celsius(self, t: f32) -> f32 {
+        match self {
+            TemperatureUnit::Celsius => t,
+            TemperatureUnit::Fahrenheit => (t - 32.0) * 5.0 / 9.0,
+            TemperatureUnit::Kelvin => t - 273.15,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum PressureUnit {
+    Pa,
+    Hpa,
+    Kpa,
+    Inhg,
+    Mmhg,
+}
+
+impl PressureUnit {
+    fn pascals_per_unit(self) -> f32 {
+        match self {
+            PressureUnit::Pa => 1.0,
+            PressureUnit::Hpa => 100.0,
+            PressureUnit::Kpa => 1000.0,
+            PressureUnit::Inhg => 3386.389,
+            PressureUnit::Mmhg => 133.322_39,
+        }
+    }
+
+    pub fn convert_pascals(self, p: f32) -> f32 {
+        p / self.pascals_per_unit()
+    }
+
+    pub fn to_pascals(self, p: f32) -> f32 {
+        p * self.pascals_per_unit()
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum LightLevelUnit {
+    /// Relative to the sensor's full scale, as stored in the DB.
+    Ratio,
+    Percent,
+}
+
+impl LightLevelUnit {
+    pub fn convert_ratio(self, l: f32) -> f32 {
+        match self {
+            LightLevelUnit::Ratio => l,
+            LightLevelUnit::Percent => l * 100.0,
+        }
+    }
+
+    pub fn to_ratio(self, l: f32) -> f32 {
+        match self {
+            LightLevelUnit::Ratio => l,
+            LightLevelUnit::Percent => l / 100.0,
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ExportArgs {
+    /// Only export measurements taken at or after this time (RFC 3339).
+    #[arg(long)]
+    from: Option<DateTime<Utc>>,
+    /// Only export measurements taken before this time (RFC 3339).
+    #[arg(long)]
+    to: Option<DateTime<Utc>>,
+    /// Only export the measurements of this node, e.g. `--node ''` for the
+    /// ones taken here. All of them if not given.
+    #[arg(long)]
+    node: Option<String>,
+    #[arg(long, value_enum, default_value_t = Format::Csv)]
+    format: Format,
+    /// Comma separated list of the columns to export, in order: id, time,
+    /// meas_duration_us, clock_synced, boot_id, monotonic_us, node_id or a
+    /// quantity. id, time, every quantity there are readings of and
+    /// meas_duration_us if not given.
+    #[arg(long, value_delimiter = ',')]
+    columns: Vec<Column>,
+    #[arg(long, value_enum, default_value_t = TimeFormat::Iso8601)]
+    time_format: TimeFormat,
+    #[arg(long, value_enum, default_value_t = TemperatureUnit::Celsius)]
+    temperature_unit: TemperatureUnit,
+    #[arg(long, value_enum, default_value_t = PressureUnit::Pa)]
+    pressure_unit: PressureUnit,
+    #[arg(long, value_enum, default_value_t = LightLevelUnit::Ratio)]
+    light_level_unit: LightLevelUnit,
+    /// File to write to, stdout if not given.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// SQLite database or postgres:// URL to export from, DATABASE_URL if not
+    /// given.
+    #[arg(long)]
+    database: Option<String>,
+}
+
+/// A single converted cell.
+enum Value {
+    Integer(i64),
+    Float(f32),
+    Double(f64),
+    Timestamp(DateTime<Utc>),
+    Boolean(bool),
+    Text(String),
+    Null,
+}
+
+impl Value {
+    fn to_csv(&self) -> String {
+        match self {
+            Value::Integer(value) => value.to_string(),
+            Value::Float(value) => value.to_string(),
+            Value::Double(value) => value.to_string(),
+            Value::Timestamp(value) => value.to_rfc3339_opts(SecondsFormat::Micros, true),
+            Value::Boolean(value) => value.to_string(),
+            Value::Text(value) => value.clone(),
+            Value::Null => String::new(),
+        }
+    }
+
+    /// Formatted by hand, going through `serde_json::Value` would widen the
+    /// floats to f64 and print the rounding noise.
+    fn to_json(&self) -> String {
+        match self {
+            Value::Integer(value) => value.to_string(),
+            Value::Float(value) if value.is_finite() => value.to_string(),
+            Value::Double(value) if value.is_finite() => value.to_string(),
+            Value::Timestamp(value) => {
+                format!("\"{}\"", value.to_rfc3339_opts(SecondsFormat::Micros, true))
+            }
+            Value::Boolean(value) => value.to_string(),
+            Value::Text(value) => serde_json::Value::from(value.as_str()).to_string(),
+            Value::Float(_) | Value::Double(_) | Value::Null => "null".to_owned(),
+        }
+    }
+}
+
+impl ExportArgs {
+    fn convert(&self, column: &Column, measurement: &Measurement) -> Value {
+        let readout = &measurement.readout;
+
+        match column {
+            Column::Id => Value::Integer(readout.id.into()),
+            Column::Time => self.convert_time(&readout.meas_time),
+            Column::MeasDuration => readout.meas_duration_us.map_or(Value::Null, Value::Integer),
+            Column::ClockSynced => Value::Boolean(readout.clock_synced),
+            Column::BootId => readout.boot_id.clone().map_or(Value::Null, Value::Text),
+            Column::Monotonic => readout.monotonic_us.map_or(Value::Null, Value::Integer),
+            Column::NodeId => Value::Text(readout.node_id.clone()),
+            Column::Quantity(quantity) => {
+                let Some(value) = measurement.value(quantity) else {
+                    return Value::Null;
+                };
+
+                // The ones there are units to choose from, the rest are
+                // exported as stored.
+                Value::Float(match quantity.as_str() {
+                    "temperature" => self.temperature_unit.convert_celsius(value),
+                    "pressure" => self.pressure_unit.convert_pascals(value),
+                    "light_level" => self.light_level_unit.convert_ratio(value),
+                    _ => value,
+                })
+            }
+        }
+    }
+
+    fn convert_time(&self, time: &DateTimeUtc) -> Value {
+        let micros = time.timestamp_micros();
+
+        match self.time_format {
+            TimeFormat::Iso8601 => Value::Timestamp(**time),
+            TimeFormat::EpochS => Value::Double(micros as f64 / 1e6),
+            TimeFormat::EpochMs => Value::Integer(micros.div_euclid(1000)),
+            TimeFormat::EpochUs => Value::Integer(micros),
+        }
+    }
+
+    /// The Parquet schema line for the column, it's always optional so that
+    /// all columns can be written the same way.
+    fn parquet_field(&self, column: &Column) -> String {
+        let physical_type = match column {
+            Column::Id | Column::MeasDuration | Column::Monotonic => "int64",
+            Column::Time => match self.time_format {
+                TimeFormat::Iso8601 => "int64",
+                TimeFormat::EpochS => "double",
+                TimeFormat::EpochMs | TimeFormat::EpochUs => "int64",
+            },
+            Column::ClockSynced => "boolean",
+            Column::BootId | Column::NodeId => "binary",
+            Column::Quantity(_) => "float",
+        };
+
+        let logical_type = match (column, self.time_format) {
+            (Column::Time, TimeFormat::Iso8601) => " (TIMESTAMP(MICROS,true))",
+            (Column::BootId | Column::NodeId, _) => " (STRING)",
+            _ => "",
+        };
+
+        format!("optional {physical_type} {}{logical_type};", column.name())
+    }
+}
+
+trait RowWriter {
+    fn write_row(&mut self, row: Vec<Value>) -> Result<()>;
+
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+struct CsvWriter<W: Write>(csv::Writer<W>);
+
+impl<W: Write> RowWriter for CsvWriter<W> {
+    fn write_row(&mut self, row: Vec<Value>) -> Result<()> {
+        self.0.write_record(row.iter().map(Value::to_csv))?;
+
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.0.flush()?;
+
+        Ok(())
+    }
+}
+
+struct JsonLinesWriter<W: Write> {
+    out: W,
+    columns: Vec<Column>,
+}
+
+impl<W: Write> RowWriter for JsonLinesWriter<W> {
+    fn write_row(&mut self, row: Vec<Value>) -> Result<()> {
+        // Built by hand rather than through a map to keep the column order.
+        write!(self.out, "{{")?;
+        for (i, (column, value)) in self.columns.iter().zip(&row).enumerate() {
+            if i > 0 {
+                write!(self.out, ",")?;
+            }
+            write!(self.out, "\"{}\":{}", column.name(), value.to_json())?;
+        }
+        writeln!(self.out, "}}")?;
+
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.out.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Buffers a row group worth of rows at a time, so memory use stays bounded
+/// however many rows are exported.
+struct ParquetWriter<W: Write + Send> {
+    writer: SerializedFileWriter<W>,
+    rows: Vec<Vec<Value>>,
+}
+
+impl<W: Write + Send> ParquetWriter<W> {
+    const ROW_GROUP_SIZE: usize = 10_000;
+
+    fn new(out: W, args: &ExportArgs) -> Result<Self> {
+        let fields = args
+            .columns
+            .iter()
+            .map(|column| args.parquet_field(column))
+            .collect::<Vec<_>>();
+        let schema =
+            parse_message_type(&format!("message measurement {{ {} }}", fields.join(" ")))?;
+
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+
+        Ok(ParquetWriter {
+            writer: SerializedFileWriter::new(out, Arc::new(schema), Arc::new(properties))?,
+            rows: Vec::with_capacity(Self::ROW_GROUP_SIZE),
+        })
+    }
+
+    fn write_row_group(&mut self) -> Result<()> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+
+        let mut row_group = self.writer.next_row_group()?;
+        let mut column_index = 0;
+
+        while let Some(mut column_writer) = row_group.next_column()? {
+            let cells = self.rows.iter().map(|row| &row[column_index]);
+            let def_levels = cells
+                .clone()
+                .map(|cell| i16::from(!matches!(cell, Value::Null)))
+                .collect::<Vec<_>>();
+
+            // The writer's type comes from the schema, `parquet_field()`.
+            match column_writer.untyped() {
+                ColumnWriter::Int64ColumnWriter(writer) => {
+                    let values = cells
+                        .filter_map(|cell| match cell {
+                            Value::Integer(value) => Some(*value),
+                            Value::Timestamp(value) => Some(value.timestamp_micros()),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>();
+                    writer.write_batch(&values, Some(&def_levels), None)?;
+                }
+                ColumnWriter::DoubleColumnWriter(writer) => {
+                    let values = cells
+                        .filter_map(|cell| match cell {
+                            Value::Double(value) => Some(*value),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>();
+                    writer.write_batch(&values, Some(&def_levels), None)?;
+                }
+                ColumnWriter::FloatColumnWriter(writer) => {
+                    let values = cells
+                        .filter_map(|cell| match cell {
+                            Value::Float(value) => Some(*value),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>();
+                    writer.write_batch(&values, Some(&def_levels), None)?;
+                }
+                ColumnWriter::BoolColumnWriter(writer) => {
+                    let values = cells
+                        .filter_map(|cell| match cell {
+                            Value::Boolean(value) => Some(*value),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>();
+                    writer.write_batch(&values, Some(&def_levels), None)?;
+                }
+                ColumnWriter::ByteArrayColumnWriter(writer) => {
+                    let values = cells
+                        .filter_map(|cell| match cell {
+                            Value::Text(value) => Some(ByteArray::from(value.as_str())),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>();
+                    writer.write_batch(&values, Some(&def_levels), None)?;
+                }
+                _ => unreachable!("Unexpected Parquet column type"),
+            }
+
+            column_writer.close()?;
+            column_index += 1;
+        }
+
+        row_group.close()?;
+        self.rows.clear();
+
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> RowWriter for ParquetWriter<W> {
+    fn write_row(&mut self, row: Vec<Value>) -> Result<()> {
+        self.rows.push(row);
+
+        if self.rows.len() >= Self::ROW_GROUP_SIZE {
+            self.write_row_group()?;
+        }
+
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.write_row_group()?;
+        self.writer.close()?;
+
+        Ok(())
+    }
+}
+
+pub fn export(mut args: ExportArgs, default_db_url: impl FnOnce() -> String) -> Result<()> {
+    let db_url = args.database.clone().unwrap_or_else(default_db_url);
+    let mut conn = db::establish_connection(&db_url)?;
+
+    let out: Box<dyn Write + Send> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+
+    let count = write_measurements(&mut args, &mut conn, out)?;
+    log::info!("Exported {count} measurements.");
+
+    Ok(())
+}
+
+/// Streams the measurements in the requested range to `out`, one row at a
+/// time, returning how many there were. They're read a page at a time,
+/// Postgres would otherwise send the whole range in one go.
+fn write_measurements(
+    args: &mut ExportArgs,
+    conn: &mut DbConnection,
+    out: Box<dyn Write + Send>,
+) -> Result<usize> {
+    let quantities = db::readings::stored_quantities(conn)?;
+    if args.columns.is_empty() {
+        args.columns = [Column::Id, Column::Time]
+            .into_iter()
+            .chain(quantities.into_iter().map(Column::Quantity))
+            .chain([Column::MeasDuration])
+            .collect();
+    } else {
+        for column in &args.columns {
+            if let Column::Quantity(quantity) = column {
+                if !quantities.contains(quantity) {
+                    log::warn!("There are no readings of {quantity}, its column will be empty.");
+                }
+            }
+        }
+    }
+
+    let mut writer: Box<dyn RowWriter> = match args.format {
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            writer.write_record(args.columns.iter().map(|column| column.name()))?;
+            Box::new(CsvWriter(writer))
+        }
+        Format::Jsonl => Box::new(JsonLinesWriter {
+            out,
+            columns: args.columns.clone(),
+        }),
+        Format::Parquet => Box::new(ParquetWriter::new(out, args)?),
+    };
+
+    // Where the last page ended, by time and then ID, the order they're
+    // exported in.
+    let mut after: Option<(DateTimeUtc, i32)> = None;
+    let mut count = 0;
+
+    loop {
+        let mut query = readouts::table
+            .select(Readout::as_select())
+            .order((readouts::meas_time.asc(), readouts::id.asc()))
+            .limit(EXPORT_PAGE_SIZE)
+            .into_boxed();
+
+        if let Some(from) = args.from {
+            query = query.filter(readouts::meas_time.ge(DateTimeUtc::from(from)));
+        }
+
+        if let Some(to) = args.to {
+            query = query.filter(readouts::meas_time.lt(DateTimeUtc::from(to)));
+        }
+
+        if let Some(node) = &args.node {
+            query = query.filter(readouts::node_id.eq(node.clone()));
+        }
+
+        if let Some((time, id)) = after.take() {
+            query = query.filter(
+                readouts::meas_time
+                    .gt(time.clone())
+                    .or(readouts::meas_time.eq(time).and(readouts::id.gt(id))),
+            );
+        }
+
+        let page = query.load::<Readout>(conn)?;
+        let page_len = page.len() as i64;
+
+        for measurement in db::readings::with_readings(conn, page)? {
+            let row = args
+                .columns
+                .iter()
+                .map(|column| args.convert(column, &measurement))
+                .collect();
+
+            writer.write_row(row)?;
+            count += 1;
+
+            after = Some((measurement.readout.meas_time, measurement.readout.id));
+        }
+
+        if page_len < EXPORT_PAGE_SIZE {
+            break;
+        }
+    }
+
+    writer.finish()?;
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{readings, test_connection, InsertableMeasurement};
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::{Field, RowAccessor};
+
+    use std::fs;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn temp_path(name: &str) -> PathBuf {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let dir = std::env::temp_dir().join(format!(
+            "rpi_client_temp-export-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+
+        dir.join(name)
+    }
+
+    fn args(format: Format) -> ExportArgs {
+        ExportArgs {
+            from: None,
+            to: None,
+            node: None,
+            format,
+            columns: Vec::new(),
+            time_format: TimeFormat::Iso8601,
+            temperature_unit: TemperatureUnit::Celsius,
+            pressure_unit: PressureUnit::Hpa,
+            light_level_unit: LightLevelUnit::Ratio,
+            output: None,
+            database: None,
+        }
+    }
+
+    /// Two measurements a minute apart, the second without a light level.
+    fn conn() -> DbConnection {
+        let mut conn = test_connection();
+        let measurements = [
+            InsertableMeasurement {
+                meas_time: DateTimeUtc::from_micros(1_800_000_000_000_000).unwrap(),
+                ..InsertableMeasurement::example()
+            },
+            InsertableMeasurement {
+                meas_time: DateTimeUtc::from_micros(1_800_000_060_000_000).unwrap(),
+                temperature: Some(22.5),
+                light_level: None,
+                meas_duration_us: Some(1500),
+                ..InsertableMeasurement::example()
+            },
+        ];
+        for measurement in &measurements {
+            readings::insert_measurement(&mut conn, measurement).unwrap();
+        }
+
+        conn
+    }
+
+    fn export_to(format: Format, path: &Path) -> usize {
+        let out = Box::new(File::create(path).unwrap());
+        write_measurements(&mut args(format), &mut conn(), out).unwrap()
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let path = temp_path("measurements.csv");
+        assert_eq!(export_to(Format::Csv, &path), 2);
+
+        let mut reader = csv::Reader::from_path(&path).unwrap();
+        assert_eq!(
+            reader.headers().unwrap(),
+            vec![
+                "id",
+                "time",
+                "temperature",
+                "pressure",
+                "light_level",
+                "meas_duration_us"
+            ]
+        );
+        let rows = reader
+            .records()
+            .map(|record| record.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            [
+                vec![
+                    "1",
+                    "2027-01-15T08:00:00.000000Z",
+                    "21.456",
+                    "1013.25",
+                    "0.0123",
+                    ""
+                ],
+                vec![
+                    "2",
+                    "2027-01-15T08:01:00.000000Z",
+                    "22.5",
+                    "1013.25",
+                    "",
+                    "1500"
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn parquet_round_trip() {
+        let path = temp_path("measurements.parquet");
+        assert_eq!(export_to(Format::Parquet, &path), 2);
+
+        let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+        let rows = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(rows.len(), 2);
+
+        assert_eq!(rows[0].get_long(0).unwrap(), 1);
+        assert_eq!(
+            rows[0].get_timestamp_micros(1).unwrap(),
+            1_800_000_000_000_000
+        );
+        assert_eq!(rows[0].get_float(2).unwrap(), 21.456);
+        assert_eq!(rows[0].get_float(3).unwrap(), 1013.25);
+        assert_eq!(rows[0].get_float(4).unwrap(), 0.0123);
+        assert_eq!(rows[0].get_column_iter().nth(5).unwrap().1, &Field::Null);
+
+        assert_eq!(
+            rows[1].get_timestamp_micros(1).unwrap(),
+            1_800_000_060_000_000
+        );
+        assert_eq!(rows[1].get_float(2).unwrap(), 22.5);
+        assert_eq!(rows[1].get_column_iter().nth(4).unwrap().1, &Field::Null);
+        assert_eq!(rows[1].get_long(5).unwrap(), 1500);
+    }
+}
@@ -0,0 +1,299 @@
+use anyhow::{anyhow, Result};
+use chrono::TimeDelta;
+use tokio::task;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::{optional_var, var_or};
+use crate::db::forecasts::Forecast;
+use crate::db::{self, DateTimeUtc, DbConnection, LOCAL_NODE_ID};
+
+/// The forecasts in Zambretti's table, by number. 1 to 9 are for falling
+/// pressure, 10 to 19 for steady and 20 to 32 for rising.
+const ZAMBRETTI_FORECASTS: [&str; 32] = [
+    "Settled fine",
+    "Fine weather",
+    "Fine, becoming less settled",
+    "Fairly fine, showery later",
+    "Showery, becoming more unsettled",
+    "Unsettled, rain later",
+    "Rain at times, worse later",
+    "Rain at times, becoming very unsettled",
+    "Very unsettled, rain",
+    "Settled fine",
+    "Fine weather",
+    "Fine, possibly showers",
+    "Fairly fine, showers likely",
+    "Showery, bright intervals",
+    "Changeable, some rain",
+    "Unsettled, rain at times",
+    "Rain at frequent intervals",
+    "Very unsettled, rain",
+    "Stormy, much rain",
+    "Settled fine",
+    "Fine weather",
+    "Becoming fine",
+    "Fairly fine, improving",
+    "Fairly fine, possibly showers early",
+    "Showery early, improving",
+    "Changeable, improving",
+    "Rather unsettled, clearing later",
+    "Unsettled, probably improving",
+    "Unsettled, short fine intervals",
+    "Very unsettled, finer at times",
+    "Stormy, possibly improving",
+    "Stormy, much rain",
+];
+
+/// How far back the pressure tendency goes.
+const TENDENCY_PERIOD: TimeDelta = TimeDelta::hours(3);
+/// How much older than wanted a reading may be and still be used.
+const MAX_READING_AGE: TimeDelta = TimeDelta::hours(1);
+/// Used for the reduction to sea level when there's no temperature reading.
+const STANDARD_TEMPERATURE: f32 = 15.0;
+
+#[derive(Debug, Clone)]
+pub struct ForecastConfig {
+    altitude_m: f32,
+    interval: Duration,
+}
+
+impl ForecastConfig {
+    const ALTITUDE_ENV_VAR: &'static str = "STATION_ALTITUDE_M";
+    const INTERVAL_ENV_VAR: &'static str = "FORECAST_INTERVAL_SECS";
+
+    /// Returns `None` if the altitude isn't set, without it the pressure
+    /// can't be reduced to sea level, which the forecast is based on.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(altitude_m) = optional_var(Self::ALTITUDE_ENV_VAR)? else {
+            return Ok(None);
+        };
+
+        let interval = var_or(Self::INTERVAL_ENV_VAR, 1800)?;
+        if interval == 0 {
+            return Err(anyhow!("{} has to be at least 1.", Self::INTERVAL_ENV_VAR));
+        }
+
+        Ok(Some(Self {
+            altitude_m,
+            interval: Duration::from_secs(interval),
+        }))
+    }
+
+    /// How often the forecast is updated.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+}
+
+/// The pressure tendency over three hours, in the categories of the UK Met
+/// Office's shipping forecast.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tendency {
+    FallingVeryRapidly,
+    FallingQuickly,
+    Falling,
+    FallingSlowly,
+    Steady,
+    RisingSlowly,
+    Rising,
+    RisingQuickly,
+    RisingVeryRapidly,
+}
+
+impl Tendency {
+    fn from_change(change_hpa: f32) -> Tendency {
+        match change_hpa {
+            change if change < -6.0 => Tendency::FallingVeryRapidly,
+            change if change < -3.5 => Tendency::FallingQuickly,
+            change if change < -1.5 => Tendency::Falling,
+            change if change < -0.1 => Tendency::FallingSlowly,
+            change if change <= 0.1 => Tendency::Steady,
+            change if change <= 1.5 => Tendency::RisingSlowly,
+            change if change <= 3.5 => Tendency::Rising,
+            change if change <= 6.0 => Tendency::RisingQuickly,
+            _ => Tendency::RisingVeryRapidly,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Tendency::FallingVeryRapidly => "falling very rapidly",
+            Tendency::FallingQuickly => "falling quickly",
+            Tendency::Falling => "falling",
+            Tendency::FallingSlowly => "falling slowly",
+            Tendency::Steady => "steady",
+            Tendency::RisingSlowly => "rising slowly",
+            Tendency::Rising => "rising",
+            Tendency::RisingQuickly => "rising quickly",
+            Tendency::RisingVeryRapidly => "rising very rapidly",
+        }
+    }
+
+    /// The number in Zambretti's table for the sea level pressure. Slow
+    /// changes count as steady. The seasonal and wind corrections of the
+    /// original aren't applied, there's no wind direction to go by.
+    fn zambretti(self, sea_level_hpa: f32) -> i32 {
+        let pressure = sea_level_hpa.clamp(947.0, 1050.0);
+
+        let (z, first, last) = match self {
+            Tendency::FallingVeryRapidly | Tendency::FallingQuickly | Tendency::Falling => {
+                (127.0 - 0.12 * pressure, 1, 9)
+            }
+            Tendency::FallingSlowly | Tendency::Steady | Tendency::RisingSlowly => {
+                (144.0 - 0.13 * pressure, 10, 19)
+            }
+            Tendency::Rising | Tendency::RisingQuickly | Tendency::RisingVeryRapidly => {
+                (185.0 - 0.16 * pressure, 20, 32)
+            }
+        };
+
+        (z.round() as i32).clamp(first, last)
+    }
+}
+
+/// The factor that reduces the pressure at the altitude to sea level, by the
+/// barometric formula with the standard lapse rate.
+fn sea_level_factor(altitude_m: f32, temperature: f32) -> f32 {
+    let lapse = 0.0065 * altitude_m;
+
+    (1.0 - lapse / (temperature + lapse + 273.15)).powf(-5.257)
+}
+
+/// Works out the forecast from this node's latest pressure reading and the
+/// one three hours before it. `None` if there aren't recent enough readings
+/// for that.
+fn forecast(conn: &mut DbConnection, config: &ForecastConfig) -> Result<Option<Forecast>> {
+    let reading = |conn: &mut DbConnection, quantity, time: &DateTimeUtc| {
+        db::query::reading_before(conn, LOCAL_NODE_ID, quantity, time).map(|reading| {
+            reading
+                .filter(|(reading_time, _)| **time - **reading_time <= MAX_READING_AGE)
+                .map(|(_, value)| value)
+        })
+    };
+
+    let Some((time, pressure)) =
+        db::query::reading_before(conn, LOCAL_NODE_ID, "pressure", &DateTimeUtc::now())?
+    else {
+        return Ok(None);
+    };
+    if *DateTimeUtc::now() - *time > MAX_READING_AGE {
+        return Ok(None);
+    }
+
+    let earlier = DateTimeUtc::from(*time - TENDENCY_PERIOD);
+    let Some(earlier_pressure) = reading(conn, "pressure", &earlier)? else {
+        return Ok(None);
+    };
+
+    let temperature = reading(conn, "temperature", &time)?.unwrap_or(STANDARD_TEMPERATURE);
+    let factor = sea_level_factor(config.altitude_m, temperature);
+
+    let sea_level_pressure = pressure * factor;
+    let pressure_change = (pressure - earlier_pressure) * factor;
+    let tendency = Tendency::from_change(pressure_change / 100.0);
+    let zambretti = tendency.zambretti(sea_level_pressure / 100.0);
+
+    Ok(Some(Forecast {
+        time,
+        sea_level_pressure,
+        pressure_change,
+        tendency: tendency.as_str().to_owned(),
+        zambretti,
+        forecast: ZAMBRETTI_FORECASTS[zambretti as usize - 1].to_owned(),
+    }))
+}
+
+/// Works out the current forecast and stores it.
+pub async fn update(
+    config: &ForecastConfig,
+    db_conn: &Arc<Mutex<DbConnection>>,
+) -> Result<Option<Forecast>> {
+    let config = config.clone();
+    let db_conn = db_conn.clone();
+
+    task::spawn_blocking(move || {
+        let mut conn = db_conn.lock().unwrap();
+
+        let forecast = forecast(&mut conn, &config)?;
+        if let Some(forecast) = &forecast {
+            db::forecasts::insert_forecast(&mut conn, forecast)?;
+        }
+
+        Ok(forecast)
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tendency_bands() {
+        let cases = [
+            (-6.1, Tendency::FallingVeryRapidly),
+            (-6.0, Tendency::FallingQuickly),
+            (-3.6, Tendency::FallingQuickly),
+            (-3.5, Tendency::Falling),
+            (-1.6, Tendency::Falling),
+            (-1.5, Tendency::FallingSlowly),
+            (-0.2, Tendency::FallingSlowly),
+            (-0.1, Tendency::Steady),
+            (0.0, Tendency::Steady),
+            (0.1, Tendency::Steady),
+            (0.2, Tendency::RisingSlowly),
+            (1.5, Tendency::RisingSlowly),
+            (1.6, Tendency::Rising),
+            (3.5, Tendency::Rising),
+            (3.6, Tendency::RisingQuickly),
+            (6.0, Tendency::RisingQuickly),
+            (6.1, Tendency::RisingVeryRapidly),
+        ];
+
+        for (change, tendency) in cases {
+            assert_eq!(Tendency::from_change(change), tendency, "{change} hPa");
+        }
+    }
+
+    #[test]
+    fn zambretti_numbers() {
+        let cases = [
+            (Tendency::Falling, 1050.0, 1),
+            (Tendency::Falling, 1020.0, 5),
+            (Tendency::Falling, 947.0, 9),
+            (Tendency::Steady, 1050.0, 10),
+            (Tendency::Steady, 1000.0, 14),
+            (Tendency::Steady, 947.0, 19),
+            (Tendency::Rising, 1050.0, 20),
+            (Tendency::Rising, 1000.0, 25),
+            (Tendency::Rising, 947.0, 32),
+        ];
+
+        for (tendency, pressure, zambretti) in cases {
+            assert_eq!(
+                tendency.zambretti(pressure),
+                zambretti,
+                "{tendency:?} at {pressure} hPa"
+            );
+        }
+    }
+
+    #[test]
+    fn zambretti_clamps_the_pressure() {
+        for tendency in [Tendency::Falling, Tendency::Steady, Tendency::Rising] {
+            assert_eq!(tendency.zambretti(900.0), tendency.zambretti(947.0));
+            assert_eq!(tendency.zambretti(1100.0), tendency.zambretti(1050.0));
+        }
+    }
+
+    #[test]
+    fn reduces_to_sea_level() {
+        assert_eq!(sea_level_factor(0.0, 15.0), 1.0);
+
+        // The standard atmosphere's pressure at 100 m.
+        let sea_level_hpa = 1001.29 * sea_level_factor(100.0, 15.0);
+        assert!((sea_level_hpa - 1013.25).abs() < 0.1, "{sea_level_hpa}");
+    }
+}
@@ -0,0 +1,463 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use clap::{Args, ValueEnum};
+use diesel::prelude::*;
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+use crate::db::schema::readouts;
+use crate::db::{self, DateTimeUtc, DbConnection, InsertableMeasurement, LOCAL_NODE_ID};
+use crate::export::{Column, LightLevelUnit, PressureUnit, TemperatureUnit, TimeFormat};
+
+// What the BMP280 and TCS3472 can actually measure, anything outside of it
+// is garbage.
+const TEMPERATURE_RANGE: RangeInclusive<f32> = -40.0..=85.0;
+const PRESSURE_RANGE: RangeInclusive<f32> = 30_000.0..=110_000.0;
+const HUMIDITY_RANGE: RangeInclusive<f32> = 0.0..=100.0;
+const LIGHT_LEVEL_RANGE: RangeInclusive<f32> = 0.0..=1.0;
+
+/// How many rejected rows are logged individually before going quiet.
+const MAX_LOGGED_ERRORS: usize = 20;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Format {
+    Csv,
+    Jsonl,
+}
+
+#[derive(Debug, Args)]
+pub struct ImportArgs {
+    /// File to import, `-` for stdin.
+    input: PathBuf,
+    /// Input format, guessed from the file extension if not given.
+    #[arg(long, value_enum)]
+    format: Option<Format>,
+    /// Renames an input column to one of the measurement columns, e.g.
+    /// `--map temp=temperature`. Can be given multiple times.
+    #[arg(long, value_parser = parse_mapping)]
+    map: Vec<(String, Column)>,
+    #[arg(long, value_enum, default_value_t = TimeFormat::Iso8601)]
+    time_format: TimeFormat,
+    #[arg(long, value_enum, default_value_t = TemperatureUnit::Celsius)]
+    temperature_unit: TemperatureUnit,
+    #[arg(long, value_enum, default_value_t = PressureUnit::Pa)]
+    pressure_unit: PressureUnit,
+    #[arg(long, value_enum, default_value_t = LightLevelUnit::Ratio)]
+    light_level_unit: LightLevelUnit,
+    /// Rows inserted per transaction.
+    #[arg(long, default_value_t = 500)]
+    batch_size: usize,
+    /// SQLite database or postgres:// URL to import into, DATABASE_URL if not
+    /// given.
+    #[arg(long)]
+    database: Option<String>,
+}
+
+fn parse_mapping(mapping: &str) -> Result<(String, Column)> {
+    let (from, to) = mapping
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Expected input_column=column, got {mapping:?}"))?;
+    let to = to.parse().map_err(|e: String| anyhow!(e))?;
+
+    Ok((from.to_owned(), to))
+}
+
+#[derive(Debug, Default)]
+struct Summary {
+    read: usize,
+    inserted: usize,
+    duplicates: usize,
+    invalid: usize,
+}
+
+impl ImportArgs {
+    fn column(&self, name: &str) -> Option<Column> {
+        if let Some((_, column)) = self.map.iter().find(|(from, _)| from == name) {
+            return Some(column.clone());
+        }
+
+        match name {
+            // Also take the raw DB column name.
+            "meas_time" => Some(Column::Time),
+            name => name.parse().ok(),
+        }
+    }
+
+    fn format(&self) -> Result<Format> {
+        if let Some(format) = self.format {
+            return Ok(format);
+        }
+
+        match self.input.extension().and_then(|ext| ext.to_str()) {
+            Some("csv") => Ok(Format::Csv),
+            Some("jsonl" | "ndjson") => Ok(Format::Jsonl),
+            _ => Err(anyhow!(
+                "Can't tell the format of {}, use --format.",
+                self.input.display()
+            )),
+        }
+    }
+
+    fn parse_time(&self, time: &str) -> Result<DateTimeUtc> {
+        let date_time = match self.time_format {
+            TimeFormat::Iso8601 => DateTime::parse_from_rfc3339(time)?.with_timezone(&Utc),
+            TimeFormat::EpochS => {
+                let micros = (time.parse::<f64>()? * 1e6).round() as i64;
+                DateTime::from_timestamp_micros(micros).ok_or_else(|| anyhow!("Out of range"))?
+            }
+            TimeFormat::EpochMs => DateTime::from_timestamp_millis(time.parse()?)
+                .ok_or_else(|| anyhow!("Out of range"))?,
+            TimeFormat::EpochUs => DateTime::from_timestamp_micros(time.parse()?)
+                .ok_or_else(|| anyhow!("Out of range"))?,
+        };
+
+        Ok(DateTimeUtc::from(date_time))
+    }
+
+    /// Builds a measurement from the named fields of one input row, converting
+    /// everything to the units stored in the DB.
+    fn parse_row(&self, fields: Vec<(String, Option<String>)>) -> Result<InsertableMeasurement> {
+        let mut meas_time = None;
+        let mut temperature = None;
+        let mut pressure = None;
+        let mut humidity = None;
+        let mut light_level = None;
+        let mut meas_duration_us = None;
+
+        for (name, value) in fields {
+            let name = name.as_str();
+            let Some(column) = self.column(name) else {
+                continue;
+            };
+            let Some(value) = value.filter(|value| !value.is_empty()) else {
+                continue;
+            };
+
+            let quantity = |range: &RangeInclusive<f32>, convert: &dyn Fn(f32) -> f32| {
+                let parsed = convert(value.parse::<f32>().with_context(|| name.to_owned())?);
+                if range.contains(&parsed) {
+                    Ok(Some(parsed))
+                } else {
+                    Err(anyhow!("{name} {parsed} is outside of {range:?}"))
+                }
+            };
+
+            match column {
+                // Rows get new IDs when they're inserted, there's no fixing
+                // up the clock of another boot, and everything imported is
+                // this node's.
+                Column::Id
+                | Column::ClockSynced
+                | Column::BootId
+                | Column::Monotonic
+                | Column::NodeId => {}
+                Column::Time => {
+                    meas_time = Some(self.parse_time(&value).with_context(|| name.to_owned())?)
+                }
+                Column::Quantity(quantity_name) => match quantity_name.as_str() {
+                    "temperature" => {
+                        temperature =
+                            quantity(&TEMPERATURE_RANGE, &|t| self.temperature_unit.to_celsius(t))?
+                    }
+                    "humidity" => humidity = quantity(&HUMIDITY_RANGE, &|h| h)?,
+                    "pressure" => {
+                        pressure = quantity(&PRESSURE_RANGE, &|p| self.pressure_unit.to_pascals(p))?
+                    }
+                    "light_level" => {
+                        light_level =
+                            quantity(&LIGHT_LEVEL_RANGE, &|l| self.light_level_unit.to_ratio(l))?
+                    }
+                    // Not one that the measurements here have.
+                    _ => {}
+                },
+                Column::MeasDuration => {
+                    let duration = value.parse::<i64>().with_context(|| name.to_owned())?;
+                    if duration < 0 {
+                        return Err(anyhow!("{name} {duration} is negative"));
+                    }
+                    meas_duration_us = Some(duration);
+                }
+            }
+        }
+
+        Ok(InsertableMeasurement {
+            meas_time: meas_time.ok_or_else(|| anyhow!("No time"))?,
+            temperature,
+            pressure,
+            humidity,
+            light_level,
+            meas_duration_us,
+            // Whatever clock the old data came from, we have to take its word.
+            clock_synced: true,
+            boot_id: None,
+            monotonic_us: None,
+            node_id: LOCAL_NODE_ID.to_owned(),
+            uid: None,
+            station_id: None,
+            temperature_sensor_id: None,
+            humidity_sensor_id: None,
+            pressure_sensor_id: None,
+            light_level_sensor_id: None,
+        })
+    }
+}
+
+/// Inserts the batch in one transaction, skipping measurements whose time is
+/// already in the DB or earlier in the batch.
+fn insert_batch(
+    conn: &mut DbConnection,
+    batch: &mut Vec<InsertableMeasurement>,
+    summary: &mut Summary,
+) -> QueryResult<()> {
+    let batch_len = batch.len();
+    let inserted = conn.transaction(|conn| {
+        let times = batch
+            .iter()
+            .map(|measurement| measurement.meas_time.clone())
+            .collect::<Vec<_>>();
+
+        // Compared as microseconds, the precision that's stored.
+        let mut seen = readouts::table
+            .select(readouts::meas_time)
+            .filter(readouts::node_id.eq(LOCAL_NODE_ID))
+            .filter(readouts::meas_time.eq_any(times))
+            .load::<DateTimeUtc>(conn)?
+            .into_iter()
+            .map(|time| time.timestamp_micros())
+            .collect::<HashSet<_>>();
+
+        let new = batch
+            .drain(..)
+            .filter(|measurement| seen.insert(measurement.meas_time.timestamp_micros()))
+            .collect::<Vec<_>>();
+
+        let mut inserted = 0;
+        for measurement in &new {
+            if db::readings::insert_measurement(conn, measurement)? {
+                inserted += 1;
+            }
+        }
+        db::rollup::update_rollups(conn, &new)?;
+
+        QueryResult::Ok(inserted)
+    })?;
+
+    summary.inserted += inserted;
+    summary.duplicates += batch_len - inserted;
+
+    Ok(())
+}
+
+fn open_input(path: &Path) -> Result<Box<dyn BufRead>> {
+    if path == Path::new("-") {
+        Ok(Box::new(BufReader::new(io::stdin())))
+    } else {
+        let file = File::open(path).with_context(|| format!("{}", path.display()))?;
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+/// The line number and the named fields of one input row, or why they
+/// couldn't be read.
+type Row = (u64, Result<Vec<(String, Option<String>)>>);
+
+/// Reads the rows one at a time. Only I/O errors are returned as errors, a
+/// malformed row is just an invalid one.
+fn read_rows(
+    format: Format,
+    input: Box<dyn BufRead>,
+) -> Result<Box<dyn Iterator<Item = Result<Row>>>> {
+    match format {
+        Format::Csv => {
+            let mut reader = csv::Reader::from_reader(input);
+            let headers = reader.headers()?.clone();
+
+            Ok(Box::new(reader.into_records().map(move |record| {
+                let record = match record {
+                    Ok(record) => record,
+                    Err(e) if e.is_io_error() => return Err(e.into()),
+                    Err(e) => {
+                        let line = e.position().map_or(0, |position| position.line());
+                        return Ok((line, Err(e.into())));
+                    }
+                };
+
+                let line = record.position().map_or(0, |position| position.line());
+                let fields = headers
+                    .iter()
+                    .zip(record.iter())
+                    .map(|(name, value)| (name.to_owned(), Some(value.to_owned())))
+                    .collect();
+
+                Ok((line, Ok(fields)))
+            })))
+        }
+        Format::Jsonl => Ok(Box::new(
+            input
+                .lines()
+                .zip(1..)
+                .filter(|(line, _)| !matches!(line, Ok(line) if line.trim().is_empty()))
+                .map(|(line, line_number)| {
+                    let object = match serde_json::from_str::<serde_json::Map<_, _>>(&line?) {
+                        Ok(object) => object,
+                        Err(e) => return Ok((line_number, Err(e.into()))),
+                    };
+                    let fields = object
+                        .into_iter()
+                        .map(|(name, value)| {
+                            let value = match value {
+                                serde_json::Value::Null => None,
+                                serde_json::Value::String(value) => Some(value),
+                                value => Some(value.to_string()),
+                            };
+                            (name, value)
+                        })
+                        .collect();
+
+                    Ok((line_number, Ok(fields)))
+                }),
+        )),
+    }
+}
+
+/// Inserts the valid rows a batch at a time.
+fn import_rows(
+    args: &ImportArgs,
+    conn: &mut DbConnection,
+    rows: impl Iterator<Item = Result<Row>>,
+) -> Result<Summary> {
+    let mut summary = Summary::default();
+    let mut batch = Vec::with_capacity(args.batch_size);
+
+    for row in rows {
+        let (line, fields) = row?;
+        summary.read += 1;
+
+        match fields.and_then(|fields| args.parse_row(fields)) {
+            Ok(measurement) => batch.push(measurement),
+            Err(e) => {
+                summary.invalid += 1;
+                if summary.invalid <= MAX_LOGGED_ERRORS {
+                    log::warn!("Skipping line {line}: {e:#}");
+                }
+                continue;
+            }
+        }
+
+        if batch.len() >= args.batch_size {
+            insert_batch(conn, &mut batch, &mut summary)?;
+        }
+    }
+
+    if !batch.is_empty() {
+        insert_batch(conn, &mut batch, &mut summary)?;
+    }
+
+    if summary.invalid > MAX_LOGGED_ERRORS {
+        log::warn!(
+            "{} more invalid rows weren't logged.",
+            summary.invalid - MAX_LOGGED_ERRORS
+        );
+    }
+
+    Ok(summary)
+}
+
+pub fn import(args: ImportArgs, default_db_url: impl FnOnce() -> String) -> Result<()> {
+    if args.batch_size == 0 {
+        return Err(anyhow!("The batch size has to be at least 1."));
+    }
+
+    let db_url = args.database.clone().unwrap_or_else(default_db_url);
+    let mut conn = db::establish_connection(&db_url)?;
+
+    let rows = read_rows(args.format()?, open_input(&args.input)?)?;
+    let summary = import_rows(&args, &mut conn, rows)?;
+
+    // Printed rather than logged, it's the whole point of running this.
+    println!(
+        "Read {} rows: {} inserted, {} duplicates skipped, {} invalid.",
+        summary.read, summary.inserted, summary.duplicates, summary.invalid
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::query::measurements_in_range;
+    use crate::db::test_connection;
+
+    use std::io::Cursor;
+
+    fn args(batch_size: usize) -> ImportArgs {
+        ImportArgs {
+            input: PathBuf::from("measurements.csv"),
+            format: None,
+            map: vec![(
+                "temp".to_owned(),
+                Column::Quantity("temperature".to_owned()),
+            )],
+            time_format: TimeFormat::Iso8601,
+            temperature_unit: TemperatureUnit::Celsius,
+            pressure_unit: PressureUnit::Hpa,
+            light_level_unit: LightLevelUnit::Ratio,
+            batch_size,
+            database: None,
+        }
+    }
+
+    #[test]
+    fn imports_in_batches_skipping_duplicates_and_invalid_rows() {
+        let args = args(2);
+        let mut conn = test_connection();
+
+        let csv = "time,temp,pressure\n\
+                   2026-01-01T00:00:00Z,20.5,1010\n\
+                   2026-01-01T00:01:00Z,21,1011\n\
+                   2026-01-01T00:00:00Z,22,1012\n\
+                   2026-01-01T00:02:00Z,200,1010\n\
+                   2026-01-01T00:03:00Z,19.5,\n";
+        let rows = read_rows(Format::Csv, Box::new(Cursor::new(csv))).unwrap();
+        let summary = import_rows(&args, &mut conn, rows).unwrap();
+
+        assert_eq!(summary.read, 5);
+        assert_eq!(summary.inserted, 3);
+        assert_eq!(summary.duplicates, 1);
+        assert_eq!(summary.invalid, 1);
+
+        // The first of the duplicates is kept, in the DB's units.
+        let stored = measurements_in_range(&mut conn, LOCAL_NODE_ID, None, None, 10)
+            .unwrap()
+            .into_iter()
+            .map(|measurement| {
+                (
+                    measurement.readout.meas_time.to_rfc3339(),
+                    measurement.value("temperature"),
+                    measurement.value("pressure"),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            stored,
+            [
+                (
+                    "2026-01-01T00:00:00+00:00".to_owned(),
+                    Some(20.5),
+                    Some(101_000.0)
+                ),
+                (
+                    "2026-01-01T00:01:00+00:00".to_owned(),
+                    Some(21.0),
+                    Some(101_100.0)
+                ),
+                ("2026-01-01T00:03:00+00:00".to_owned(), Some(19.5), None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,471 @@
+use anyhow::{anyhow, Result};
+use reqwest::{Client, RequestBuilder, StatusCode};
+use tokio::sync::mpsc;
+use tokio::task::{self, JoinHandle};
+use tokio::{select, time};
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::{self, optional_var, var_or};
+use crate::db::InsertableMeasurement;
+
+mod queue;
+use queue::DiskQueue;
+
+#[derive(Debug, Clone)]
+enum ApiVersion {
+    V1 {
+        database: String,
+        retention_policy: Option<String>,
+        username: Option<String>,
+        password: Option<String>,
+    },
+    V2 {
+        org: String,
+        bucket: String,
+        token: String,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct InfluxConfig {
+    url: String,
+    api_version: ApiVersion,
+    measurement_name: String,
+    tags: Vec<(String, String)>,
+    batch_size: usize,
+    flush_interval: Duration,
+    queue_path: PathBuf,
+}
+
+impl InfluxConfig {
+    const URL_ENV_VAR: &'static str = "INFLUX_URL";
+    const API_VERSION_ENV_VAR: &'static str = "INFLUX_API_VERSION";
+    const DATABASE_ENV_VAR: &'static str = "INFLUX_DATABASE";
+    const RETENTION_POLICY_ENV_VAR: &'static str = "INFLUX_RETENTION_POLICY";
+    const USERNAME_ENV_VAR: &'static str = "INFLUX_USERNAME";
+    const PASSWORD_ENV_VAR: &'static str = "INFLUX_PASSWORD";
+    const ORG_ENV_VAR: &'static str = "INFLUX_ORG";
+    const BUCKET_ENV_VAR: &'static str = "INFLUX_BUCKET";
+    const TOKEN_ENV_VAR: &'static str = "INFLUX_TOKEN";
+    const MEASUREMENT_ENV_VAR: &'static str = "INFLUX_MEASUREMENT";
+    const TAGS_ENV_VAR: &'static str = "INFLUX_TAGS";
+    const BATCH_SIZE_ENV_VAR: &'static str = "INFLUX_BATCH_SIZE";
+    const FLUSH_INTERVAL_ENV_VAR: &'static str = "INFLUX_FLUSH_INTERVAL_SECS";
+    const QUEUE_PATH_ENV_VAR: &'static str = "INFLUX_QUEUE_PATH";
+
+    /// Returns `None` if InfluxDB output isn't configured.
+    pub fn from_env() -> Result<Option<Self>> {
+        let url: String = match optional_var(Self::URL_ENV_VAR)? {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+
+        let required = |name: &str| -> Result<String> {
+            optional_var(name)?.ok_or_else(|| anyhow!("{name} has to be set to use InfluxDB."))
+        };
+
+        let api_version = match var_or(Self::API_VERSION_ENV_VAR, 2u8)? {
+            1 => ApiVersion::V1 {
+                database: required(Self::DATABASE_ENV_VAR)?,
+                retention_policy: optional_var(Self::RETENTION_POLICY_ENV_VAR)?,
+                username: optional_var(Self::USERNAME_ENV_VAR)?,
+                password: optional_var(Self::PASSWORD_ENV_VAR)?,
+            },
+            2 => ApiVersion::V2 {
+                org: required(Self::ORG_ENV_VAR)?,
+                bucket: required(Self::BUCKET_ENV_VAR)?,
+                token: required(Self::TOKEN_ENV_VAR)?,
+            },
+            version => return Err(anyhow!("Unsupported InfluxDB API version {version}.")),
+        };
+
+        // The node tag is always there, extra ones come as "key=value,key=value".
+        let mut tags = vec![("node".to_owned(), config::hostname()?)];
+        if let Some(extra_tags) = optional_var::<String>(Self::TAGS_ENV_VAR)? {
+            for tag in extra_tags.split(',').filter(|tag| !tag.is_empty()) {
+                let (key, value) = tag
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("Invalid InfluxDB tag {tag:?}, expected key=value."))?;
+                tags.push((key.trim().to_owned(), value.trim().to_owned()));
+            }
+        }
+
+        Ok(Some(Self {
+            url: url.trim_end_matches('/').to_owned(),
+            api_version,
+            measurement_name: var_or(Self::MEASUREMENT_ENV_VAR, "environment".to_owned())?,
+            tags,
+            batch_size: var_or(Self::BATCH_SIZE_ENV_VAR, 10)?,
+            flush_interval: Duration::from_secs(var_or(Self::FLUSH_INTERVAL_ENV_VAR, 60)?),
+            queue_path: var_or(Self::QUEUE_PATH_ENV_VAR, PathBuf::from("influx_queue.lp"))?,
+        }))
+    }
+
+    fn write_request(&self, client: &Client) -> RequestBuilder {
+        match &self.api_version {
+            ApiVersion::V1 {
+                database,
+                retention_policy,
+                username,
+                password,
+            } => {
+                let mut query = vec![("db", database.as_str()), ("precision", "u")];
+                if let Some(retention_policy) = retention_policy {
+                    query.push(("rp", retention_policy));
+                }
+
+                let mut request = client.post(format!("{}/write", self.url)).query(&query);
+                if let Some(username) = username {
+                    request = request.basic_auth(username, password.as_ref());
+                }
+
+                request
+            }
+            ApiVersion::V2 { org, bucket, token } => client
+                .post(format!("{}/api/v2/write", self.url))
+                .query(&[
+                    ("org", org.as_str()),
+                    ("bucket", bucket.as_str()),
+                    ("precision", "us"),
+                ])
+                .header("Authorization", format!("Token {token}")),
+        }
+    }
+
+    /// Formats the measurement as a single line of line protocol, `None` if
+    /// there's nothing to write. NaN and infinite values are left out, there's
+    /// no way to write them and InfluxDB would reject the whole line.
+    fn line_protocol(&self, measurement: &InsertableMeasurement) -> Option<String> {
+        let fields = [
+            ("temperature", measurement.temperature),
+            ("pressure", measurement.pressure),
+            ("humidity", measurement.humidity),
+            ("light_level", measurement.light_level),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| {
+            value
+                .filter(|value| value.is_finite())
+                .map(|value| format!("{name}={value}"))
+        })
+        .collect::<Vec<_>>();
+
+        if fields.is_empty() {
+            return None;
+        }
+
+        let mut line = escape(&self.measurement_name, &[',', ' ']);
+        for (key, value) in &self.tags {
+            line.push(',');
+            line.push_str(&escape(key, &[',', '=', ' ']));
+            line.push('=');
+            line.push_str(&escape(value, &[',', '=', ' ']));
+        }
+
+        Some(format!(
+            "{line} {} {}",
+            fields.join(","),
+            measurement.meas_time.timestamp_micros()
+        ))
+    }
+}
+
+fn escape(s: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        if c == '\\' || special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// What InfluxDB made of a write request.
+enum Written {
+    Accepted,
+    /// With the error InfluxDB gave.
+    Rejected(String),
+}
+
+/// Runs blocking queue I/O off the async task.
+async fn with_queue<T, F>(queue: &Arc<Mutex<DiskQueue>>, f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut DiskQueue) -> Result<T> + Send + 'static,
+{
+    let queue = queue.clone();
+    task::spawn_blocking(move || f(&mut queue.lock().unwrap())).await?
+}
+
+pub struct InfluxWriter {
+    config: InfluxConfig,
+    line_tx: mpsc::Sender<String>,
+    task: JoinHandle<()>,
+}
+
+impl InfluxWriter {
+    /// The most lines sent in one request, InfluxDB recommends batches of
+    /// around 5000 lines.
+    const MAX_REQUEST_LINES: usize = 5000;
+    const CHANNEL_SIZE: usize = 64;
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+    const MIN_RETRY_DELAY: Duration = Duration::from_secs(5);
+    const MAX_RETRY_DELAY: Duration = Duration::from_secs(600);
+    const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub fn start(config: InfluxConfig) -> Result<InfluxWriter> {
+        let queue = DiskQueue::open(config.queue_path.clone())?;
+        let client = Client::builder().timeout(Self::REQUEST_TIMEOUT).build()?;
+
+        log::info!("Writing measurements to InfluxDB at {}", config.url);
+
+        let (line_tx, line_rx) = mpsc::channel(Self::CHANNEL_SIZE);
+        let task = tokio::spawn(Self::run(config.clone(), client, queue, line_rx));
+
+        Ok(InfluxWriter {
+            config,
+            line_tx,
+            task,
+        })
+    }
+
+    /// Queues the measurement for writing, never blocks.
+    pub fn push(&self, measurement: &InsertableMeasurement) {
+        if let Some(line) = self.config.line_protocol(measurement) {
+            if let Err(e) = self.line_tx.try_send(line) {
+                log::warn!("Failed to queue InfluxDB line: {e}");
+            }
+        }
+    }
+
+    /// Waits for the writer to persist everything that was pushed and make a
+    /// last attempt at sending it.
+    pub async fn shutdown(self) {
+        drop(self.line_tx);
+
+        let mut task = self.task;
+        if time::timeout(Self::SHUTDOWN_TIMEOUT, &mut task)
+            .await
+            .is_err()
+        {
+            log::warn!("Timed out flushing InfluxDB queue.");
+            task.abort();
+        }
+    }
+
+    async fn run(
+        config: InfluxConfig,
+        client: Client,
+        queue: DiskQueue,
+        mut line_rx: mpsc::Receiver<String>,
+    ) {
+        let queue = Arc::new(Mutex::new(queue));
+        let mut flush_timer = time::interval(config.flush_interval);
+        let mut retry_delay = Self::MIN_RETRY_DELAY;
+        let mut retry_at = time::Instant::now();
+
+        loop {
+            let flush_due = select! {
+                line = line_rx.recv() => match line {
+                    Some(line) => {
+                        // Whatever else has arrived by now goes to disk with
+                        // the same sync.
+                        let mut lines = vec![line];
+                        while let Ok(line) = line_rx.try_recv() {
+                            lines.push(line);
+                        }
+
+                        if let Err(e) = with_queue(&queue, move |queue| queue.push(&lines)).await {
+                            log::error!("Failed to queue InfluxDB lines: {e:#}");
+                        }
+                        queue.lock().unwrap().len() >= config.batch_size
+                    }
+                    // All senders are gone, we're shutting down.
+                    None => break,
+                },
+                _ = flush_timer.tick() => true,
+            };
+
+            if !flush_due || time::Instant::now() < retry_at {
+                continue;
+            }
+
+            match Self::flush(&config, &client, &queue).await {
+                Ok(()) => retry_delay = Self::MIN_RETRY_DELAY,
+                Err(e) => {
+                    log::warn!("Failed to write to InfluxDB, retrying in {retry_delay:?}: {e:#}");
+                    retry_at = time::Instant::now() + retry_delay;
+                    retry_delay = (retry_delay * 2).min(Self::MAX_RETRY_DELAY);
+                }
+            }
+        }
+
+        // Give the server one last chance, whatever doesn't make it stays on
+        // disk for next time.
+        if let Err(e) = Self::flush(&config, &client, &queue).await {
+            log::warn!(
+                "Failed to write to InfluxDB, {} lines left queued: {e:#}",
+                queue.lock().unwrap().len()
+            );
+        }
+    }
+
+    /// Sends everything that's queued, in batches.
+    async fn flush(
+        config: &InfluxConfig,
+        client: &Client,
+        queue: &Arc<Mutex<DiskQueue>>,
+    ) -> Result<()> {
+        loop {
+            let lines = with_queue(queue, |queue| queue.peek(Self::MAX_REQUEST_LINES)).await?;
+            if lines.is_empty() {
+                return Ok(());
+            }
+
+            let rejected = Self::write_accepted(config, client, &lines).await?;
+
+            let written = lines.len() - rejected;
+            with_queue(queue, move |queue| queue.pop(&lines)).await?;
+            log::debug!("Wrote {written} lines to InfluxDB.");
+        }
+    }
+
+    /// Writes the lines, leaving out the ones InfluxDB rejects, and returns
+    /// how many those were. A rejected request is split in halves until the
+    /// bad lines are on their own, so that they don't take the rest with
+    /// them. Sending lines again that a partial write has already stored does
+    /// no harm, a point with the same series and time is just overwritten.
+    async fn write_accepted(
+        config: &InfluxConfig,
+        client: &Client,
+        lines: &[String],
+    ) -> Result<usize> {
+        let mut pending = vec![lines];
+        let mut rejected = 0;
+
+        while let Some(lines) = pending.pop() {
+            match Self::write(config, client, lines).await? {
+                Written::Accepted => {}
+                // The server won't ever accept it, retrying would just block
+                // everything queued behind it.
+                Written::Rejected(error) if lines.len() == 1 => {
+                    log::error!("InfluxDB rejected {:?}, dropping it: {error}", lines[0]);
+                    rejected += 1;
+                }
+                Written::Rejected(_) => {
+                    let (first, second) = lines.split_at(lines.len() / 2);
+                    pending.extend([second, first]);
+                }
+            }
+        }
+
+        Ok(rejected)
+    }
+
+    async fn write(config: &InfluxConfig, client: &Client, lines: &[String]) -> Result<Written> {
+        let response = config
+            .write_request(client)
+            .body(lines.join("\n"))
+            .send()
+            .await?;
+
+        match response.status() {
+            status if status.is_success() => Ok(Written::Accepted),
+            StatusCode::BAD_REQUEST => {
+                Ok(Written::Rejected(response.text().await.unwrap_or_default()))
+            }
+            status => {
+                let body = response.text().await.unwrap_or_default();
+                Err(anyhow!("InfluxDB responded with {status}: {body}"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(measurement_name: &str, tags: &[(&str, &str)]) -> InfluxConfig {
+        InfluxConfig {
+            url: "http://localhost:8086".to_owned(),
+            api_version: ApiVersion::V2 {
+                org: "home".to_owned(),
+                bucket: "environment".to_owned(),
+                token: "token".to_owned(),
+            },
+            measurement_name: measurement_name.to_owned(),
+            tags: tags
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            batch_size: 10,
+            flush_interval: Duration::from_secs(60),
+            queue_path: PathBuf::from("influx_queue.lp"),
+        }
+    }
+
+    fn measurement() -> InsertableMeasurement {
+        InsertableMeasurement {
+            meas_time: crate::db::DateTimeUtc::from_micros(1_700_000_000_123_456).unwrap(),
+            temperature: Some(21.5),
+            pressure: Some(101325.0),
+            light_level: None,
+            ..InsertableMeasurement::example()
+        }
+    }
+
+    #[test]
+    fn escapes_measurement_tag_keys_and_values() {
+        assert_eq!(escape("living room", &[',', ' ']), "living\\ room");
+        assert_eq!(escape("a,b=c", &[',', '=', ' ']), "a\\,b\\=c");
+        assert_eq!(escape("back\\slash", &[',', ' ']), "back\\\\slash");
+        assert_eq!(escape("a=b", &[',', ' ']), "a=b");
+    }
+
+    #[test]
+    fn formats_a_line() {
+        let config = config("env, home", &[("node", "pi"), ("room", "living room")]);
+
+        assert_eq!(
+            config.line_protocol(&measurement()).unwrap(),
+            "env\\,\\ home,node=pi,room=living\\ room \
+             temperature=21.5,pressure=101325 1700000000123456"
+        );
+    }
+
+    #[test]
+    fn leaves_out_non_finite_values() {
+        let config = config("environment", &[]);
+        let measurement = InsertableMeasurement {
+            temperature: Some(f32::NAN),
+            pressure: Some(f32::INFINITY),
+            light_level: Some(0.5),
+            ..measurement()
+        };
+
+        assert_eq!(
+            config.line_protocol(&measurement).unwrap(),
+            "environment light_level=0.5 1700000000123456"
+        );
+    }
+
+    #[test]
+    fn nothing_to_write() {
+        let config = config("environment", &[]);
+        let measurement = InsertableMeasurement {
+            temperature: Some(f32::NAN),
+            pressure: None,
+            light_level: None,
+            ..measurement()
+        };
+
+        assert_eq!(config.line_protocol(&measurement), None);
+    }
+}
@@ -0,0 +1,188 @@
+use anyhow::Result;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+/// An append-only file of line protocol lines waiting to be written to
+/// InfluxDB, so that nothing is lost if the server is unreachable for a while
+/// or we get restarted in the meantime.
+///
+/// Sent lines aren't removed from the file, the offset of the first one
+/// that's still queued is kept next to it instead. The file is truncated once
+/// everything's been sent, which is after every flush as long as the server's
+/// reachable. The methods do blocking I/O.
+pub struct DiskQueue {
+    path: PathBuf,
+    head_path: PathBuf,
+    /// Where the oldest queued line starts.
+    head: u64,
+    len: usize,
+}
+
+impl DiskQueue {
+    pub fn open(path: PathBuf) -> Result<DiskQueue> {
+        let head_path = path.with_extension("head");
+        let head = match fs::read_to_string(&head_path) {
+            Ok(head) => head.trim().parse()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+
+        let len = match File::open(&path) {
+            Ok(mut file) => {
+                file.seek(SeekFrom::Start(head))?;
+                BufReader::new(file).lines().count()
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+
+        if len > 0 {
+            log::info!("{len} InfluxDB lines queued in {}", path.display());
+        }
+
+        Ok(DiskQueue {
+            path,
+            head_path,
+            head,
+            len,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Appends the lines, syncing once for all of them.
+    pub fn push(&mut self, lines: &[String]) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+
+        for line in lines {
+            writeln!(file, "{line}")?;
+        }
+        file.sync_data()?;
+
+        self.len += lines.len();
+
+        Ok(())
+    }
+
+    /// Returns up to `max_lines` of the oldest queued lines.
+    pub fn peek(&self, max_lines: usize) -> Result<Vec<String>> {
+        if self.len == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.head))?;
+
+        BufReader::new(file)
+            .lines()
+            .take(max_lines)
+            .map(|line| line.map_err(Into::into))
+            .collect()
+    }
+
+    /// Drops the oldest lines, the ones `peek` returned.
+    pub fn pop(&mut self, lines: &[String]) -> Result<()> {
+        let len = self.len.saturating_sub(lines.len());
+
+        if len == 0 {
+            File::create(&self.path)?.sync_data()?;
+            match fs::remove_file(&self.head_path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            self.head = 0;
+        } else {
+            let head = self.head + lines.iter().map(|line| line.len() as u64 + 1).sum::<u64>();
+
+            // Write to a temporary file and rename it over the old one, so
+            // that a crash halfway through doesn't leave a broken offset.
+            let tmp_path = self.head_path.with_extension("head.tmp");
+            let mut tmp_file = File::create(&tmp_path)?;
+            write!(tmp_file, "{head}")?;
+            tmp_file.sync_data()?;
+            fs::rename(&tmp_path, &self.head_path)?;
+
+            self.head = head;
+        }
+
+        self.len = len;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn queue_path() -> PathBuf {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let dir = std::env::temp_dir().join(format!(
+            "rpi_client_temp-queue-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+
+        dir.join("influx_queue.lp")
+    }
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn pops_in_order() {
+        let mut queue = DiskQueue::open(queue_path()).unwrap();
+        queue.push(&lines(&["a 1", "b 2", "c 3"])).unwrap();
+
+        let batch = queue.peek(2).unwrap();
+        assert_eq!(batch, ["a 1", "b 2"]);
+
+        queue.pop(&batch).unwrap();
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.peek(2).unwrap(), ["c 3"]);
+    }
+
+    #[test]
+    fn keeps_the_position_across_restarts() {
+        let path = queue_path();
+
+        let mut queue = DiskQueue::open(path.clone()).unwrap();
+        queue.push(&lines(&["a 1", "b 2"])).unwrap();
+        queue.pop(&queue.peek(1).unwrap()).unwrap();
+        queue.push(&lines(&["c 3"])).unwrap();
+
+        let queue = DiskQueue::open(path).unwrap();
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.peek(10).unwrap(), ["b 2", "c 3"]);
+    }
+
+    #[test]
+    fn truncates_once_empty() {
+        let path = queue_path();
+
+        let mut queue = DiskQueue::open(path.clone()).unwrap();
+        queue.push(&lines(&["a 1", "b 2"])).unwrap();
+        queue.pop(&queue.peek(10).unwrap()).unwrap();
+
+        assert_eq!(queue.len(), 0);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+        assert!(!path.with_extension("head").exists());
+
+        queue.push(&lines(&["c 3"])).unwrap();
+        assert_eq!(DiskQueue::open(path).unwrap().peek(10).unwrap(), ["c 3"]);
+    }
+}
@@ -1,16 +1,68 @@
 use anyhow::Result;
+use clap::Parser;
 use lazy_static::lazy_static;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
 use tokio::{select, task, time};
 
-use std::{path::PathBuf, sync::Arc, time::Duration};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+mod alerts;
+use alerts::{AlertConfig, AlertEngine};
+
+mod api;
+mod backfill;
+mod backup;
+use backup::BackupConfig;
+
+mod cli;
+mod clock;
+mod collect;
+use cli::{Cli, Command};
+
+mod config;
 
 mod enviro_phat;
+use enviro_phat::leds::{LedConfig, Leds};
 use enviro_phat::{EnviroPHat, MeasureEnvironment};
 
-use diesel::prelude::*;
-
 mod db;
-use db::{InsertableMeasurement, Measurement};
+use db::inventory::Inventory;
+use db::{DbConnection, InsertableMeasurement};
+
+mod export;
+mod forecast;
+use forecast::ForecastConfig;
+
+mod import;
+
+mod influx;
+use influx::{InfluxConfig, InfluxWriter};
+
+mod metrics;
+use metrics::METRICS;
+
+mod mqtt;
+use mqtt::{MqttConfig, MqttPublisher};
+
+mod notify;
+use notify::leds::LedNotifier;
+use notify::webhook::{WebhookConfig, WebhookNotifier};
+use notify::{Event, LogNotifier, Notifier, Notifiers};
+
+mod retention;
+use retention::RetentionConfig;
+
+mod systemd;
+
+mod upload;
+use upload::UploadConfig;
+
+mod write_buffer;
+use write_buffer::{WriteBuffer, WriteBufferConfig};
 
 lazy_static! {
     static ref CONFIG: GlobalConfig = GlobalConfig::from_env().unwrap();
@@ -20,13 +72,29 @@ lazy_static! {
 struct GlobalConfig {
     i2c_bus_path: PathBuf,
     measurement_period: Duration,
-    db_path: PathBuf,
+    db_url: String,
+    location: Option<String>,
+    clock_sync_check: bool,
+    http_listen_addr: Option<SocketAddr>,
+    mqtt: Option<MqttConfig>,
+    influx: Option<InfluxConfig>,
+    retention: Option<RetentionConfig>,
+    upload: Option<UploadConfig>,
+    write_buffer: Option<WriteBufferConfig>,
+    backup: Option<BackupConfig>,
+    alerts: Option<AlertConfig>,
+    webhook: Option<WebhookConfig>,
+    forecast: Option<ForecastConfig>,
+    leds: Option<LedConfig>,
 }
 
 impl GlobalConfig {
     const I2C_DEV_PATH_ENV_VAR: &'static str = "I2C_DEV_PATH";
     const MEASUREMENT_PERIOD_ENV_VAR: &'static str = "MEASUREMENT_PERIOD_SECS";
-    const DB_FILE_PATH_ENV_VAR: &'static str = "DATABASE_URL";
+    const DB_URL_ENV_VAR: &'static str = "DATABASE_URL";
+    const LOCATION_ENV_VAR: &'static str = "STATION_LOCATION";
+    const CLOCK_SYNC_CHECK_ENV_VAR: &'static str = "CLOCK_SYNC_CHECK";
+    const HTTP_LISTEN_ADDR_ENV_VAR: &'static str = "HTTP_LISTEN_ADDR";
 
     fn from_env() -> Result<Self> {
         dotenv::dotenv()?;
@@ -35,54 +103,438 @@ impl GlobalConfig {
         let measurement_period_secs = dotenv::var(Self::MEASUREMENT_PERIOD_ENV_VAR)?.parse()?;
         let measurement_period = Duration::from_secs(measurement_period_secs);
 
-        let db_path = PathBuf::from(dotenv::var(Self::DB_FILE_PATH_ENV_VAR)?);
+        let db_url = dotenv::var(Self::DB_URL_ENV_VAR)?;
+
+        // Where the node is, e.g. "living room", recorded with the station.
+        let location = config::optional_var(Self::LOCATION_ENV_VAR)?;
+
+        // Can be turned off where the kernel isn't told about NTP sync, e.g.
+        // in containers or with an RTC and no NTP at all.
+        let clock_sync_check = config::var_or(Self::CLOCK_SYNC_CHECK_ENV_VAR, true)?;
+
+        // The HTTP API is optional, it's only started if a listen address is set.
+        let http_listen_addr = config::optional_var(Self::HTTP_LISTEN_ADDR_ENV_VAR)?;
+
+        let mqtt = MqttConfig::from_env()?;
+        let influx = InfluxConfig::from_env()?;
+        let upload = UploadConfig::from_env()?;
+        let retention = RetentionConfig::from_env(upload.is_some())?;
+        let write_buffer = WriteBufferConfig::from_env()?;
+        let backup = BackupConfig::from_env()?;
+        let alerts = AlertConfig::from_env()?;
+        let webhook = WebhookConfig::from_env()?;
+        let forecast = ForecastConfig::from_env()?;
+        let leds = LedConfig::from_env()?;
 
         Ok(Self {
             i2c_bus_path,
             measurement_period,
-            db_path,
+            db_url,
+            location,
+            clock_sync_check,
+            http_listen_addr,
+            mqtt,
+            influx,
+            retention,
+            upload,
+            write_buffer,
+            backup,
+            alerts,
+            webhook,
+            forecast,
+            leds,
         })
     }
 }
 
-#[tokio::main]
-async fn main() {
+async fn measure(
+    enviro_phat: &Arc<EnviroPHat>,
+    inventory: &Inventory,
+) -> Result<InsertableMeasurement> {
+    let phat = enviro_phat.clone();
+    let measurement_res = task::spawn_blocking(move || phat.measure()).await??;
+    log::info!("Measurement result: {measurement_res:?}");
+
+    let mut insertable = InsertableMeasurement::from(&measurement_res);
+    inventory.apply(&mut insertable);
+    if !CONFIG.clock_sync_check {
+        insertable.clock_synced = true;
+    }
+
+    Ok(insertable)
+}
+
+/// Fixes up the measurements taken before the clock was synchronised.
+async fn retimestamp_unsynced(
+    write_buffer: &mut WriteBuffer,
+    db_conn: &Arc<Mutex<DbConnection>>,
+) -> Result<usize> {
+    let Some(boot_id) = clock::BOOT_ID.as_ref() else {
+        return Ok(0);
+    };
+    // Those still buffered need fixing up as well.
+    write_buffer.flush().await?;
+    let db_conn = db_conn.clone();
+
+    task::spawn_blocking(move || {
+        let now = clock::Timestamp::now();
+        db::query::retimestamp_unsynced(&mut db_conn.lock().unwrap(), boot_id, &now)
+    })
+    .await?
+    .map_err(Into::into)
+}
+
+fn main() -> Result<()> {
     pretty_env_logger::init();
+
+    match Cli::parse().command.unwrap_or(Command::Run) {
+        Command::Run => run(),
+        Command::Export(args) => export::export(args, || CONFIG.db_url.clone())?,
+        Command::Import(args) => import::import(args, || CONFIG.db_url.clone())?,
+        Command::Backfill(args) => backfill::backfill(args, || CONFIG.db_url.clone())?,
+        Command::Backup(args) => backup::backup(args, || CONFIG.db_url.clone())?,
+        Command::Collect => collect::run(collect::CollectConfig::from_env()?)?,
+    }
+
+    Ok(())
+}
+
+/// Ticks the interval, or never if there isn't one.
+async fn tick(interval: &mut Option<time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+#[tokio::main]
+async fn run() {
     log::info!("Hello, world!");
 
     let mut measurement_timer = time::interval(CONFIG.measurement_period);
-    let enviro_phat = Arc::new(EnviroPHat::new(&CONFIG.i2c_bus_path).unwrap());
+    let mut forecast_timer = CONFIG
+        .forecast
+        .as_ref()
+        .map(|forecast_config| time::interval(forecast_config.interval()));
+    let leds = CONFIG
+        .leds
+        .as_ref()
+        .map(|led_config| Arc::new(Leds::open(led_config).unwrap()));
+    let enviro_phat = Arc::new(EnviroPHat::new(&CONFIG.i2c_bus_path, leds.clone()).unwrap());
+
+    let mut db_conn = db::establish_connection(&CONFIG.db_url).unwrap();
+    if CONFIG.retention.is_some() {
+        retention::enable_incremental_vacuum(&mut db_conn).unwrap();
+    }
+    let db_conn = Arc::new(Mutex::new(db_conn));
+
+    let sensors = enviro_phat.sensors();
+    let inventory = db::inventory::register_local(
+        &mut db_conn.lock().unwrap(),
+        &config::hostname().unwrap(),
+        CONFIG.location.as_deref(),
+        CONFIG.i2c_bus_path.to_str(),
+        &sensors,
+    )
+    .unwrap();
+    log::info!(
+        "Registered as station {} with {} sensors",
+        inventory.station_id,
+        sensors.len()
+    );
+
+    let mut write_buffer = WriteBuffer::new(CONFIG.write_buffer.clone(), db_conn.clone());
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let mut notifiers: Vec<Arc<dyn Notifier>> = vec![Arc::new(LogNotifier)];
+
+    let webhook_task = CONFIG.webhook.clone().map(|webhook_config| {
+        let (webhook_notifier, webhook_task) =
+            WebhookNotifier::start(webhook_config, db_conn.clone(), shutdown_rx.clone()).unwrap();
+        notifiers.push(Arc::new(webhook_notifier));
+
+        webhook_task
+    });
+
+    let led_task = leds
+        .filter(|_| CONFIG.leds.as_ref().is_some_and(LedConfig::status_blink))
+        .map(|leds| {
+            let (led_notifier, led_task) = LedNotifier::start(leds, shutdown_rx.clone());
+            notifiers.push(Arc::new(led_notifier));
+
+            led_task
+        });
+
+    let notifiers = Notifiers::new(notifiers);
 
-    let mut db_conn = SqliteConnection::establish(CONFIG.db_path.to_str().unwrap()).unwrap();
+    let mut alert_engine = CONFIG.alerts.clone().map(|alert_config| {
+        AlertEngine::new(alert_config, db_conn.clone(), notifiers.clone()).unwrap()
+    });
+
+    let mqtt_publisher = CONFIG.mqtt.clone().map(|mqtt_config| {
+        MqttPublisher::start(
+            mqtt_config,
+            &enviro_phat.quantities(),
+            CONFIG.forecast.is_some(),
+        )
+        .unwrap()
+    });
+
+    let influx_writer = CONFIG
+        .influx
+        .clone()
+        .map(|influx_config| InfluxWriter::start(influx_config).unwrap());
+
+    let api_task = CONFIG.http_listen_addr.map(|listen_addr| {
+        tokio::spawn(api::serve(
+            listen_addr,
+            db_conn.clone(),
+            Some(enviro_phat.clone()),
+            None,
+            shutdown_rx.clone(),
+        ))
+    });
+
+    let retention_task = CONFIG.retention.clone().map(|retention_config| {
+        tokio::spawn(retention::run(
+            retention_config,
+            db_conn.clone(),
+            shutdown_rx.clone(),
+        ))
+    });
+
+    let upload_task = CONFIG.upload.clone().map(|upload_config| {
+        tokio::spawn(upload::run(
+            upload_config,
+            db_conn.clone(),
+            shutdown_rx.clone(),
+        ))
+    });
+
+    let backup_task = CONFIG.backup.clone().map(|backup_config| {
+        tokio::spawn(backup::run(
+            backup_config,
+            CONFIG.db_url.clone(),
+            shutdown_rx.clone(),
+        ))
+    });
+
+    let mut sigint = signal(SignalKind::interrupt()).unwrap();
+    let mut sigterm = signal(SignalKind::terminate()).unwrap();
+
+    if let Some(warning) = systemd::watchdog_interval()
+        .and_then(|interval| systemd::watchdog_warning(interval, CONFIG.measurement_period))
+    {
+        log::warn!("{warning}");
+    }
+
+    systemd::notify_ready();
+
+    // Measurements from before a restart may still be waiting for the clock.
+    let mut retimestamp_pending = true;
+    let mut clock_was_synced = true;
+    // Consecutive failed measurement cycles, only the first one of a run is
+    // notified about.
+    let mut failed_cycles = 0;
 
     loop {
+        // The branch handlers are not polled concurrently with the other
+        // branches, so a signal that arrives while a measurement is in flight
+        // is only acted upon after the measurement has been stored.
         select! {
             _ = measurement_timer.tick() => {
                 log::info!("Measuring");
 
-                let phat = enviro_phat.clone();
+                match measure(&enviro_phat, &inventory).await {
+                    Ok(measurement) => {
+                        METRICS.record_measurement(&measurement);
+
+                        // The measurement stays buffered if it can't be
+                        // written, the rest of the cycle goes on with it.
+                        if let Err(e) = write_buffer.push(measurement.clone()).await {
+                            METRICS.db_write_failures.inc();
+                            log::error!("Storing the measurement failed: {e:#}");
+                        }
 
-                let measurement_task = task::spawn_blocking(move || {
-                    phat.measure()
-                });
+                        if failed_cycles > 0 {
+                            notifiers.notify(&Event::SensorRecovered {
+                                time: measurement.meas_time.clone(),
+                                failures: failed_cycles,
+                            });
+                            failed_cycles = 0;
+                        }
 
-                let measurement_res = measurement_task.await.unwrap().unwrap();
-                log::info!("Measurement result: {measurement_res:?}");
+                        if let Some(mqtt_publisher) = &mqtt_publisher {
+                            mqtt_publisher.publish(&measurement);
+                        }
 
-                let measurements = {
-                    use db::schema::measurements::dsl::*;
+                        if let Some(alert_engine) = &mut alert_engine {
+                            if let Err(e) = alert_engine.evaluate(&measurement, write_buffer.pending()).await {
+                                log::error!("Evaluating alert rules failed: {e:#}");
+                            }
+                        }
 
-                    let insertable = InsertableMeasurement::from(measurement_res);
-                    diesel::insert_into(measurements)
-                        .values(&insertable)
-                        .execute(&mut db_conn)
-                          .unwrap();
+                        // InfluxDB has no way to fix the time up later.
+                        if let Some(influx_writer) = influx_writer
+                            .as_ref()
+                            .filter(|_| measurement.clock_synced)
+                        {
+                            influx_writer.push(&measurement);
+                        }
 
-                    measurements.load::<Measurement>(&mut db_conn).unwrap()
+                        if !measurement.clock_synced {
+                            if clock_was_synced {
+                                log::warn!(
+                                    "The system clock isn't synchronised, measurements will be \
+                                     re-timestamped once it is."
+                                );
+                            }
+                            retimestamp_pending = true;
+                        } else if retimestamp_pending {
+                            match retimestamp_unsynced(&mut write_buffer, &db_conn).await {
+                                Ok(fixed) => {
+                                    if fixed > 0 {
+                                        log::info!("Re-timestamped {fixed} measurements.");
+                                    }
+                                    retimestamp_pending = false;
+                                }
+                                Err(e) => log::error!("Re-timestamping measurements failed: {e:#}"),
+                            }
+                        }
+                        clock_was_synced = measurement.clock_synced;
+
+                        systemd::notify_status(&format!("Last measurement: {measurement}"));
+                        // Only pet the watchdog when the whole cycle went
+                        // through, so that systemd restarts us if the I2C bus
+                        // or the DB gets stuck. A DB that fails rather than
+                        // hangs isn't helped by a restart.
+                        systemd::notify_watchdog();
+                    }
+                    Err(e) => {
+                        METRICS.measurement_cycle_failures.inc();
+                        log::error!("Measurement failed: {e:#}");
+
+                        if failed_cycles == 0 {
+                            notifiers.notify(&Event::SensorFailure {
+                                time: db::DateTimeUtc::now(),
+                                error: format!("{e:#}"),
+                            });
+                        }
+                        failed_cycles += 1;
+                        systemd::notify_status(&format!("Last measurement failed: {e}"));
+                    }
+                }
+            }
+            _ = tick(&mut forecast_timer) => {
+                let Some(forecast_config) = &CONFIG.forecast else {
+                    continue;
                 };
 
-                log::info!("Measurements already in DB: {measurements:?}");
+                match forecast::update(forecast_config, &db_conn).await {
+                    Ok(Some(forecast)) => {
+                        log::info!(
+                            "Forecast: {} ({}, {:.1} hPa)",
+                            forecast.forecast,
+                            forecast.tendency,
+                            forecast.sea_level_pressure / 100.0
+                        );
+
+                        if let Some(mqtt_publisher) = &mqtt_publisher {
+                            mqtt_publisher.publish_forecast(&forecast);
+                        }
+                    }
+                    Ok(None) => log::info!("Not enough pressure readings for a forecast yet."),
+                    Err(e) => log::error!("Forecasting failed: {e:#}"),
+                }
+            }
+            _ = sigint.recv() => {
+                log::info!("Received SIGINT, shutting down.");
+                break;
+            }
+            _ = sigterm.recv() => {
+                log::info!("Received SIGTERM, shutting down.");
+                break;
             }
         }
     }
+
+    systemd::notify_stopping();
+
+    match write_buffer.flush().await {
+        Ok(flushed) => log::info!("Wrote {flushed} buffered measurements."),
+        Err(e) => log::error!("Writing buffered measurements failed: {e:#}"),
+    }
+
+    // Let the background tasks finish whatever they're doing and exit.
+    shutdown_tx.send_replace(true);
+
+    if let Some(mqtt_publisher) = mqtt_publisher {
+        mqtt_publisher.shutdown().await;
+    }
+
+    if let Some(influx_writer) = influx_writer {
+        influx_writer.shutdown().await;
+    }
+
+    if let Some(api_task) = api_task {
+        match api_task.await {
+            Ok(Ok(())) => log::info!("HTTP API stopped."),
+            Ok(Err(e)) => log::error!("HTTP API failed: {e:#}"),
+            Err(e) => log::error!("HTTP API task failed: {e}"),
+        }
+    }
+
+    if let Some(retention_task) = retention_task {
+        match retention_task.await {
+            Ok(Ok(())) => log::info!("Retention task stopped."),
+            Ok(Err(e)) => log::error!("Retention task failed: {e:#}"),
+            Err(e) => log::error!("Retention task failed: {e}"),
+        }
+    }
+
+    if let Some(upload_task) = upload_task {
+        match upload_task.await {
+            Ok(Ok(())) => log::info!("Upload task stopped."),
+            Ok(Err(e)) => log::error!("Upload task failed: {e:#}"),
+            Err(e) => log::error!("Upload task failed: {e}"),
+        }
+    }
+
+    if let Some(webhook_task) = webhook_task {
+        match webhook_task.await {
+            Ok(Ok(())) => log::info!("Webhook notifier stopped."),
+            Ok(Err(e)) => log::error!("Webhook notifier failed: {e:#}"),
+            Err(e) => log::error!("Webhook notifier task failed: {e}"),
+        }
+    }
+
+    if let Some(led_task) = led_task {
+        match led_task.await {
+            Ok(Ok(())) => log::info!("LED status blinking stopped."),
+            Ok(Err(e)) => log::error!("LED status blinking failed: {e:#}"),
+            Err(e) => log::error!("LED status task failed: {e}"),
+        }
+    }
+
+    if let Some(backup_task) = backup_task {
+        match backup_task.await {
+            Ok(Ok(())) => log::info!("Backup task stopped."),
+            Ok(Err(e)) => log::error!("Backup task failed: {e:#}"),
+            Err(e) => log::error!("Backup task failed: {e}"),
+        }
+    }
+
+    let phat = enviro_phat.clone();
+    match task::spawn_blocking(move || phat.power_down()).await {
+        Ok(Ok(())) => log::info!("Sensors powered down."),
+        Ok(Err(e)) => log::error!("Failed to power down sensors: {e}"),
+        Err(e) => log::error!("Sensor power down task failed: {e}"),
+    }
+
+    drop(db_conn);
+
+    log::info!("Goodbye.");
 }
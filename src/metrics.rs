@@ -0,0 +1,326 @@
+use lazy_static::lazy_static;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::db::InsertableMeasurement;
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+/// A float gauge, NaN until it's set for the first time.
+pub struct Gauge(AtomicU64);
+
+impl Default for Gauge {
+    fn default() -> Self {
+        Gauge(AtomicU64::new(f64::NAN.to_bits()))
+    }
+}
+
+impl Gauge {
+    pub fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Option<f64> {
+        let value = f64::from_bits(self.0.load(Ordering::Relaxed));
+
+        if value.is_nan() {
+            None
+        } else {
+            Some(value)
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct SensorCounts {
+    successes: u64,
+    failures: u64,
+}
+
+#[derive(Default, Clone, Copy)]
+struct DurationSummary {
+    sum: Duration,
+    count: u64,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    pub temperature: Gauge,
+    pub pressure: Gauge,
+    pub humidity: Gauge,
+    pub light_level: Gauge,
+
+    pub measurement_cycle_successes: Counter,
+    pub measurement_cycle_failures: Counter,
+    pub last_success_timestamp: Gauge,
+
+    pub i2c_transfer_errors: Counter,
+    pub db_write_failures: Counter,
+
+    pub pruned_rows: Counter,
+    pub uploaded_measurements: Counter,
+
+    pub backup_successes: Counter,
+    pub backup_failures: Counter,
+    pub backup_integrity_failures: Counter,
+    pub last_backup_timestamp: Gauge,
+    pub last_backup_size: Gauge,
+
+    pub alerts_firing: Gauge,
+    pub webhook_deliveries: Counter,
+    pub webhook_dead_letters: Counter,
+
+    sensor_counts: Mutex<BTreeMap<&'static str, SensorCounts>>,
+    db_insert_duration: Mutex<DurationSummary>,
+}
+
+impl Metrics {
+    pub fn record_sensor_result(&self, sensor: &'static str, success: bool) {
+        let mut sensor_counts = self.sensor_counts.lock().unwrap();
+        let counts = sensor_counts.entry(sensor).or_default();
+
+        if success {
+            counts.successes += 1;
+        } else {
+            counts.failures += 1;
+        }
+    }
+
+    pub fn record_db_insert(&self, duration: Duration) {
+        let mut summary = self.db_insert_duration.lock().unwrap();
+
+        summary.sum += duration;
+        summary.count += 1;
+    }
+
+    pub fn record_measurement(&self, measurement: &InsertableMeasurement) {
+        let gauges = [
+            (&self.temperature, measurement.temperature),
+            (&self.pressure, measurement.pressure),
+            (&self.humidity, measurement.humidity),
+            (&self.light_level, measurement.light_level),
+        ];
+
+        for (gauge, value) in gauges {
+            if let Some(value) = value {
+                gauge.set(value.into());
+            }
+        }
+
+        self.measurement_cycle_successes.inc();
+        self.last_success_timestamp
+            .set(chrono::Utc::now().timestamp_micros() as f64 / 1e6);
+    }
+}
+
+/// Renders all the metrics in the Prometheus text exposition format.
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let gauges = [
+            (
+                "enviro_temperature_celsius",
+                "Last measured temperature.",
+                &self.temperature,
+            ),
+            (
+                "enviro_pressure_pascals",
+                "Last measured pressure.",
+                &self.pressure,
+            ),
+            (
+                "enviro_humidity_percent",
+                "Last measured relative humidity.",
+                &self.humidity,
+            ),
+            (
+                "enviro_light_level_ratio",
+                "Last measured light level relative to the sensor's full scale.",
+                &self.light_level,
+            ),
+            (
+                "enviro_last_success_timestamp_seconds",
+                "Unix time of the last successful measurement cycle.",
+                &self.last_success_timestamp,
+            ),
+            (
+                "enviro_last_backup_timestamp_seconds",
+                "Unix time of the last successful DB snapshot.",
+                &self.last_backup_timestamp,
+            ),
+            (
+                "enviro_last_backup_size_bytes",
+                "Size of the last successful DB snapshot.",
+                &self.last_backup_size,
+            ),
+            (
+                "enviro_alerts_firing",
+                "Alert rules that are currently firing.",
+                &self.alerts_firing,
+            ),
+        ];
+
+        for (name, help, gauge) in gauges {
+            if let Some(value) = gauge.get() {
+                writeln!(f, "# HELP {name} {help}")?;
+                writeln!(f, "# TYPE {name} gauge")?;
+                writeln!(f, "{name} {value}")?;
+            }
+        }
+
+        writeln!(
+            f,
+            "# HELP enviro_measurement_cycles_total Measurement cycles by result."
+        )?;
+        writeln!(f, "# TYPE enviro_measurement_cycles_total counter")?;
+        writeln!(
+            f,
+            "enviro_measurement_cycles_total{{result=\"success\"}} {}",
+            self.measurement_cycle_successes.get()
+        )?;
+        writeln!(
+            f,
+            "enviro_measurement_cycles_total{{result=\"failure\"}} {}",
+            self.measurement_cycle_failures.get()
+        )?;
+
+        writeln!(
+            f,
+            "# HELP enviro_sensor_measurements_total Sensor readouts by sensor and result."
+        )?;
+        writeln!(f, "# TYPE enviro_sensor_measurements_total counter")?;
+        for (sensor, counts) in self.sensor_counts.lock().unwrap().iter() {
+            writeln!(
+                f,
+                "enviro_sensor_measurements_total{{sensor=\"{sensor}\",result=\"success\"}} {}",
+                counts.successes
+            )?;
+            writeln!(
+                f,
+                "enviro_sensor_measurements_total{{sensor=\"{sensor}\",result=\"failure\"}} {}",
+                counts.failures
+            )?;
+        }
+
+        writeln!(
+            f,
+            "# HELP enviro_i2c_transfer_errors_total Failed I2C transfers."
+        )?;
+        writeln!(f, "# TYPE enviro_i2c_transfer_errors_total counter")?;
+        writeln!(
+            f,
+            "enviro_i2c_transfer_errors_total {}",
+            self.i2c_transfer_errors.get()
+        )?;
+
+        writeln!(
+            f,
+            "# HELP enviro_db_write_failures_total Failed writes of measurements to the DB."
+        )?;
+        writeln!(f, "# TYPE enviro_db_write_failures_total counter")?;
+        writeln!(
+            f,
+            "enviro_db_write_failures_total {}",
+            self.db_write_failures.get()
+        )?;
+
+        writeln!(
+            f,
+            "# HELP enviro_pruned_rows_total Rows deleted by the retention policy."
+        )?;
+        writeln!(f, "# TYPE enviro_pruned_rows_total counter")?;
+        writeln!(f, "enviro_pruned_rows_total {}", self.pruned_rows.get())?;
+
+        writeln!(
+            f,
+            "# HELP enviro_uploaded_measurements_total Measurements acknowledged by the collector."
+        )?;
+        writeln!(f, "# TYPE enviro_uploaded_measurements_total counter")?;
+        writeln!(
+            f,
+            "enviro_uploaded_measurements_total {}",
+            self.uploaded_measurements.get()
+        )?;
+
+        writeln!(f, "# HELP enviro_backups_total DB snapshots by result.")?;
+        writeln!(f, "# TYPE enviro_backups_total counter")?;
+        writeln!(
+            f,
+            "enviro_backups_total{{result=\"success\"}} {}",
+            self.backup_successes.get()
+        )?;
+        writeln!(
+            f,
+            "enviro_backups_total{{result=\"failure\"}} {}",
+            self.backup_failures.get()
+        )?;
+
+        writeln!(
+            f,
+            "# HELP enviro_backup_integrity_failures_total DB snapshots that failed the integrity check."
+        )?;
+        writeln!(f, "# TYPE enviro_backup_integrity_failures_total counter")?;
+        writeln!(
+            f,
+            "enviro_backup_integrity_failures_total {}",
+            self.backup_integrity_failures.get()
+        )?;
+
+        writeln!(
+            f,
+            "# HELP enviro_webhook_deliveries_total Webhook deliveries by result."
+        )?;
+        writeln!(f, "# TYPE enviro_webhook_deliveries_total counter")?;
+        writeln!(
+            f,
+            "enviro_webhook_deliveries_total{{result=\"delivered\"}} {}",
+            self.webhook_deliveries.get()
+        )?;
+        writeln!(
+            f,
+            "enviro_webhook_deliveries_total{{result=\"dead_letter\"}} {}",
+            self.webhook_dead_letters.get()
+        )?;
+
+        let db_insert_duration = *self.db_insert_duration.lock().unwrap();
+        writeln!(
+            f,
+            "# HELP enviro_db_insert_duration_seconds Time taken by measurement inserts."
+        )?;
+        writeln!(f, "# TYPE enviro_db_insert_duration_seconds summary")?;
+        writeln!(
+            f,
+            "enviro_db_insert_duration_seconds_sum {}",
+            db_insert_duration.sum.as_secs_f64()
+        )?;
+        writeln!(
+            f,
+            "enviro_db_insert_duration_seconds_count {}",
+            db_insert_duration.count
+        )?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,269 @@
+use serde::Serialize;
+
+use super::{MqttConfig, MqttPublisher};
+use crate::enviro_phat::Quantity;
+
+// See https://www.home-assistant.io/integrations/sensor.mqtt/ for the meaning
+// of the individual fields.
+
+#[derive(Debug, Serialize)]
+struct Device<'a> {
+    identifiers: [&'a str; 1],
+    name: &'a str,
+    manufacturer: &'static str,
+    model: &'static str,
+    sw_version: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct SensorConfig<'a> {
+    name: &'static str,
+    unique_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state_class: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit_of_measurement: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suggested_display_precision: Option<u8>,
+    state_topic: String,
+    value_template: &'static str,
+    availability_topic: &'a str,
+    payload_available: &'static str,
+    payload_not_available: &'static str,
+    device: &'a Device<'a>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SensorDescription {
+    object_id: &'static str,
+    name: &'static str,
+    device_class: Option<&'static str>,
+    /// `None` for text sensors, which HA doesn't keep statistics of.
+    state_class: Option<&'static str>,
+    unit: Option<&'static str>,
+    precision: Option<u8>,
+    /// The level under the topic prefix that the value is taken from.
+    topic: &'static str,
+    value_template: &'static str,
+}
+
+fn describe(quantity: Quantity) -> SensorDescription {
+    match quantity {
+        Quantity::Temperature => SensorDescription {
+            object_id: "temperature",
+            name: "Temperature",
+            device_class: Some("temperature"),
+            state_class: Some("measurement"),
+            unit: Some("°C"),
+            precision: Some(1),
+            topic: "state",
+            value_template: "{{ value_json.temperature }}",
+        },
+        Quantity::Pressure => SensorDescription {
+            object_id: "pressure",
+            name: "Pressure",
+            device_class: Some("atmospheric_pressure"),
+            state_class: Some("measurement"),
+            unit: Some("hPa"),
+            precision: Some(1),
+            topic: "state",
+            value_template: "{{ (value_json.pressure / 100) if value_json.pressure is not none else none }}",
+        },
+        // The TCS3472 clear channel isn't calibrated in lux, so this can't be
+        // an illuminance sensor as far as HA is concerned. With a unit and a
+        // state class it's still a numeric sensor with a history graph.
+        Quantity::LightLevel => SensorDescription {
+            object_id: "light_level",
+            name: "Light level",
+            device_class: None,
+            state_class: Some("measurement"),
+            unit: Some("%"),
+            precision: Some(2),
+            topic: "state",
+            value_template: "{{ (value_json.light_level * 100) if value_json.light_level is not none else none }}",
+        },
+    }
+}
+
+/// The forecast, published on its own topic whenever it's updated.
+const FORECAST_SENSORS: [SensorDescription; 3] = [
+    SensorDescription {
+        object_id: "forecast",
+        name: "Forecast",
+        device_class: None,
+        state_class: None,
+        unit: None,
+        precision: None,
+        topic: "forecast",
+        value_template: "{{ value_json.forecast }}",
+    },
+    SensorDescription {
+        object_id: "sea_level_pressure",
+        name: "Sea level pressure",
+        device_class: Some("atmospheric_pressure"),
+        state_class: Some("measurement"),
+        unit: Some("hPa"),
+        precision: Some(1),
+        topic: "forecast",
+        value_template: "{{ (value_json.sea_level_pressure / 100) if value_json.sea_level_pressure is not none else none }}",
+    },
+    SensorDescription {
+        object_id: "pressure_tendency",
+        name: "Pressure tendency",
+        device_class: None,
+        state_class: None,
+        unit: None,
+        precision: None,
+        topic: "forecast",
+        value_template: "{{ value_json.tendency }}",
+    },
+];
+
+/// Builds the retained (topic, payload) discovery messages for the given
+/// quantities, and for the forecast if there's one.
+pub(super) fn discovery_messages(
+    config: &MqttConfig,
+    quantities: &[Quantity],
+    forecast: bool,
+) -> serde_json::Result<Vec<(String, String)>> {
+    let device = Device {
+        identifiers: [&config.device_id],
+        name: &config.device_name,
+        manufacturer: "Pimoroni",
+        model: "Enviro pHAT",
+        sw_version: env!("CARGO_PKG_VERSION"),
+    };
+
+    let status_topic = config.status_topic();
+
+    let forecast_sensors = if forecast { &FORECAST_SENSORS[..] } else { &[] };
+
+    quantities
+        .iter()
+        .map(|&quantity| describe(quantity))
+        .chain(forecast_sensors.iter().copied())
+        .map(|description| {
+            let sensor_config = SensorConfig {
+                name: description.name,
+                unique_id: format!("{}_{}", config.device_id, description.object_id),
+                device_class: description.device_class,
+                state_class: description.state_class,
+                unit_of_measurement: description.unit,
+                suggested_display_precision: description.precision,
+                state_topic: format!("{}/{}", config.topic_prefix, description.topic),
+                value_template: description.value_template,
+                availability_topic: &status_topic,
+                payload_available: MqttPublisher::STATUS_ONLINE,
+                payload_not_available: MqttPublisher::STATUS_OFFLINE,
+                device: &device,
+            };
+
+            let topic = format!(
+                "{}/sensor/{}/{}/config",
+                config.ha_discovery_prefix, config.device_id, description.object_id
+            );
+
+            Ok((topic, serde_json::to_string(&sensor_config)?))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn config() -> MqttConfig {
+        MqttConfig {
+            host: "localhost".to_owned(),
+            port: 1883,
+            client_id: "rpi_client_temp-pi".to_owned(),
+            topic_prefix: "rpi_client_temp/pi".to_owned(),
+            qos: rumqttc::QoS::AtLeastOnce,
+            retain: false,
+            username: None,
+            password: None,
+            tls: false,
+            ca_file: None,
+            client_cert_file: None,
+            client_key_file: None,
+            ha_discovery: true,
+            ha_discovery_prefix: "homeassistant".to_owned(),
+            device_id: "rpi_client_temp_abc".to_owned(),
+            device_name: "pi".to_owned(),
+            node_id: "node".to_owned(),
+        }
+    }
+
+    fn messages(quantities: &[Quantity]) -> Vec<(String, Value)> {
+        discovery_messages(&config(), quantities, false)
+            .unwrap()
+            .into_iter()
+            .map(|(topic, payload)| (topic, serde_json::from_str(&payload).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn one_config_per_quantity() {
+        let topics = messages(&[Quantity::Temperature, Quantity::Pressure])
+            .into_iter()
+            .map(|(topic, _)| topic)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            topics,
+            [
+                "homeassistant/sensor/rpi_client_temp_abc/temperature/config",
+                "homeassistant/sensor/rpi_client_temp_abc/pressure/config",
+            ]
+        );
+    }
+
+    #[test]
+    fn sensors_read_the_state_topic() {
+        let (_, config) = messages(&[Quantity::Pressure]).remove(0);
+
+        assert_eq!(config["state_topic"], "rpi_client_temp/pi/state");
+        assert_eq!(config["availability_topic"], "rpi_client_temp/pi/status");
+        assert_eq!(config["unique_id"], "rpi_client_temp_abc_pressure");
+        assert_eq!(config["device"]["identifiers"][0], "rpi_client_temp_abc");
+    }
+
+    #[test]
+    fn light_level_is_numeric() {
+        let (_, config) = messages(&[Quantity::LightLevel]).remove(0);
+
+        assert_eq!(config["unit_of_measurement"], "%");
+        assert_eq!(config["state_class"], "measurement");
+        assert!(config.get("device_class").is_none());
+    }
+
+    #[test]
+    fn scaled_values_tolerate_missing_readings() {
+        let templates =
+            discovery_messages(&config(), &[Quantity::Pressure, Quantity::LightLevel], true)
+                .unwrap()
+                .into_iter()
+                .map(|(topic, payload)| {
+                    let config: Value = serde_json::from_str(&payload).unwrap();
+                    (topic, config["value_template"].as_str().unwrap().to_owned())
+                })
+                .collect::<Vec<_>>();
+
+        for object_id in ["pressure", "light_level", "sea_level_pressure"] {
+            let (_, template) = templates
+                .iter()
+                .find(|(topic, _)| topic.ends_with(&format!("/{object_id}/config")))
+                .unwrap();
+            assert_eq!(
+                *template,
+                format!(
+                    "{{{{ (value_json.{object_id} {}) if value_json.{object_id} is not none else none }}}}",
+                    if object_id == "light_level" { "* 100" } else { "/ 100" }
+                )
+            );
+        }
+    }
+}
@@ -0,0 +1,437 @@
+use anyhow::{anyhow, Result};
+use rumqttc::{
+    AsyncClient, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, QoS, TlsConfiguration,
+    Transport,
+};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::config::{self, optional_var, var_or};
+use crate::db::forecasts::Forecast;
+use crate::db::InsertableMeasurement;
+use crate::enviro_phat::Quantity;
+
+mod discovery;
+
+/// What's published to the state topic: the measurement, and the node that
+/// took it, so that a collector stores it under the same node ID as an upload
+/// from the node.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct State {
+    /// Missing from older nodes, the collector goes by the topic then.
+    #[serde(default)]
+    pub node_id: Option<String>,
+    #[serde(flatten)]
+    pub measurement: InsertableMeasurement,
+}
+
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    host: String,
+    port: u16,
+    client_id: String,
+    topic_prefix: String,
+    qos: QoS,
+    retain: bool,
+    username: Option<String>,
+    password: Option<String>,
+    tls: bool,
+    ca_file: Option<PathBuf>,
+    client_cert_file: Option<PathBuf>,
+    client_key_file: Option<PathBuf>,
+    ha_discovery: bool,
+    ha_discovery_prefix: String,
+    device_id: String,
+    device_name: String,
+    node_id: String,
+}
+
+impl MqttConfig {
+    const HOST_ENV_VAR: &'static str = "MQTT_HOST";
+    const PORT_ENV_VAR: &'static str = "MQTT_PORT";
+    const CLIENT_ID_ENV_VAR: &'static str = "MQTT_CLIENT_ID";
+    const TOPIC_PREFIX_ENV_VAR: &'static str = "MQTT_TOPIC_PREFIX";
+    const QOS_ENV_VAR: &'static str = "MQTT_QOS";
+    const RETAIN_ENV_VAR: &'static str = "MQTT_RETAIN";
+    const USERNAME_ENV_VAR: &'static str = "MQTT_USERNAME";
+    const PASSWORD_ENV_VAR: &'static str = "MQTT_PASSWORD";
+    const TLS_ENV_VAR: &'static str = "MQTT_TLS";
+    const CA_FILE_ENV_VAR: &'static str = "MQTT_CA_FILE";
+    const CLIENT_CERT_FILE_ENV_VAR: &'static str = "MQTT_CLIENT_CERT_FILE";
+    const CLIENT_KEY_FILE_ENV_VAR: &'static str = "MQTT_CLIENT_KEY_FILE";
+    const HA_DISCOVERY_ENV_VAR: &'static str = "MQTT_HA_DISCOVERY";
+    const HA_DISCOVERY_PREFIX_ENV_VAR: &'static str = "MQTT_HA_DISCOVERY_PREFIX";
+
+    /// Returns `None` if MQTT publishing isn't configured.
+    pub fn from_env() -> Result<Option<Self>> {
+        let host: String = match optional_var(Self::HOST_ENV_VAR)? {
+            Some(host) => host,
+            None => return Ok(None),
+        };
+
+        let tls = var_or(Self::TLS_ENV_VAR, false)?;
+        let port = var_or(Self::PORT_ENV_VAR, if tls { 8883 } else { 1883 })?;
+
+        let device_name = config::hostname()?;
+        let device_id = format!("rpi_client_temp_{}", config::machine_id()?);
+
+        let client_id = match optional_var(Self::CLIENT_ID_ENV_VAR)? {
+            Some(client_id) => client_id,
+            None => format!("rpi_client_temp-{device_name}"),
+        };
+        let topic_prefix = var_or(
+            Self::TOPIC_PREFIX_ENV_VAR,
+            format!("rpi_client_temp/{client_id}"),
+        )?;
+
+        let qos = match var_or(Self::QOS_ENV_VAR, 1u8)? {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            2 => QoS::ExactlyOnce,
+            qos => return Err(anyhow!("Invalid MQTT QoS {qos}, must be 0, 1 or 2.")),
+        };
+
+        Ok(Some(Self {
+            host,
+            port,
+            client_id,
+            topic_prefix,
+            qos,
+            retain: var_or(Self::RETAIN_ENV_VAR, false)?,
+            username: optional_var(Self::USERNAME_ENV_VAR)?,
+            password: optional_var(Self::PASSWORD_ENV_VAR)?,
+            tls,
+            ca_file: optional_var(Self::CA_FILE_ENV_VAR)?,
+            client_cert_file: optional_var(Self::CLIENT_CERT_FILE_ENV_VAR)?,
+            client_key_file: optional_var(Self::CLIENT_KEY_FILE_ENV_VAR)?,
+            ha_discovery: var_or(Self::HA_DISCOVERY_ENV_VAR, false)?,
+            ha_discovery_prefix: var_or(
+                Self::HA_DISCOVERY_PREFIX_ENV_VAR,
+                "homeassistant".to_owned(),
+            )?,
+            device_id,
+            device_name,
+            node_id: config::node_id()?,
+        }))
+    }
+
+    fn mqtt_options(&self) -> Result<MqttOptions> {
+        let mut options = self.connection_options(&self.client_id)?;
+
+        // The broker publishes this on our behalf if we drop off without
+        // saying goodbye.
+        options.set_last_will(LastWill::new(
+            self.status_topic(),
+            MqttPublisher::STATUS_OFFLINE,
+            self.qos,
+            true,
+        ));
+
+        Ok(options)
+    }
+
+    /// Options for the collector's own connection to the same broker. It
+    /// needs a client ID of its own, the broker would otherwise drop the
+    /// publisher's connection when the collector connects.
+    pub fn collector_options(&self) -> Result<MqttOptions> {
+        self.connection_options(&format!("{}-collector", self.client_id))
+    }
+
+    fn connection_options(&self, client_id: &str) -> Result<MqttOptions> {
+        let mut options = MqttOptions::new(client_id, &self.host, self.port);
+        options.set_keep_alive(Duration::from_secs(30));
+
+        if let Some(username) = &self.username {
+            options.set_credentials(username, self.password.as_deref().unwrap_or_default());
+        }
+
+        if self.tls {
+            let client_auth = match (&self.client_cert_file, &self.client_key_file) {
+                (Some(cert), Some(key)) => Some((std::fs::read(cert)?, std::fs::read(key)?)),
+                (None, None) => None,
+                _ => {
+                    return Err(anyhow!(
+                        "Both {} and {} have to be set for MQTT client authentication.",
+                        Self::CLIENT_CERT_FILE_ENV_VAR,
+                        Self::CLIENT_KEY_FILE_ENV_VAR
+                    ))
+                }
+            };
+
+            let transport = match &self.ca_file {
+                Some(ca_file) => Transport::tls(std::fs::read(ca_file)?, client_auth, None),
+                None if client_auth.is_none() => {
+                    Transport::tls_with_config(TlsConfiguration::default())
+                }
+                None => {
+                    return Err(anyhow!(
+                        "{} has to be set when using MQTT client authentication.",
+                        Self::CA_FILE_ENV_VAR
+                    ))
+                }
+            };
+
+            options.set_transport(transport);
+        }
+
+        Ok(options)
+    }
+
+    fn status_topic(&self) -> String {
+        format!("{}/status", self.topic_prefix)
+    }
+
+    fn state_topic(&self) -> String {
+        format!("{}/state", self.topic_prefix)
+    }
+}
+
+pub struct MqttPublisher {
+    client: AsyncClient,
+    config: MqttConfig,
+    event_loop_task: JoinHandle<()>,
+}
+
+impl MqttPublisher {
+    const STATUS_ONLINE: &'static str = "online";
+    const STATUS_OFFLINE: &'static str = "offline";
+
+    const REQUEST_QUEUE_SIZE: usize = 64;
+    const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+    const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Sets up the client and spawns the task driving the connection.
+    /// Home Assistant discovery configs (if enabled) are published for each
+    /// of the `quantities`, and for the forecast if `forecast` is set.
+    pub fn start(
+        config: MqttConfig,
+        quantities: &[Quantity],
+        forecast: bool,
+    ) -> Result<MqttPublisher> {
+        let (client, event_loop) =
+            AsyncClient::new(config.mqtt_options()?, Self::REQUEST_QUEUE_SIZE);
+
+        log::info!(
+            "Publishing measurements to MQTT broker {}:{} under {}",
+            config.host,
+            config.port,
+            config.topic_prefix
+        );
+
+        let mut connect_messages = vec![(config.status_topic(), Self::STATUS_ONLINE.to_owned())];
+
+        if config.ha_discovery {
+            connect_messages.extend(discovery::discovery_messages(
+                &config, quantities, forecast,
+            )?);
+        }
+
+        let event_loop_task = tokio::spawn(Self::run_event_loop(
+            event_loop,
+            client.clone(),
+            connect_messages,
+            config.qos,
+        ));
+
+        Ok(MqttPublisher {
+            client,
+            config,
+            event_loop_task,
+        })
+    }
+
+    /// Drives the connection. `connect_messages` are (re)published, retained,
+    /// every time we (re)connect to the broker.
+    async fn run_event_loop(
+        mut event_loop: EventLoop,
+        client: AsyncClient,
+        connect_messages: Vec<(String, String)>,
+        qos: QoS,
+    ) {
+        loop {
+            match event_loop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    log::info!("Connected to MQTT broker.");
+
+                    for (topic, payload) in &connect_messages {
+                        if let Err(e) = client.try_publish(topic, qos, true, payload.clone()) {
+                            log::warn!("Failed to queue MQTT message for {topic}: {e}");
+                        }
+                    }
+                }
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                    log::info!("Disconnected from MQTT broker.");
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    // The event loop reconnects by itself on the next poll.
+                    log::warn!("MQTT connection error: {e}");
+                    time::sleep(Self::RECONNECT_DELAY).await;
+                }
+            }
+        }
+    }
+
+    /// Queues the measurement for publishing. This never blocks, if the broker
+    /// is unreachable for long enough that the queue fills up, the
+    /// measurement is dropped.
+    pub fn publish(&self, measurement: &InsertableMeasurement) {
+        let quantities = [
+            ("temperature", measurement.temperature),
+            ("pressure", measurement.pressure),
+            ("humidity", measurement.humidity),
+            ("light_level", measurement.light_level),
+        ];
+
+        for (quantity, value) in quantities {
+            if let Some(value) = value {
+                self.try_publish(
+                    &format!("{}/{quantity}", self.config.topic_prefix),
+                    value.to_string(),
+                );
+            }
+        }
+
+        let state = State {
+            node_id: Some(self.config.node_id.clone()),
+            measurement: measurement.clone(),
+        };
+        match serde_json::to_string(&state) {
+            Ok(state) => self.try_publish(&self.config.state_topic(), state),
+            Err(e) => log::error!("Failed to serialize MQTT state: {e}"),
+        }
+    }
+
+    /// Queues the forecast for publishing, as JSON on its own topic.
+    pub fn publish_forecast(&self, forecast: &Forecast) {
+        match serde_json::to_string(forecast) {
+            Ok(payload) => {
+                self.try_publish(&format!("{}/forecast", self.config.topic_prefix), payload)
+            }
+            Err(e) => log::error!("Failed to serialize MQTT forecast: {e}"),
+        }
+    }
+
+    fn try_publish(&self, topic: &str, payload: String) {
+        if let Err(e) = self
+            .client
+            .try_publish(topic, self.config.qos, self.config.retain, payload)
+        {
+            log::warn!("Failed to queue MQTT message for {topic}: {e}");
+        }
+    }
+
+    /// Marks the node as offline and waits (for a bounded amount of time) for
+    /// the queued messages to go out.
+    pub async fn shutdown(self) {
+        let status_res = self.client.try_publish(
+            self.config.status_topic(),
+            self.config.qos,
+            true,
+            Self::STATUS_OFFLINE,
+        );
+        let disconnect_res = self.client.try_disconnect();
+
+        if let Err(e) = status_res.and(disconnect_res) {
+            log::warn!("Failed to queue MQTT disconnect: {e}");
+        }
+
+        let mut event_loop_task = self.event_loop_task;
+        if time::timeout(Self::SHUTDOWN_TIMEOUT, &mut event_loop_task)
+            .await
+            .is_err()
+        {
+            log::warn!("Timed out flushing MQTT messages.");
+            event_loop_task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::Publish;
+
+    use std::collections::HashMap;
+
+    /// Publishes a measurement through the broker in `MQTT_TEST_BROKER`, as
+    /// `host` or `host:port`, and reads it back. Skipped if that isn't set.
+    #[tokio::test]
+    async fn publishes_measurements() {
+        let Ok(broker) = std::env::var("MQTT_TEST_BROKER") else {
+            return;
+        };
+        let (host, port) = match broker.split_once(':') {
+            Some((host, port)) => (host.to_owned(), port.parse().unwrap()),
+            None => (broker, 1883),
+        };
+
+        let client_id = format!("rpi_client_temp-test-{}", std::process::id());
+        let config = MqttConfig {
+            host,
+            port,
+            client_id: client_id.clone(),
+            topic_prefix: format!("rpi_client_temp/{client_id}"),
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            username: None,
+            password: None,
+            tls: false,
+            ca_file: None,
+            client_cert_file: None,
+            client_key_file: None,
+            ha_discovery: false,
+            ha_discovery_prefix: "homeassistant".to_owned(),
+            device_id: "rpi_client_temp_test".to_owned(),
+            device_name: "test".to_owned(),
+            node_id: "test-node".to_owned(),
+        };
+
+        let (subscriber, mut event_loop) =
+            AsyncClient::new(config.collector_options().unwrap(), 16);
+        subscriber
+            .subscribe(format!("{}/#", config.topic_prefix), QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        // Only publish once the subscription is in place.
+        loop {
+            if let Event::Incoming(Packet::SubAck(_)) = event_loop.poll().await.unwrap() {
+                break;
+            }
+        }
+
+        let publisher = MqttPublisher::start(config.clone(), &[], false).unwrap();
+        let measurement = InsertableMeasurement::example();
+        publisher.publish(&measurement);
+
+        let mut received = HashMap::new();
+        let receive = async {
+            while !received.contains_key(&config.state_topic()) {
+                if let Event::Incoming(Packet::Publish(Publish { topic, payload, .. })) =
+                    event_loop.poll().await.unwrap()
+                {
+                    received.insert(topic, String::from_utf8(payload.to_vec()).unwrap());
+                }
+            }
+        };
+        time::timeout(Duration::from_secs(10), receive)
+            .await
+            .unwrap();
+        publisher.shutdown().await;
+
+        let topic = |quantity| format!("{}/{quantity}", config.topic_prefix);
+        assert_eq!(received[&topic("temperature")], "21.456");
+        assert_eq!(received[&topic("pressure")], "101325");
+        assert!(!received.contains_key(&topic("humidity")));
+
+        let state = serde_json::from_str::<State>(&received[&config.state_topic()]).unwrap();
+        assert_eq!(state.node_id.as_deref(), Some("test-node"));
+        assert_eq!(state.measurement.temperature, measurement.temperature);
+        assert_eq!(*state.measurement.meas_time, *measurement.meas_time);
+    }
+}
@@ -0,0 +1,233 @@
+use anyhow::Result;
+use tokio::sync::watch;
+use tokio::task::{self, JoinHandle};
+use tokio::{select, time};
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::{Event, Notifier};
+use crate::alerts::AlertStatus;
+use crate::enviro_phat::leds::Leds;
+
+const SHORT_FLASH: Duration = Duration::from_millis(150);
+const LONG_FLASH: Duration = Duration::from_millis(600);
+
+/// The worst of what's wrong at the moment, which is what the LEDs show.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Ok,
+    AlertFiring,
+    SensorFailing,
+}
+
+impl Status {
+    /// The flashes, as how long the LEDs are on and then off for each.
+    fn pattern(self) -> &'static [(Duration, Duration)] {
+        match self {
+            Status::Ok => &[],
+            Status::AlertFiring => &[(LONG_FLASH, SHORT_FLASH)],
+            Status::SensorFailing => &[
+                (SHORT_FLASH, SHORT_FLASH),
+                (SHORT_FLASH, SHORT_FLASH),
+                (SHORT_FLASH, SHORT_FLASH),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Problems {
+    sensor_failing: bool,
+    firing_rules: HashSet<String>,
+}
+
+impl Problems {
+    fn status(&self) -> Status {
+        if self.sensor_failing {
+            Status::SensorFailing
+        } else if !self.firing_rules.is_empty() {
+            Status::AlertFiring
+        } else {
+            Status::Ok
+        }
+    }
+}
+
+/// Blinks the LEDs for as long as something's wrong: three short flashes
+/// while measuring fails, one long one while an alert is firing, repeated
+/// every few seconds. Alerts that were already firing before a restart don't
+/// count, there's no event for them.
+pub struct LedNotifier {
+    problems: Mutex<Problems>,
+    status_tx: watch::Sender<Status>,
+}
+
+impl LedNotifier {
+    const PAUSE: Duration = Duration::from_secs(3);
+
+    /// Starts blinking in the background, until shutdown. The LEDs are left
+    /// off.
+    pub fn start(
+        leds: Arc<Leds>,
+        shutdown: watch::Receiver<bool>,
+    ) -> (LedNotifier, JoinHandle<Result<()>>) {
+        let (status_tx, status_rx) = watch::channel(Status::Ok);
+        let task = tokio::spawn(Self::run(leds, status_rx, shutdown));
+
+        let notifier = LedNotifier {
+            problems: Mutex::default(),
+            status_tx,
+        };
+
+        (notifier, task)
+    }
+
+    async fn run(
+        leds: Arc<Leds>,
+        mut status_rx: watch::Receiver<Status>,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> Result<()> {
+        loop {
+            let status = *status_rx.borrow_and_update();
+            if status != Status::Ok {
+                let leds = leds.clone();
+                // A hiccup on the line mustn't end the blinking for good,
+                // it's tried again after the pause.
+                if let Err(e) = task::spawn_blocking(move || leds.blink(status.pattern())).await? {
+                    log::error!("Failed to blink the LEDs: {e:#}");
+                }
+            }
+
+            select! {
+                _ = time::sleep(Self::PAUSE), if status != Status::Ok => {}
+                res = status_rx.changed() => {
+                    if res.is_err() {
+                        break;
+                    }
+                }
+                _ = shutdown.wait_for(|&shutdown| shutdown) => break,
+            }
+        }
+
+        task::spawn_blocking(move || leds.off()).await?
+    }
+}
+
+impl Notifier for LedNotifier {
+    fn notify(&self, event: &Event) {
+        let mut problems = self.problems.lock().unwrap();
+
+        match event {
+            Event::Alert(alert) => match alert.status {
+                AlertStatus::Firing => {
+                    problems.firing_rules.insert(alert.rule.clone());
+                }
+                AlertStatus::Resolved => {
+                    problems.firing_rules.remove(&alert.rule);
+                }
+            },
+            Event::SensorFailure { .. } => problems.sensor_failing = true,
+            Event::SensorRecovered { .. } => problems.sensor_failing = false,
+        }
+
+        let status = problems.status();
+        self.status_tx.send_if_modified(|current| {
+            let modified = *current != status;
+            *current = status;
+            modified
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::Ordering;
+
+    use crate::alerts::AlertEvent;
+    use crate::db::DateTimeUtc;
+    use crate::enviro_phat::leds::MockLed;
+
+    fn alert(rule: &str, status: AlertStatus) -> Event {
+        Event::Alert(AlertEvent {
+            rule: rule.to_owned(),
+            status,
+            quantity: "temperature",
+            value: 30.0,
+            threshold: 28.0,
+            unit: "°C",
+            time: DateTimeUtc::now(),
+            message: String::new(),
+        })
+    }
+
+    fn sensor_failure() -> Event {
+        Event::SensorFailure {
+            time: DateTimeUtc::now(),
+            error: "Failed to read".to_owned(),
+        }
+    }
+
+    fn sensor_recovered() -> Event {
+        Event::SensorRecovered {
+            time: DateTimeUtc::now(),
+            failures: 1,
+        }
+    }
+
+    #[test]
+    fn status_priority() {
+        let (status_tx, status_rx) = watch::channel(Status::Ok);
+        let notifier = LedNotifier {
+            problems: Mutex::default(),
+            status_tx,
+        };
+
+        let steps = [
+            (alert("hot", AlertStatus::Firing), Status::AlertFiring),
+            (sensor_failure(), Status::SensorFailing),
+            (alert("hot", AlertStatus::Resolved), Status::SensorFailing),
+            (alert("humid", AlertStatus::Firing), Status::SensorFailing),
+            (sensor_recovered(), Status::AlertFiring),
+            (alert("humid", AlertStatus::Resolved), Status::Ok),
+        ];
+
+        for (event, status) in steps {
+            notifier.notify(&event);
+            assert_eq!(*status_rx.borrow(), status, "after {event:?}");
+        }
+
+        assert!(Status::Ok.pattern().is_empty());
+        assert_eq!(Status::AlertFiring.pattern().len(), 1);
+        assert_eq!(Status::SensorFailing.pattern().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn keeps_blinking_after_a_failure() {
+        let led = MockLed::default();
+        led.failures.store(1, Ordering::SeqCst);
+        let leds = Arc::new(Leds::new(Box::new(led.clone()), false));
+
+        let (status_tx, status_rx) = watch::channel(Status::Ok);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let task = tokio::spawn(LedNotifier::run(leds, status_rx, shutdown_rx));
+
+        status_tx.send(Status::SensorFailing).unwrap();
+        while led.failures.load(Ordering::SeqCst) > 0 {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        status_tx.send(Status::AlertFiring).unwrap();
+        while led.switches.lock().unwrap().len() < 2 {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        shutdown_tx.send(true).unwrap();
+        task.await.unwrap().unwrap();
+
+        assert_eq!(*led.switches.lock().unwrap(), [true, false, false]);
+    }
+}
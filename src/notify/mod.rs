@@ -0,0 +1,85 @@
+use serde::Serialize;
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::alerts::{AlertEvent, AlertStatus};
+use crate::db::DateTimeUtc;
+
+pub mod leds;
+pub mod webhook;
+
+/// Something that happened that someone may want to hear about.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Event {
+    Alert(AlertEvent),
+    /// A measurement cycle failed after the last one had succeeded.
+    SensorFailure {
+        time: DateTimeUtc,
+        error: String,
+    },
+    /// A measurement cycle succeeded after `failures` ones that didn't.
+    SensorRecovered {
+        time: DateTimeUtc,
+        failures: u64,
+    },
+}
+
+impl Event {
+    /// Whether something went wrong, rather than back to normal.
+    pub fn is_problem(&self) -> bool {
+        match self {
+            Event::Alert(alert) => alert.status == AlertStatus::Firing,
+            Event::SensorFailure { .. } => true,
+            Event::SensorRecovered { .. } => false,
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Alert(alert) => write!(f, "{alert}"),
+            Event::SensorFailure { error, .. } => write!(f, "Measuring failed: {error}"),
+            Event::SensorRecovered { failures, .. } => {
+                write!(f, "Measuring works again after {failures} failed attempts")
+            }
+        }
+    }
+}
+
+/// Somewhere events go. Notifying must not block, it's done from the
+/// measurement loop.
+pub trait Notifier: Send + Sync {
+    fn notify(&self, event: &Event);
+}
+
+/// Logs problems as warnings and everything else as info.
+pub struct LogNotifier;
+
+impl Notifier for LogNotifier {
+    fn notify(&self, event: &Event) {
+        if event.is_problem() {
+            log::warn!("{event}");
+        } else {
+            log::info!("{event}");
+        }
+    }
+}
+
+/// All the configured notifiers, an event is sent to each of them.
+#[derive(Clone)]
+pub struct Notifiers(Vec<Arc<dyn Notifier>>);
+
+impl Notifiers {
+    pub fn new(notifiers: Vec<Arc<dyn Notifier>>) -> Self {
+        Notifiers(notifiers)
+    }
+
+    pub fn notify(&self, event: &Event) {
+        for notifier in &self.0 {
+            notifier.notify(event);
+        }
+    }
+}
@@ -0,0 +1,620 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use diesel::prelude::*;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, StatusCode};
+use ring::hmac;
+use serde_json::{Map, Value};
+use tokio::sync::{mpsc, watch};
+use tokio::task::{self, JoinHandle, JoinSet};
+use tokio::{select, time};
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::{Event, Notifier};
+use crate::config::{self, optional_var, var_or};
+use crate::db::schema::webhook_dead_letters;
+use crate::db::{DateTimeUtc, DbConnection};
+use crate::metrics::METRICS;
+
+const SIGNATURE_HEADER: &str = "X-Signature-256";
+/// When the request was signed, in seconds since the Unix epoch. It's part of
+/// what's signed, so a receiver can turn away requests that are replayed
+/// later.
+const TIMESTAMP_HEADER: &str = "X-Signature-Timestamp";
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    urls: Vec<String>,
+    secret: Option<String>,
+    template: Option<Value>,
+    max_attempts: u32,
+    /// How long to wait before the first retry, doubled for every one after.
+    min_retry_delay: Duration,
+}
+
+impl WebhookConfig {
+    const URLS_ENV_VAR: &'static str = "WEBHOOK_URLS";
+    const SECRET_ENV_VAR: &'static str = "WEBHOOK_SECRET";
+    const TEMPLATE_PATH_ENV_VAR: &'static str = "WEBHOOK_TEMPLATE_PATH";
+    const MAX_ATTEMPTS_ENV_VAR: &'static str = "WEBHOOK_MAX_ATTEMPTS";
+
+    /// Returns `None` if there are no URLs to post to.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(urls) = optional_var::<String>(Self::URLS_ENV_VAR)? else {
+            return Ok(None);
+        };
+        let urls = urls
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        if urls.is_empty() {
+            return Ok(None);
+        }
+
+        let template = optional_var::<PathBuf>(Self::TEMPLATE_PATH_ENV_VAR)?
+            .map(|path| -> Result<Value> {
+                let template = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                serde_json::from_str(&template)
+                    .with_context(|| format!("Invalid webhook template in {}", path.display()))
+            })
+            .transpose()?;
+
+        let max_attempts = var_or(Self::MAX_ATTEMPTS_ENV_VAR, 8)?;
+        if max_attempts == 0 {
+            return Err(anyhow!(
+                "{} has to be at least 1.",
+                Self::MAX_ATTEMPTS_ENV_VAR
+            ));
+        }
+
+        Ok(Some(Self {
+            urls,
+            secret: optional_var(Self::SECRET_ENV_VAR)?,
+            template,
+            max_attempts,
+            min_retry_delay: WebhookNotifier::MIN_RETRY_DELAY,
+        }))
+    }
+
+    /// The JSON posted for the event: the event itself with the node and a
+    /// human readable message added, or the template filled in with those
+    /// fields.
+    fn body(&self, event: &Event, node: &str) -> Result<String> {
+        let Value::Object(mut fields) = serde_json::to_value(event)? else {
+            return Err(anyhow!("Events are JSON objects."));
+        };
+        fields.insert("node".to_owned(), Value::from(node));
+        fields.insert("message".to_owned(), Value::from(event.to_string()));
+
+        let body = match &self.template {
+            Some(template) => fill(template, &fields),
+            None => Value::Object(fields),
+        };
+
+        Ok(body.to_string())
+    }
+
+    /// `sha256=` and the hex HMAC-SHA256 of the timestamp, a `.` and the
+    /// body, like GitHub signs its webhooks but with the time included as
+    /// Slack and Stripe do.
+    fn signature(&self, timestamp: i64, body: &str) -> Option<String> {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, self.secret.as_ref()?.as_bytes());
+        let tag = hmac::sign(&key, format!("{timestamp}.{body}").as_bytes());
+
+        let hex = tag
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+
+        Some(format!("sha256={hex}"))
+    }
+}
+
+/// Replaces the `{{field}}` placeholders in the template's strings. A string
+/// that's nothing but a placeholder becomes the field's JSON value, so that
+/// numbers stay numbers; elsewhere the value is put into the text.
+fn fill(template: &Value, fields: &Map<String, Value>) -> Value {
+    match template {
+        Value::String(text) => {
+            let whole = text
+                .strip_prefix("{{")
+                .and_then(|name| name.strip_suffix("}}"))
+                .filter(|name| !name.contains("{{"));
+            if let Some(name) = whole {
+                return fields.get(name.trim()).cloned().unwrap_or(Value::Null);
+            }
+
+            let mut filled = String::new();
+            let mut rest = text.as_str();
+            while let Some((before, after)) = rest.split_once("{{") {
+                let Some((name, after)) = after.split_once("}}") else {
+                    break;
+                };
+
+                filled.push_str(before);
+                match fields.get(name.trim()) {
+                    Some(Value::String(value)) => filled.push_str(value),
+                    Some(Value::Null) | None => {}
+                    Some(value) => filled.push_str(&value.to_string()),
+                }
+                rest = after;
+            }
+            filled.push_str(rest);
+
+            Value::String(filled)
+        }
+        Value::Array(items) => Value::Array(items.iter().map(|item| fill(item, fields)).collect()),
+        Value::Object(members) => Value::Object(
+            members
+                .iter()
+                .map(|(key, value)| (key.clone(), fill(value, fields)))
+                .collect(),
+        ),
+        value => value.clone(),
+    }
+}
+
+/// Why a delivery attempt failed, and whether trying again could help.
+struct DeliveryError {
+    error: anyhow::Error,
+    retry: bool,
+}
+
+impl From<reqwest::Error> for DeliveryError {
+    fn from(err: reqwest::Error) -> Self {
+        DeliveryError {
+            error: err.into(),
+            retry: true,
+        }
+    }
+}
+
+/// A delivery of one event to one URL.
+struct Delivery {
+    url: String,
+    body: String,
+    created_at: DateTimeUtc,
+}
+
+/// Posts events to the configured URLs in the background, retrying failed
+/// deliveries with exponential backoff. Deliveries that are given up on are
+/// stored in the `webhook_dead_letters` table.
+pub struct WebhookNotifier {
+    event_tx: mpsc::Sender<Event>,
+}
+
+impl WebhookNotifier {
+    const CHANNEL_SIZE: usize = 64;
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+    const MIN_RETRY_DELAY: Duration = Duration::from_secs(5);
+    const MAX_RETRY_DELAY: Duration = Duration::from_secs(600);
+
+    /// Starts delivering, until shutdown. The task's done once all the
+    /// deliveries have either gone through or been dead-lettered.
+    pub fn start(
+        config: WebhookConfig,
+        db_conn: Arc<Mutex<DbConnection>>,
+        shutdown: watch::Receiver<bool>,
+    ) -> Result<(WebhookNotifier, JoinHandle<Result<()>>)> {
+        let client = Client::builder().timeout(Self::REQUEST_TIMEOUT).build()?;
+        let node = config::hostname()?;
+
+        log::info!("Posting events to {} webhooks", config.urls.len());
+
+        let (event_tx, event_rx) = mpsc::channel(Self::CHANNEL_SIZE);
+        let task = tokio::spawn(Self::run(
+            Arc::new(config),
+            client,
+            node,
+            db_conn,
+            event_rx,
+            shutdown,
+        ));
+
+        Ok((WebhookNotifier { event_tx }, task))
+    }
+
+    async fn run(
+        config: Arc<WebhookConfig>,
+        client: Client,
+        node: String,
+        db_conn: Arc<Mutex<DbConnection>>,
+        mut event_rx: mpsc::Receiver<Event>,
+        shutdown: watch::Receiver<bool>,
+    ) -> Result<()> {
+        let mut deliveries = JoinSet::new();
+        let mut shutdown_signal = shutdown.clone();
+
+        let spawn_deliveries = |deliveries: &mut JoinSet<()>, event: Event| {
+            let body = match config.body(&event, &node) {
+                Ok(body) => body,
+                Err(e) => {
+                    log::error!("Failed to make a webhook body for {event}: {e:#}");
+                    return;
+                }
+            };
+
+            for url in &config.urls {
+                let delivery = Delivery {
+                    url: url.clone(),
+                    body: body.clone(),
+                    created_at: DateTimeUtc::now(),
+                };
+                deliveries.spawn(Self::deliver(
+                    config.clone(),
+                    client.clone(),
+                    delivery,
+                    db_conn.clone(),
+                    shutdown.clone(),
+                ));
+            }
+        };
+
+        loop {
+            select! {
+                event = event_rx.recv() => match event {
+                    Some(event) => spawn_deliveries(&mut deliveries, event),
+                    None => break,
+                },
+                Some(_) = deliveries.join_next(), if !deliveries.is_empty() => {}
+                _ = shutdown_signal.wait_for(|&shutdown| shutdown) => break,
+            }
+        }
+
+        // What's still queued gets one attempt. Like the deliveries waiting
+        // to retry, it's dead-lettered right away if that fails.
+        while let Ok(event) = event_rx.try_recv() {
+            spawn_deliveries(&mut deliveries, event);
+        }
+        while deliveries.join_next().await.is_some() {}
+
+        Ok(())
+    }
+
+    /// Posts the delivery until it's accepted, giving up after the configured
+    /// number of attempts, as soon as it's rejected for good, or on shutdown.
+    async fn deliver(
+        config: Arc<WebhookConfig>,
+        client: Client,
+        delivery: Delivery,
+        db_conn: Arc<Mutex<DbConnection>>,
+        mut shutdown: watch::Receiver<bool>,
+    ) {
+        let mut retry_delay = config.min_retry_delay;
+        let mut attempts = 0;
+
+        let error = loop {
+            attempts += 1;
+
+            let e = match Self::post(&config, &client, &delivery).await {
+                Ok(()) => {
+                    METRICS.webhook_deliveries.inc();
+                    return;
+                }
+                Err(e) if !e.retry || attempts >= config.max_attempts => break e.error,
+                Err(e) => e.error,
+            };
+
+            log::warn!(
+                "Webhook delivery to {} failed, retrying in {retry_delay:?}: {e:#}",
+                delivery.url
+            );
+            select! {
+                _ = time::sleep(retry_delay) => {}
+                _ = shutdown.wait_for(|&shutdown| shutdown) => {
+                    break e.context("Shut down before retrying");
+                }
+            }
+            retry_delay = (retry_delay * 2).min(Self::MAX_RETRY_DELAY);
+        };
+
+        log::error!(
+            "Giving up on webhook delivery to {} after {attempts} attempts: {error:#}",
+            delivery.url
+        );
+        METRICS.webhook_dead_letters.inc();
+
+        let result = task::spawn_blocking(move || {
+            diesel::insert_into(webhook_dead_letters::table)
+                .values((
+                    webhook_dead_letters::url.eq(&delivery.url),
+                    webhook_dead_letters::body.eq(&delivery.body),
+                    webhook_dead_letters::attempts.eq(attempts as i32),
+                    webhook_dead_letters::error.eq(format!("{error:#}")),
+                    webhook_dead_letters::created_at.eq(&delivery.created_at),
+                    webhook_dead_letters::failed_at.eq(DateTimeUtc::now()),
+                ))
+                .execute(&mut *db_conn.lock().unwrap())
+        })
+        .await;
+
+        match result {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => log::error!("Failed to store dead webhook delivery: {e}"),
+            Err(e) => log::error!("Dead webhook delivery task failed: {e}"),
+        }
+    }
+
+    async fn post(
+        config: &WebhookConfig,
+        client: &Client,
+        delivery: &Delivery,
+    ) -> Result<(), DeliveryError> {
+        let mut request = client
+            .post(&delivery.url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(delivery.body.clone());
+        // Signed again for every attempt, a retry isn't a replay.
+        let timestamp = Utc::now().timestamp();
+        if let Some(signature) = config.signature(timestamp, &delivery.body) {
+            request = request
+                .header(SIGNATURE_HEADER, signature)
+                .header(TIMESTAMP_HEADER, timestamp);
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+
+        let body = response.text().await.unwrap_or_default();
+        Err(DeliveryError {
+            error: anyhow!("The webhook responded with {status}: {body}"),
+            // Anything else is a problem with the request, which won't go
+            // away by sending it again.
+            retry: status.is_server_error()
+                || status == StatusCode::REQUEST_TIMEOUT
+                || status == StatusCode::TOO_MANY_REQUESTS,
+        })
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify(&self, event: &Event) {
+        if let Err(e) = self.event_tx.try_send(event.clone()) {
+            log::warn!("Failed to queue webhook event: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_connection;
+    use axum::extract::State;
+    use axum::http::HeaderMap;
+    use axum::routing::post;
+    use axum::Router;
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use std::time::Instant;
+
+    fn config(secret: Option<&str>) -> WebhookConfig {
+        WebhookConfig {
+            urls: vec!["http://localhost/hook".to_owned()],
+            secret: secret.map(str::to_owned),
+            template: None,
+            max_attempts: 1,
+            min_retry_delay: WebhookNotifier::MIN_RETRY_DELAY,
+        }
+    }
+
+    fn fields() -> Map<String, Value> {
+        let Value::Object(fields) = json!({
+            "rule": "hot",
+            "value": 31.5,
+            "node": "pi",
+            "unit": null,
+        }) else {
+            unreachable!()
+        };
+
+        fields
+    }
+
+    #[test]
+    fn whole_placeholders_keep_the_json_type() {
+        let template = json!({"value": "{{value}}", "rule": "{{ rule }}", "unit": "{{unit}}"});
+
+        assert_eq!(
+            fill(&template, &fields()),
+            json!({"value": 31.5, "rule": "hot", "unit": null})
+        );
+    }
+
+    #[test]
+    fn placeholders_in_text_are_replaced() {
+        let template = json!({"text": "{{rule}} on {{node}}: {{value}}{{unit}}{{missing}}!"});
+
+        assert_eq!(
+            fill(&template, &fields()),
+            json!({"text": "hot on pi: 31.5!"})
+        );
+    }
+
+    #[test]
+    fn fills_nested_templates() {
+        let template = json!({
+            "blocks": [{"text": "{{rule}}", "count": 1}, "{{node}}"],
+            "flag": true,
+        });
+
+        assert_eq!(
+            fill(&template, &fields()),
+            json!({"blocks": [{"text": "hot", "count": 1}, "pi"], "flag": true})
+        );
+    }
+
+    #[test]
+    fn unclosed_placeholders_are_left_alone() {
+        let template = json!("{{rule}} and {{node");
+
+        assert_eq!(fill(&template, &fields()), json!("hot and {{node"));
+    }
+
+    #[test]
+    fn signs_the_timestamp_and_body() {
+        assert_eq!(
+            config(Some("secret")).signature(1_700_000_000, r#"{"rule":"hot"}"#),
+            Some(
+                "sha256=b12accefdd5ae0f75f0936f403dfb4a33ecbadf8d55ae5d6e8efcf05f9e8cb31"
+                    .to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn signature_changes_with_the_timestamp() {
+        let config = config(Some("secret"));
+
+        assert_ne!(
+            config.signature(1_700_000_000, "{}"),
+            config.signature(1_700_000_001, "{}")
+        );
+    }
+
+    #[test]
+    fn no_signature_without_a_secret() {
+        assert_eq!(config(None).signature(1_700_000_000, "{}"), None);
+    }
+
+    /// What the stand-in receiver got, and how many more requests it fails.
+    #[derive(Default)]
+    struct StandIn {
+        requests: Vec<(Instant, HeaderMap, String)>,
+        failures: usize,
+        status: u16,
+    }
+
+    /// Serves a webhook on a free local port that responds to the first
+    /// `failures` requests with `status`, returning its URL.
+    async fn stand_in(failures: usize, status: u16) -> (String, Arc<Mutex<StandIn>>) {
+        let stand_in = Arc::new(Mutex::new(StandIn {
+            failures,
+            status,
+            ..StandIn::default()
+        }));
+
+        async fn hook(
+            State(stand_in): State<Arc<Mutex<StandIn>>>,
+            headers: HeaderMap,
+            body: String,
+        ) -> StatusCode {
+            let mut stand_in = stand_in.lock().unwrap();
+            stand_in.requests.push((Instant::now(), headers, body));
+
+            if stand_in.failures > 0 {
+                stand_in.failures -= 1;
+                StatusCode::from_u16(stand_in.status).unwrap()
+            } else {
+                StatusCode::NO_CONTENT
+            }
+        }
+
+        let app = Router::new()
+            .route("/hook", post(hook))
+            .with_state(stand_in.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        (url, stand_in)
+    }
+
+    /// Delivers `{"rule":"hot"}` to the URL, returning the dead letters.
+    async fn deliver(url: String, max_attempts: u32) -> Vec<(String, String, i32, String)> {
+        let config = WebhookConfig {
+            urls: vec![url.clone()],
+            max_attempts,
+            min_retry_delay: Duration::from_millis(50),
+            ..config(Some("secret"))
+        };
+        let delivery = Delivery {
+            url,
+            body: r#"{"rule":"hot"}"#.to_owned(),
+            created_at: DateTimeUtc::now(),
+        };
+        let db_conn = Arc::new(Mutex::new(test_connection()));
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+
+        WebhookNotifier::deliver(
+            Arc::new(config),
+            Client::new(),
+            delivery,
+            db_conn.clone(),
+            shutdown_rx,
+        )
+        .await;
+
+        let mut conn = db_conn.lock().unwrap();
+        webhook_dead_letters::table
+            .select((
+                webhook_dead_letters::url,
+                webhook_dead_letters::body,
+                webhook_dead_letters::attempts,
+                webhook_dead_letters::error,
+            ))
+            .load(&mut *conn)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn retries_with_backoff_until_delivered() {
+        let (url, stand_in) = stand_in(2, 503).await;
+
+        assert!(deliver(url, 5).await.is_empty());
+
+        let stand_in = stand_in.lock().unwrap();
+        assert_eq!(stand_in.requests.len(), 3);
+        let delays = stand_in
+            .requests
+            .windows(2)
+            .map(|pair| pair[1].0 - pair[0].0)
+            .collect::<Vec<_>>();
+        assert!(delays[0] >= Duration::from_millis(50), "{delays:?}");
+        assert!(delays[1] >= Duration::from_millis(100), "{delays:?}");
+
+        for (_, headers, body) in &stand_in.requests {
+            assert_eq!(body, r#"{"rule":"hot"}"#);
+            let timestamp = headers[TIMESTAMP_HEADER].to_str().unwrap().parse().unwrap();
+            assert_eq!(
+                headers[SIGNATURE_HEADER].to_str().unwrap(),
+                config(Some("secret")).signature(timestamp, body).unwrap()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn dead_letters_after_the_last_attempt() {
+        let (url, stand_in) = stand_in(usize::MAX, 500).await;
+
+        let dead_letters = deliver(url.clone(), 2).await;
+        assert_eq!(stand_in.lock().unwrap().requests.len(), 2);
+        assert_eq!(dead_letters.len(), 1);
+
+        let (dead_url, body, attempts, error) = &dead_letters[0];
+        assert_eq!(*dead_url, url);
+        assert_eq!(body, r#"{"rule":"hot"}"#);
+        assert_eq!(*attempts, 2);
+        assert!(error.contains("500"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn rejected_deliveries_arent_retried() {
+        let (url, stand_in) = stand_in(usize::MAX, 400).await;
+
+        let dead_letters = deliver(url, 5).await;
+        assert_eq!(stand_in.lock().unwrap().requests.len(), 1);
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].2, 1);
+    }
+}
@@ -0,0 +1,333 @@
+use anyhow::Result;
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Integer};
+use tokio::sync::watch;
+use tokio::{select, task, time};
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::{optional_var, var_or};
+use crate::db::{DateTimeUtc, DbConnection, UtcTime, LOCAL_NODE_ID};
+use crate::metrics::METRICS;
+
+const DAY_US: i64 = 24 * 3_600 * 1_000_000;
+
+#[derive(Debug, Clone)]
+pub struct RetentionConfig {
+    raw_days: Option<i64>,
+    hourly_days: Option<i64>,
+    daily_days: Option<i64>,
+    interval: Duration,
+    batch_size: i64,
+    /// Whether this node's measurements are uploaded to a collector, in which
+    /// case they're kept until they have been.
+    uploading: bool,
+}
+
+impl RetentionConfig {
+    const RAW_DAYS_ENV_VAR: &'static str = "RETENTION_RAW_DAYS";
+    const HOURLY_DAYS_ENV_VAR: &'static str = "RETENTION_HOURLY_DAYS";
+    const DAILY_DAYS_ENV_VAR: &'static str = "RETENTION_DAILY_DAYS";
+    const INTERVAL_ENV_VAR: &'static str = "RETENTION_INTERVAL_SECS";
+    const BATCH_SIZE_ENV_VAR: &'static str = "RETENTION_BATCH_SIZE";
+
+    /// Returns `None` if nothing is ever to be deleted. A resolution without
+    /// a retention period is kept forever.
+    pub fn from_env(uploading: bool) -> Result<Option<Self>> {
+        let raw_days = optional_var(Self::RAW_DAYS_ENV_VAR)?;
+        let hourly_days = optional_var(Self::HOURLY_DAYS_ENV_VAR)?;
+        let daily_days = optional_var(Self::DAILY_DAYS_ENV_VAR)?;
+
+        if raw_days.is_none() && hourly_days.is_none() && daily_days.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            raw_days,
+            hourly_days,
+            daily_days,
+            interval: Duration::from_secs(var_or(Self::INTERVAL_ENV_VAR, 3600)?),
+            batch_size: var_or(Self::BATCH_SIZE_ENV_VAR, 1000)?,
+            uploading,
+        }))
+    }
+
+    /// Before when raw measurements are pruned, if they are at all.
+    pub fn raw_cutoff(&self) -> Option<DateTimeUtc> {
+        cutoff(DateTimeUtc::now().timestamp_micros(), self.raw_days?, 0)
+    }
+
+    /// Which readouts may be deleted once they're old enough. The ones still
+    /// waiting for the clock are kept, their time is going to change, and so
+    /// are the ones that haven't been uploaded yet, unless the collector has
+    /// rejected them.
+    fn expendable_readouts(&self) -> String {
+        if self.uploading {
+            format!(
+                "clock_synced AND (node_id <> '{LOCAL_NODE_ID}' OR uploaded_at IS NOT NULL \
+                 OR upload_error IS NOT NULL)"
+            )
+        } else {
+            "clock_synced".to_owned()
+        }
+    }
+
+    /// The tables to prune as (table, time column, bucket length in µs,
+    /// retention in days, condition for deleting a row). A bucket only
+    /// expires once all of it is too old.
+    fn tables(&self) -> [(&'static str, &'static str, i64, Option<i64>, String); 5] {
+        let readouts = self.expendable_readouts();
+
+        [
+            // Readings before their readouts, which they refer to.
+            (
+                "readings",
+                "time",
+                0,
+                self.raw_days,
+                format!("readout_id IN (SELECT id FROM readouts WHERE {readouts})"),
+            ),
+            ("readouts", "meas_time", 0, self.raw_days, readouts),
+            ("forecasts", "time", 0, self.raw_days, "TRUE".to_owned()),
+            (
+                "measurements_hourly",
+                "bucket_start",
+                3_600 * 1_000_000,
+                self.hourly_days,
+                "TRUE".to_owned(),
+            ),
+            (
+                "measurements_daily",
+                "bucket_start",
+                DAY_US,
+                self.daily_days,
+                "TRUE".to_owned(),
+            ),
+        ]
+    }
+}
+
+/// Before when rows of buckets `bucket_us` long expire after `days`, rounded
+/// down to a whole day, so that raw rows are pruned a day at a time.
+fn cutoff(now: i64, days: i64, bucket_us: i64) -> Option<DateTimeUtc> {
+    DateTimeUtc::from_micros((now - days * DAY_US - bucket_us).div_euclid(DAY_US) * DAY_US)
+}
+
+#[derive(QueryableByName)]
+struct AutoVacuum {
+    #[diesel(sql_type = Integer)]
+    auto_vacuum: i32,
+}
+
+/// Switches the DB to incremental auto-vacuum, so that the pages freed by
+/// pruning can be given back to the file system. That only takes effect after
+/// a full `VACUUM`, which is done once here. It can take a while for a big
+/// DB, so this is to be called at startup before anything else uses the
+/// connection. Postgres' autovacuum takes care of this by itself.
+pub fn enable_incremental_vacuum(conn: &mut DbConnection) -> QueryResult<()> {
+    const INCREMENTAL: i32 = 2;
+
+    let DbConnection::Sqlite(conn) = conn else {
+        return Ok(());
+    };
+
+    let mode = diesel::sql_query("PRAGMA auto_vacuum").get_result::<AutoVacuum>(conn)?;
+    if mode.auto_vacuum != INCREMENTAL {
+        log::info!("Enabling incremental auto-vacuum, this rewrites the whole DB once.");
+        let start = Instant::now();
+        conn.batch_execute("PRAGMA auto_vacuum = INCREMENTAL; VACUUM;")?;
+        log::info!("Vacuumed the DB in {:.1?}.", start.elapsed());
+    }
+
+    Ok(())
+}
+
+/// Deletes up to `limit` rows from the table whose key is below `cutoff` and
+/// that meet `condition`, returning how many were deleted.
+fn delete_batch(
+    conn: &mut DbConnection,
+    table: &str,
+    key: &str,
+    condition: &str,
+    cutoff: &DateTimeUtc,
+    limit: i64,
+) -> QueryResult<usize> {
+    // The key isn't unique, the rows that share it with a deleted one have to
+    // meet the condition as well.
+    conn.sql_query(&format!(
+        "DELETE FROM {table} WHERE {key} < ?1 AND {condition} AND {key} IN \
+         (SELECT {key} FROM {table} WHERE {key} < ?1 AND {condition} LIMIT ?2)"
+    ))
+    .bind::<UtcTime, _>(cutoff)
+    .bind::<BigInt, _>(limit)
+    .execute(conn)
+}
+
+/// Prunes everything that's expired, a batch at a time so that the
+/// measurements don't have to wait for the DB for long. Stops early on
+/// shutdown.
+async fn prune(
+    config: &RetentionConfig,
+    db_conn: &Arc<Mutex<DbConnection>>,
+    shutdown: &watch::Receiver<bool>,
+) -> Result<()> {
+    const BATCH_PAUSE: Duration = Duration::from_millis(100);
+
+    let now = DateTimeUtc::now().timestamp_micros();
+
+    for (table, key, bucket_us, days, condition) in config.tables() {
+        let Some(days) = days else {
+            continue;
+        };
+        // The readouts that are kept back leave the days before the cutoff
+        // only partly pruned, which is why rollups aren't rebuilt there.
+        let Some(cutoff) = cutoff(now, days, bucket_us) else {
+            continue;
+        };
+        let mut table_deleted = 0;
+
+        loop {
+            if *shutdown.borrow() {
+                return Ok(());
+            }
+
+            let db_conn = db_conn.clone();
+            let cutoff = cutoff.clone();
+            let condition = condition.clone();
+            let limit = config.batch_size;
+            let deleted = task::spawn_blocking(move || {
+                delete_batch(
+                    &mut db_conn.lock().unwrap(),
+                    table,
+                    key,
+                    &condition,
+                    &cutoff,
+                    limit,
+                )
+            })
+            .await??;
+
+            table_deleted += deleted;
+            METRICS.pruned_rows.add(deleted as u64);
+
+            if deleted < config.batch_size as usize {
+                break;
+            }
+            time::sleep(BATCH_PAUSE).await;
+        }
+
+        if table_deleted > 0 {
+            log::info!("Pruned {table_deleted} rows from {table}.");
+        }
+    }
+
+    let db_conn = db_conn.clone();
+    task::spawn_blocking(move || match &mut *db_conn.lock().unwrap() {
+        DbConnection::Sqlite(conn) => conn.batch_execute("PRAGMA incremental_vacuum"),
+        DbConnection::Postgres(_) => Ok(()),
+    })
+    .await??;
+
+    Ok(())
+}
+
+/// Periodically deletes expired rows until shutdown.
+pub async fn run(
+    config: RetentionConfig,
+    db_conn: Arc<Mutex<DbConnection>>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut prune_timer = time::interval(config.interval);
+
+    loop {
+        select! {
+            _ = prune_timer.tick() => {}
+            _ = shutdown.wait_for(|&shutdown| shutdown) => break,
+        }
+
+        if let Err(e) = prune(&config, &db_conn, &shutdown).await {
+            log::error!("Pruning expired measurements failed: {e:#}");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::query::{aggregate_measurements, Bucket};
+    use crate::db::schema::readouts;
+    use crate::db::{readings, rollup, test_connection, InsertableMeasurement};
+
+    #[tokio::test]
+    async fn keeps_unsynced_and_unuploaded_measurements() {
+        let config = RetentionConfig {
+            raw_days: Some(30),
+            hourly_days: None,
+            daily_days: None,
+            interval: Duration::from_secs(3600),
+            batch_size: 1,
+            uploading: true,
+        };
+        let mut conn = test_connection();
+
+        let old =
+            DateTimeUtc::from_micros(DateTimeUtc::now().timestamp_micros() - 60 * DAY_US).unwrap();
+        let measurements = [
+            ("uploaded", LOCAL_NODE_ID, true),
+            ("not uploaded", LOCAL_NODE_ID, true),
+            ("unsynced", LOCAL_NODE_ID, false),
+            ("collected", "node", true),
+        ];
+        for (uid, node_id, clock_synced) in measurements {
+            let measurement = InsertableMeasurement {
+                meas_time: old.clone(),
+                clock_synced,
+                node_id: node_id.to_owned(),
+                uid: Some(uid.to_owned()),
+                ..InsertableMeasurement::example()
+            };
+            readings::insert_measurement(&mut conn, &measurement).unwrap();
+            rollup::update_rollups(&mut conn, &[measurement]).unwrap();
+        }
+        diesel::update(readouts::table.filter(readouts::uid.eq("uploaded")))
+            .set(readouts::uploaded_at.eq(DateTimeUtc::now()))
+            .execute(&mut conn)
+            .unwrap();
+
+        let db_conn = Arc::new(Mutex::new(conn));
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        prune(&config, &db_conn, &shutdown_rx).await.unwrap();
+
+        let mut conn = db_conn.lock().unwrap();
+        let kept = readouts::table
+            .select(readouts::uid)
+            .order(readouts::id)
+            .load::<Option<String>>(&mut *conn)
+            .unwrap();
+        assert_eq!(
+            kept,
+            [Some("not uploaded".to_owned()), Some("unsynced".to_owned())]
+        );
+
+        let readings = crate::db::schema::readings::table
+            .count()
+            .get_result::<i64>(&mut *conn)
+            .unwrap();
+        assert_eq!(readings, 2 * 3);
+
+        // The rollups of the day still cover what was pruned of it.
+        let cutoff = config.raw_cutoff();
+        assert!(cutoff.as_ref().unwrap().timestamp_micros() > old.timestamp_micros());
+        rollup::rebuild_rollups(&mut conn, None, None, cutoff).unwrap();
+        for bucket in [Bucket::Hour, Bucket::Day] {
+            let aggregates =
+                aggregate_measurements(&mut conn, LOCAL_NODE_ID, None, None, Some(bucket)).unwrap();
+            assert_eq!(aggregates.len(), 1, "{bucket:?}");
+            assert_eq!(aggregates[0].count(), 3, "{bucket:?}");
+        }
+    }
+}
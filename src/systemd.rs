@@ -0,0 +1,117 @@
+use sd_notify::NotifyState;
+
+use std::time::Duration;
+
+// All of these are no-ops when the daemon isn't started by systemd (i.e. when
+// NOTIFY_SOCKET isn't set), so they can be called unconditionally.
+
+pub fn notify_ready() {
+    notify(&[NotifyState::Ready]);
+}
+
+pub fn notify_status(status: &str) {
+    notify(&[NotifyState::Status(status)]);
+}
+
+pub fn notify_watchdog() {
+    notify(&[NotifyState::Watchdog]);
+}
+
+pub fn notify_stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+/// Returns the watchdog interval configured in the unit file, if any.
+pub fn watchdog_interval() -> Option<Duration> {
+    let mut usec = 0;
+
+    if sd_notify::watchdog_enabled(false, &mut usec) {
+        Some(Duration::from_micros(usec))
+    } else {
+        None
+    }
+}
+
+/// What's wrong with the watchdog interval, if anything. It's only petted
+/// once per measurement, so it has to be longer than the measurement period.
+pub fn watchdog_warning(
+    watchdog_interval: Duration,
+    measurement_period: Duration,
+) -> Option<String> {
+    if watchdog_interval > measurement_period {
+        return None;
+    }
+
+    Some(format!(
+        "The systemd watchdog interval ({watchdog_interval:?}) is not longer than the \
+         measurement period ({measurement_period:?}), the service will be restarted spuriously."
+    ))
+}
+
+fn notify(state: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        log::warn!("Failed to notify systemd: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn watchdog_longer_than_period_is_fine() {
+        assert_eq!(
+            watchdog_warning(Duration::from_secs(120), Duration::from_secs(60)),
+            None
+        );
+    }
+
+    #[test]
+    fn watchdog_not_longer_than_period_is_warned_about() {
+        assert_eq!(
+            watchdog_warning(Duration::from_secs(60), Duration::from_secs(60)).as_deref(),
+            Some(
+                "The systemd watchdog interval (60s) is not longer than the measurement period \
+                 (60s), the service will be restarted spuriously."
+            )
+        );
+        assert!(watchdog_warning(Duration::from_secs(30), Duration::from_secs(60)).is_some());
+    }
+
+    #[test]
+    fn notifies_the_socket() {
+        let dir =
+            std::env::temp_dir().join(format!("rpi_client_temp-notify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify.sock");
+        let _ = std::fs::remove_file(&path);
+
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        // Nothing else in the tests notifies, so setting it for the whole
+        // process doesn't get in anyone's way.
+        std::env::set_var("NOTIFY_SOCKET", &path);
+
+        let received = || {
+            let mut buf = [0; 256];
+            let len = socket.recv(&mut buf).unwrap();
+            String::from_utf8(buf[..len].to_vec()).unwrap()
+        };
+
+        notify_ready();
+        assert_eq!(received().trim_end(), "READY=1");
+        notify_watchdog();
+        assert_eq!(received().trim_end(), "WATCHDOG=1");
+        notify_status("temperature 21.46°C");
+        assert_eq!(received().trim_end(), "STATUS=temperature 21.46°C");
+        notify_stopping();
+        assert_eq!(received().trim_end(), "STOPPING=1");
+
+        std::env::remove_var("NOTIFY_SOCKET");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
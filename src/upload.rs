@@ -0,0 +1,351 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tokio::{select, task, time};
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::{self, optional_var, var_or};
+use crate::db::readings::with_readings;
+use crate::db::schema::readouts;
+use crate::db::{DateTimeUtc, DbConnection, Measurement, Readout, LOCAL_NODE_ID};
+use crate::metrics::METRICS;
+
+#[derive(Debug, Clone)]
+pub struct UploadConfig {
+    url: String,
+    token: Option<String>,
+    node_id: String,
+    batch_size: i64,
+    interval: Duration,
+}
+
+impl UploadConfig {
+    const URL_ENV_VAR: &'static str = "UPLOAD_URL";
+    const TOKEN_ENV_VAR: &'static str = "UPLOAD_TOKEN";
+    const BATCH_SIZE_ENV_VAR: &'static str = "UPLOAD_BATCH_SIZE";
+    const INTERVAL_ENV_VAR: &'static str = "UPLOAD_INTERVAL_SECS";
+
+    /// Returns `None` if uploading to a collector isn't configured.
+    pub fn from_env() -> Result<Option<Self>> {
+        let url: String = match optional_var(Self::URL_ENV_VAR)? {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+
+        let batch_size = var_or(Self::BATCH_SIZE_ENV_VAR, 500)?;
+        if batch_size < 1 {
+            return Err(anyhow!(
+                "{} has to be at least 1.",
+                Self::BATCH_SIZE_ENV_VAR
+            ));
+        }
+
+        Ok(Some(Self {
+            url,
+            token: optional_var(Self::TOKEN_ENV_VAR)?,
+            node_id: config::node_id()?,
+            batch_size,
+            interval: Duration::from_secs(var_or(Self::INTERVAL_ENV_VAR, 60)?),
+        }))
+    }
+}
+
+/// The body of an upload request. The collector stores the measurements
+/// under `node_id` and ignores any whose `uid` it already has, so that a
+/// batch can be sent again if the acknowledgement got lost.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadBatch {
+    /// The same for the same measurements, so the collector can spot a
+    /// repeated request without looking at the measurements.
+    pub batch_id: String,
+    pub node_id: String,
+    pub hostname: String,
+    pub measurements: Vec<UploadedMeasurement>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadedMeasurement {
+    /// Unique per node, derived from the measurement time rather than the row
+    /// ID so that it survives the DB being recreated from an export.
+    pub uid: String,
+    pub time: DateTime<Utc>,
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+    pub pressure: Option<f32>,
+    pub light_level: Option<f32>,
+    pub meas_duration_us: Option<i64>,
+}
+
+/// Returns up to `limit` of the oldest measurements taken here that haven't
+/// been acknowledged yet. Measurements still waiting for the clock are held
+/// back, their time would change after the collector has stored them, and so
+/// are the ones the collector rejected.
+fn pending_measurements(conn: &mut DbConnection, limit: i64) -> QueryResult<Vec<Measurement>> {
+    let readouts = readouts::table
+        .select(Readout::as_select())
+        .filter(readouts::node_id.eq(LOCAL_NODE_ID))
+        .filter(readouts::uploaded_at.is_null())
+        .filter(readouts::clock_synced)
+        .filter(readouts::upload_error.is_null())
+        .order(readouts::id.asc())
+        .limit(limit)
+        .load(conn)?;
+
+    with_readings(conn, readouts)
+}
+
+fn mark_uploaded(conn: &mut DbConnection, ids: &[i32]) -> QueryResult<usize> {
+    diesel::update(readouts::table)
+        .filter(readouts::id.eq_any(ids))
+        .set(readouts::uploaded_at.eq(DateTimeUtc::now()))
+        .execute(conn)
+}
+
+fn mark_rejected(conn: &mut DbConnection, rejected: &[(i32, String)]) -> QueryResult<()> {
+    for (id, error) in rejected {
+        diesel::update(readouts::table)
+            .filter(readouts::id.eq(id))
+            .set(readouts::upload_error.eq(error))
+            .execute(conn)?;
+    }
+
+    Ok(())
+}
+
+/// What the collector made of a batch.
+enum Sent {
+    Accepted,
+    /// It's never going to take it, with the reason why.
+    Rejected(String),
+}
+
+/// What the collector made of the measurements of a batch, which it either
+/// acknowledged or rejected, by row ID.
+#[derive(Debug, Default)]
+struct Outcome {
+    accepted: Vec<i32>,
+    rejected: Vec<(i32, String)>,
+}
+
+struct Uploader {
+    config: UploadConfig,
+    client: Client,
+    hostname: String,
+    db_conn: Arc<Mutex<DbConnection>>,
+}
+
+impl Uploader {
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+    const MIN_RETRY_DELAY: Duration = Duration::from_secs(5);
+    const MAX_RETRY_DELAY: Duration = Duration::from_secs(600);
+
+    /// Uploads the oldest batch of pending measurements, returning how many
+    /// were either acknowledged or rejected.
+    async fn upload_batch(&self) -> Result<usize> {
+        let db_conn = self.db_conn.clone();
+        let limit = self.config.batch_size;
+        let pending =
+            task::spawn_blocking(move || pending_measurements(&mut db_conn.lock().unwrap(), limit))
+                .await??;
+
+        if pending.is_empty() {
+            return Ok(0);
+        }
+
+        let outcome = self.send_accepted(&pending).await?;
+        let (accepted, rejected) = (outcome.accepted.len(), outcome.rejected.len());
+
+        let db_conn = self.db_conn.clone();
+        task::spawn_blocking(move || {
+            let mut conn = db_conn.lock().unwrap();
+            conn.transaction(|conn| {
+                mark_uploaded(conn, &outcome.accepted)?;
+                mark_rejected(conn, &outcome.rejected)
+            })
+        })
+        .await??;
+
+        METRICS.uploaded_measurements.add(accepted as u64);
+        log::debug!("Uploaded {accepted} measurements.");
+
+        Ok(accepted + rejected)
+    }
+
+    /// Sends the measurements, and if the collector rejects them, halves
+    /// until the ones it won't take are found on their own.
+    async fn send_accepted(&self, measurements: &[Measurement]) -> Result<Outcome> {
+        let mut pending = vec![measurements];
+        let mut outcome = Outcome::default();
+
+        while let Some(measurements) = pending.pop() {
+            match self.send(measurements).await? {
+                Sent::Accepted => outcome.accepted.extend(
+                    measurements
+                        .iter()
+                        .map(|measurement| measurement.readout.id),
+                ),
+                // The collector won't ever take it, retrying would just block
+                // everything behind it.
+                Sent::Rejected(error) if measurements.len() == 1 => {
+                    let measurement = &measurements[0];
+                    log::error!(
+                        "The collector rejected the measurement of {}, skipping it: {error}",
+                        *measurement.readout.meas_time
+                    );
+                    outcome.rejected.push((measurement.readout.id, error));
+                }
+                Sent::Rejected(_) => {
+                    let (first, second) = measurements.split_at(measurements.len() / 2);
+                    pending.extend([second, first]);
+                }
+            }
+        }
+
+        Ok(outcome)
+    }
+
+    async fn send(&self, pending: &[Measurement]) -> Result<Sent> {
+        let (Some(first), Some(last)) = (pending.first(), pending.last()) else {
+            return Ok(Sent::Accepted);
+        };
+
+        let batch = UploadBatch {
+            batch_id: format!(
+                "{}-{}-{}-{}",
+                self.config.node_id,
+                first.readout.id,
+                last.readout.id,
+                pending.len()
+            ),
+            node_id: self.config.node_id.clone(),
+            hostname: self.hostname.clone(),
+            measurements: pending
+                .iter()
+                .map(|measurement| UploadedMeasurement {
+                    uid: format!(
+                        "{}-{}",
+                        self.config.node_id,
+                        measurement.readout.meas_time.timestamp_micros()
+                    ),
+                    time: *measurement.readout.meas_time,
+                    temperature: measurement.value("temperature"),
+                    humidity: measurement.value("humidity"),
+                    pressure: measurement.value("pressure"),
+                    light_level: measurement.value("light_level"),
+                    meas_duration_us: measurement.readout.meas_duration_us,
+                })
+                .collect(),
+        };
+
+        let mut request = self
+            .client
+            .post(&self.config.url)
+            .header("Content-Type", "application/json")
+            .header("Idempotency-Key", &batch.batch_id)
+            .body(serde_json::to_vec(&batch)?);
+        if let Some(token) = &self.config.token {
+            request = request.bearer_auth(token);
+        }
+
+        let response = request.send().await?;
+
+        match response.status() {
+            status if status.is_success() => Ok(Sent::Accepted),
+            StatusCode::BAD_REQUEST => {
+                Ok(Sent::Rejected(response.text().await.unwrap_or_default()))
+            }
+            status => {
+                let body = response.text().await.unwrap_or_default();
+                Err(anyhow!("The collector responded with {status}: {body}"))
+            }
+        }
+    }
+}
+
+/// Uploads the measurements that the collector hasn't acknowledged until
+/// shutdown. What's been acknowledged is tracked in the DB, so an outage or a
+/// restart just means there's more to catch up on.
+pub async fn run(
+    config: UploadConfig,
+    db_conn: Arc<Mutex<DbConnection>>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    log::info!(
+        "Uploading measurements to {} as node {}",
+        config.url,
+        config.node_id
+    );
+
+    let uploader = Uploader {
+        client: Client::builder()
+            .timeout(Uploader::REQUEST_TIMEOUT)
+            .build()?,
+        hostname: config::hostname()?,
+        config,
+        db_conn,
+    };
+    let mut retry_delay = Uploader::MIN_RETRY_DELAY;
+
+    loop {
+        let delay = match uploader.upload_batch().await {
+            Ok(uploaded) => {
+                retry_delay = Uploader::MIN_RETRY_DELAY;
+                // Keep going while there's a backlog.
+                if uploaded as i64 == uploader.config.batch_size {
+                    Duration::ZERO
+                } else {
+                    uploader.config.interval
+                }
+            }
+            Err(e) => {
+                log::warn!("Uploading measurements failed, retrying in {retry_delay:?}: {e:#}");
+                let delay = retry_delay;
+                retry_delay = (retry_delay * 2).min(Uploader::MAX_RETRY_DELAY);
+                delay
+            }
+        };
+
+        select! {
+            _ = time::sleep(delay) => {}
+            _ = shutdown.wait_for(|&shutdown| shutdown) => break,
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{readings, test_connection, InsertableMeasurement};
+
+    #[test]
+    fn rejected_measurements_are_not_pending() {
+        let mut conn = test_connection();
+        for i in 0..3 {
+            let measurement = InsertableMeasurement {
+                uid: Some(i.to_string()),
+                ..InsertableMeasurement::example()
+            };
+            readings::insert_measurement(&mut conn, &measurement).unwrap();
+        }
+
+        let pending = pending_measurements(&mut conn, 10).unwrap();
+        assert_eq!(pending.len(), 3);
+
+        mark_uploaded(&mut conn, &[pending[0].readout.id]).unwrap();
+        mark_rejected(&mut conn, &[(pending[1].readout.id, "Bad".to_owned())]).unwrap();
+
+        let ids = pending_measurements(&mut conn, 10)
+            .unwrap()
+            .iter()
+            .map(|measurement| measurement.readout.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [pending[2].readout.id]);
+    }
+}
@@ -0,0 +1,239 @@
+use anyhow::{anyhow, Result};
+use diesel::prelude::*;
+use tokio::task;
+
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::optional_var;
+use crate::db::{self, DbConnection, InsertableMeasurement};
+use crate::metrics::METRICS;
+
+#[derive(Debug, Clone)]
+pub struct WriteBufferConfig {
+    batch_size: usize,
+    max_delay: Duration,
+}
+
+impl WriteBufferConfig {
+    const BATCH_SIZE_ENV_VAR: &'static str = "DB_WRITE_BATCH_SIZE";
+    const INTERVAL_ENV_VAR: &'static str = "DB_WRITE_INTERVAL_SECS";
+
+    /// Returns `None` if every measurement is to be written right away.
+    pub fn from_env() -> Result<Option<Self>> {
+        let batch_size = optional_var(Self::BATCH_SIZE_ENV_VAR)?;
+        let max_delay = optional_var(Self::INTERVAL_ENV_VAR)?;
+
+        if batch_size.is_none() && max_delay.is_none() {
+            return Ok(None);
+        }
+
+        if batch_size == Some(0) {
+            return Err(anyhow!(
+                "{} has to be at least 1.",
+                Self::BATCH_SIZE_ENV_VAR
+            ));
+        }
+
+        // Without one of the limits it's only the other that counts.
+        Ok(Some(Self {
+            batch_size: batch_size.unwrap_or(usize::MAX),
+            max_delay: max_delay.map_or(Duration::MAX, Duration::from_secs),
+        }))
+    }
+}
+
+/// Holds measurements back and writes them in one transaction once there are
+/// enough of them or the oldest has waited long enough, so that the SD card
+/// isn't written to on every measurement. Until then they're only in memory,
+/// and not in what the API returns either.
+pub struct WriteBuffer {
+    config: Option<WriteBufferConfig>,
+    db_conn: Arc<Mutex<DbConnection>>,
+    pending: Vec<InsertableMeasurement>,
+    oldest: Option<Instant>,
+    max_pending: usize,
+}
+
+impl WriteBuffer {
+    /// How many measurements are kept for the next try while the DB can't be
+    /// written to, about a week's worth at one a minute.
+    const MAX_PENDING: usize = 10_000;
+
+    pub fn new(config: Option<WriteBufferConfig>, db_conn: Arc<Mutex<DbConnection>>) -> Self {
+        Self {
+            config,
+            db_conn,
+            pending: Vec::new(),
+            oldest: None,
+            max_pending: Self::MAX_PENDING,
+        }
+    }
+
+    /// The measurements that haven't been written yet, oldest first.
+    pub fn pending(&self) -> &[InsertableMeasurement] {
+        &self.pending
+    }
+
+    /// Adds the measurement, writing everything that's buffered if it's time
+    /// to. If that fails the measurements stay buffered for the next try, up
+    /// to a limit past which the oldest ones are dropped.
+    pub async fn push(&mut self, measurement: InsertableMeasurement) -> Result<()> {
+        self.pending.push(measurement);
+        let oldest = *self.oldest.get_or_insert_with(Instant::now);
+
+        let due = match &self.config {
+            Some(config) => {
+                self.pending.len() >= config.batch_size || oldest.elapsed() >= config.max_delay
+            }
+            None => true,
+        };
+        if due {
+            self.flush().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes everything that's buffered, returning how many measurements
+    /// that was.
+    pub async fn flush(&mut self) -> Result<usize> {
+        if self.pending.is_empty() {
+            return Ok(0);
+        }
+
+        let db_conn = self.db_conn.clone();
+        let pending = mem::take(&mut self.pending);
+
+        let (result, pending) = task::spawn_blocking(move || {
+            let start = Instant::now();
+            let result = db_conn.lock().unwrap().transaction(|conn| {
+                for measurement in &pending {
+                    db::readings::insert_measurement(conn, measurement)?;
+                }
+                db::rollup::update_rollups(conn, &pending)
+            });
+            if result.is_ok() {
+                METRICS.record_db_insert(start.elapsed());
+            }
+
+            (result, pending)
+        })
+        .await?;
+
+        match result {
+            Ok(()) => {
+                self.oldest = None;
+                Ok(pending.len())
+            }
+            Err(e) => {
+                self.pending = pending;
+
+                let excess = self.pending.len().saturating_sub(self.max_pending);
+                if excess > 0 {
+                    self.pending.drain(..excess);
+                    log::warn!(
+                        "Dropped the {excess} oldest measurements, the DB can't be written to \
+                         and the write buffer is full."
+                    );
+                }
+
+                Err(e.into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::schema::readouts;
+    use crate::db::test_connection;
+    use diesel::connection::SimpleConnection;
+    use tokio::time;
+
+    fn buffer(batch_size: usize, max_delay: Duration) -> WriteBuffer {
+        let config = WriteBufferConfig {
+            batch_size,
+            max_delay,
+        };
+
+        WriteBuffer::new(Some(config), Arc::new(Mutex::new(test_connection())))
+    }
+
+    fn stored(buffer: &WriteBuffer) -> i64 {
+        readouts::table
+            .count()
+            .get_result(&mut *buffer.db_conn.lock().unwrap())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn flushes_full_batches() {
+        let mut buffer = buffer(2, Duration::MAX);
+
+        buffer.push(InsertableMeasurement::example()).await.unwrap();
+        assert_eq!(buffer.pending().len(), 1);
+        assert_eq!(stored(&buffer), 0);
+
+        buffer.push(InsertableMeasurement::example()).await.unwrap();
+        assert!(buffer.pending().is_empty());
+        assert_eq!(stored(&buffer), 2);
+    }
+
+    #[tokio::test]
+    async fn flushes_once_the_oldest_has_waited_long_enough() {
+        let mut buffer = buffer(usize::MAX, Duration::from_millis(50));
+
+        buffer.push(InsertableMeasurement::example()).await.unwrap();
+        assert_eq!(stored(&buffer), 0);
+
+        time::sleep(Duration::from_millis(60)).await;
+        buffer.push(InsertableMeasurement::example()).await.unwrap();
+        assert!(buffer.pending().is_empty());
+        assert_eq!(stored(&buffer), 2);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_batch_when_writing_fails() {
+        let mut buffer = buffer(2, Duration::MAX);
+        buffer.max_pending = 3;
+        buffer
+            .db_conn
+            .lock()
+            .unwrap()
+            .batch_execute("ALTER TABLE readings RENAME TO gone")
+            .unwrap();
+
+        let measurement = |uid: &str| InsertableMeasurement {
+            uid: Some(uid.to_owned()),
+            ..InsertableMeasurement::example()
+        };
+        buffer.push(measurement("1")).await.unwrap();
+        assert!(buffer.push(measurement("2")).await.is_err());
+        assert_eq!(buffer.pending().len(), 2);
+        // Nothing of the batch is written.
+        assert_eq!(stored(&buffer), 0);
+
+        // Past the limit, the oldest are dropped.
+        assert!(buffer.push(measurement("3")).await.is_err());
+        assert!(buffer.push(measurement("4")).await.is_err());
+        let uids = buffer
+            .pending()
+            .iter()
+            .map(|measurement| measurement.uid.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(uids, ["2", "3", "4"]);
+
+        buffer
+            .db_conn
+            .lock()
+            .unwrap()
+            .batch_execute("ALTER TABLE gone RENAME TO readings")
+            .unwrap();
+        assert_eq!(buffer.flush().await.unwrap(), 3);
+        assert!(buffer.pending().is_empty());
+        assert_eq!(stored(&buffer), 3);
+    }
+}